@@ -1,22 +1,70 @@
 use anyhow::{anyhow, Context, Result};
-use crossbeam::sync::{ShardedLock, ShardedLockWriteGuard};
-use log::{debug, info, trace};
-use memmap2::MmapMut;
-use std::fs::OpenOptions;
+use crossbeam::sync::{ShardedLock, ShardedLockReadGuard, ShardedLockWriteGuard};
+use log::{debug, info, trace, warn};
+#[cfg(target_os = "linux")]
+use memmap2::Advice;
+use memmap2::{MmapMut, MmapOptions};
+use parking_lot::Mutex;
+use std::fs::{File, OpenOptions};
 use std::io::Write;
 use std::ops::Deref;
 use std::path::PathBuf;
 use tempfile::NamedTempFile;
 
+/// The deepest a directory (and so any segment) can get by default. Past this the directory would
+/// need 16GiB of entries.
+pub const MAX_DEPTH: u8 = 32;
+
+/// The number of entries `Directory::grow` returns for a directory grown from `global_depth`.
+/// Checked before growing, so a directory that's too deep or too large to report it is left as is.
+pub(crate) fn grown_entries(global_depth: u8, max_depth: u8) -> Result<u32> {
+    if global_depth >= max_depth {
+        return Err(anyhow!(
+            "Directory is already at its maximum depth of {}",
+            max_depth
+        ));
+    }
+    u32::try_from(1u64 << (global_depth + 1)).map_err(|_| {
+        anyhow!(
+            "Directory at depth {} would have more entries than fit in a u32",
+            global_depth + 1
+        )
+    })
+}
+
 pub trait Directory<T: Sized = Self>: Sized {
     type Config;
     fn init(config: Self::Config) -> Result<Self>;
     fn segment_index(&self, i: u64) -> Result<u32>;
-    fn set_segment_index(&self, i: u64, index: u32, global_depth: &mut GlobalDepth) -> Result<()>;
+    fn set_segment_index(
+        &self,
+        i: u64,
+        index: u32,
+        global_depth: &mut GlobalDepth<'_>,
+    ) -> Result<()>;
+    /// Points `count` consecutive directory entries starting at `start` to segment `index`. This
+    /// is what a split uses to hand half of a segment's entries to the new segment, and
+    /// implementations should persist the whole range at once rather than entry by entry.
+    fn set_segment_range(
+        &self,
+        start: u64,
+        count: u64,
+        index: u32,
+        global_depth: &mut GlobalDepth<'_>,
+    ) -> Result<()>;
     /// Doubles the size of the directory and returns the new size (not the global_depth)
     fn grow(&self) -> Result<u32>;
-    fn global_depth(&self) -> Result<GlobalDepth>;
+    fn global_depth(&self) -> Result<GlobalDepth<'_>>;
     fn grow_if_eq(&self, local_depth: u8) -> Result<u8>;
+    /// The largest global depth the directory can grow to. Segments at this depth can't be split.
+    fn max_depth(&self) -> u8 {
+        MAX_DEPTH
+    }
+    /// Iterates over every distinct segment referenced by the directory, in directory order.
+    /// Each item is `(segment_index, first_dir_entry, entry_count, local_depth)`. Implementations
+    /// may hold a read lock on the directory for the lifetime of the iterator, so it should not
+    /// be kept around while splitting.
+    fn segments(&self) -> Result<impl Iterator<Item = (u32, u64, u64, u8)> + '_>;
 }
 
 pub struct MMapDirectory {
@@ -24,36 +72,129 @@ pub struct MMapDirectory {
     // as a guard to turn Mmap into a MmapMut
     map: ShardedLock<MmapMut>,
     config: PathBuf, // We only care about the path for now
+    options: MapOptions,
+    stats: Mutex<MapStats>,
+    max_depth: u8,
+}
+
+/// Tuning knobs for how `MMapDirectory` maps `directory.bin`. They are re-applied every time the
+/// directory grows and gets remapped. All of them are best-effort: if the OS refuses one (e.g.
+/// `RLIMIT_MEMLOCK` is too low for `mlock`) the directory still opens and `MapStats` reports that
+/// the option did not take effect.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MapOptions {
+    /// `mlock` the directory so lookups never page fault on it.
+    pub mlock: bool,
+    /// Request transparent huge pages with `madvise(MADV_HUGEPAGE)` to cut TLB misses.
+    pub huge_pages: bool,
+    /// Populate the page tables when mapping (`MAP_POPULATE`) instead of faulting lazily.
+    pub prefault: bool,
+}
+
+/// Whether each of the requested `MapOptions` took effect on the current mapping.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MapStats {
+    pub mlocked: bool,
+    pub huge_pages: bool,
+    pub prefaulted: bool,
+}
+
+/// Maps `file` and applies `options` to the new mapping.
+fn map_file(file: &File, options: &MapOptions) -> Result<(MmapMut, MapStats)> {
+    let mut stats = MapStats::default();
+    let mut map_options = MmapOptions::new();
+    if options.prefault {
+        // MAP_POPULATE only exists on Linux, memmap2 ignores it everywhere else
+        if cfg!(any(target_os = "linux", target_os = "android")) {
+            map_options.populate();
+            stats.prefaulted = true;
+        } else {
+            warn!("Unable to prefault directory: MAP_POPULATE is not supported on this platform");
+        }
+    }
+    // mmaps are unsafe!
+    let map = unsafe { map_options.map_mut(file).context("Initializing mmap")? };
+    if options.huge_pages {
+        // Transparent huge pages are Linux only
+        #[cfg(target_os = "linux")]
+        match map.advise(Advice::HugePage) {
+            Ok(_) => stats.huge_pages = true,
+            Err(e) => warn!("Unable to request huge pages for directory: {}", e),
+        }
+        #[cfg(not(target_os = "linux"))]
+        warn!("Unable to request huge pages for directory: not supported on this platform");
+    }
+    if options.mlock {
+        #[cfg(unix)]
+        match map.lock() {
+            Ok(_) => stats.mlocked = true,
+            Err(e) => warn!("Unable to mlock directory: {}", e),
+        }
+        #[cfg(not(unix))]
+        warn!("Unable to mlock directory: not supported on this platform");
+    }
+    Ok((map, stats))
+}
+
+/// Reads the segment index for hash key `i` from a directory that keeps its global depth in the
+/// first byte of `map`, followed by the entries.
+fn lookup(map: &[u8], i: u64) -> Result<u32> {
+    let global_depth = map[0];
+    let index = if global_depth == 0 {
+        // Lazy way to get out of overflowing bitshift
+        0
+    } else {
+        i >> (64 - global_depth)
+    };
+    debug!("Retrieving segment index from dir index: {}", index);
+    let offset = ((index * 4) + 1) as usize;
+    match map.get(offset..offset + 4) {
+        Some(i) => {
+            let mut buf: [u8; 4] = [0; 4];
+            buf.copy_from_slice(i);
+            Ok(u32::from_le_bytes(buf))
+        }
+        None => Err(anyhow!(
+            "Unable to find segment index in directory at location {}",
+            index
+        )),
+    }
+}
+
+/// Points `count` entries starting at `start` to segment `index` in a directory laid out like
+/// `lookup` expects. Returns the byte offset and length of the entries it changed.
+fn fill_range(map: &mut [u8], start: u64, count: u64, index: u32) -> Result<(usize, usize)> {
+    let offset = ((start * 4) + 1) as usize;
+    let len = (count * 4) as usize;
+    info!(
+        "Setting dir indexes {}..{} to segment index {}",
+        start,
+        start + count,
+        index
+    );
+    let range = map.get_mut(offset..offset + len).with_context(|| {
+        format!(
+            "Directory entries {}..{} are out of bounds",
+            start,
+            start + count
+        )
+    })?;
+    let bytes = index.to_le_bytes();
+    for entry in range.chunks_exact_mut(4) {
+        entry.copy_from_slice(&bytes);
+    }
+    Ok((offset, len))
 }
 
 pub struct GlobalDepth<'a> {
-    global_depth: u8,
-    lock: ShardedLockWriteGuard<'a, MmapMut>,
+    pub(crate) global_depth: u8,
+    pub(crate) lock: ShardedLockWriteGuard<'a, MmapMut>,
 }
 
 impl Directory for MMapDirectory {
     type Config = PathBuf;
     fn init(config: Self::Config) -> Result<Self> {
-        // TODO: better error handling
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .truncate(false) // Don't clear the file, we need it!
-            .create(true)
-            .open(&config)
-            .context("Opening up mmap file")
-            .expect("Unable to initialize mmap file.");
-        if file.metadata().unwrap().len() == 0 {
-            file.set_len(5)
-                .context("Setting initial mmap file size to 5")?;
-        }
-        // mmaps are unsafe!
-        let map = unsafe { MmapMut::map_mut(&file).context("Initializing mmap")? };
-        // If the mmap is empty or new, make it writeable, populate the global_
-        Ok(Self {
-            map: ShardedLock::new(map),
-            config,
-        })
+        Self::with_options(config, MapOptions::default())
     }
 
     fn segment_index(&self, i: u64) -> Result<u32> {
@@ -61,34 +202,32 @@ impl Directory for MMapDirectory {
             Err(_) => return Err(anyhow!("Directory lock is probably poisoned.")),
             Ok(l) => l,
         };
-        let global_depth = unlocked[0];
-        let index = if global_depth == 0 {
-            // Lazy way to get out of overflowing bitshift
-            0
-        } else {
-            i >> 64 - global_depth
-        };
-        debug!("Retrieving segment index from dir index: {}", index);
-        let offset = ((index * 4) + 1) as usize;
-        match unlocked.get(offset..offset + 4) {
-            Some(i) => {
-                let mut buf: [u8; 4] = [0; 4];
-                buf.copy_from_slice(i);
-                Ok(u32::from_le_bytes(buf))
-            }
-            None => Err(anyhow!(
-                "Unable to find segment index in directory at location {}",
-                index
-            )),
-        }
+        lookup(&unlocked, i)
     }
 
-    fn set_segment_index(&self, i: u64, index: u32, gd: &mut GlobalDepth) -> Result<()> {
+    fn set_segment_index(&self, i: u64, index: u32, gd: &mut GlobalDepth<'_>) -> Result<()> {
         // We have a RW lock now
         let offset = ((i * 4) + 1) as usize;
         info!("Setting dir index {} to segment index {}", i, index);
         gd.lock[offset..offset + 4].copy_from_slice(&index.to_le_bytes()[..]);
-        gd.lock.flush().context("Flushing directory file")?;
+        gd.lock
+            .flush_range(offset, 4)
+            .context("Flushing directory file")?;
+        Ok(())
+    }
+
+    fn set_segment_range(
+        &self,
+        start: u64,
+        count: u64,
+        index: u32,
+        gd: &mut GlobalDepth<'_>,
+    ) -> Result<()> {
+        let (offset, len) = fill_range(&mut gd.lock, start, count, index)?;
+        // Only msync the pages we actually dirtied instead of the whole directory
+        gd.lock
+            .flush_range(offset, len)
+            .context("Flushing directory range")?;
         Ok(())
     }
 
@@ -97,13 +236,19 @@ impl Directory for MMapDirectory {
             Err(_) => return Err(anyhow!("Directory lock is probably poisoned.")),
             Ok(l) => l,
         };
+        let global_depth = unlocked[0];
+        if global_depth >= self.max_depth {
+            return Err(anyhow!(
+                "Directory is already at its maximum depth of {}",
+                self.max_depth
+            ));
+        }
         // Create a temporary file, we'll fill this with the contents of the current map, but
         // duplicated per the rules of a MSP extendible hashing directory
         let mut dir_path = self.config.clone();
         dir_path.pop();
         let mut temporary_file = NamedTempFile::new_in(dir_path)?;
         let f = temporary_file.as_file_mut();
-        let global_depth = unlocked[0];
         info!(
             "Increase global_depth from {} to {}",
             global_depth,
@@ -111,19 +256,19 @@ impl Directory for MMapDirectory {
         );
         debug!(
             "Old # of dir entries {} increasing to {}",
-            1 << global_depth,
-            1 << (global_depth + 1)
+            1u64 << global_depth,
+            1u64 << (global_depth + 1)
         );
-        f.write(&[global_depth + 1])?;
-        for i in 0..1 << global_depth {
+        f.write_all(&[global_depth + 1])?;
+        for i in 0..1u64 << global_depth {
             let offset = ((i * 4) + 1) as usize;
             trace!("Reading old map at offsets[{}:{}]", offset, offset + 4);
             let data = unlocked
                 .get(offset..offset + 4)
                 .expect("Somehow mmap file is smaller than expected, or this is a bug");
             // Write exactly twice
-            f.write(data)?;
-            f.write(data)?;
+            f.write_all(data)?;
+            f.write_all(data)?;
         }
         f.flush().context("Flushing new dir tempfile")?;
         // We're done preparing our file, tine to upgrade our handle on the memorymap, copy over
@@ -131,7 +276,7 @@ impl Directory for MMapDirectory {
         let f = temporary_file
             .persist(&self.config)
             .context("Copying over new grown directory file over existing filepath.")?;
-        let new_map = unsafe { MmapMut::map_mut(&f)? };
+        let new_map = self.remap(&f)?;
         drop(unlocked);
         let mut unlocked = match self.map.write() {
             Err(_) => return Err(anyhow!("Directory lock is probably poisoned.")),
@@ -141,12 +286,12 @@ impl Directory for MMapDirectory {
         Ok(0)
     }
 
-    fn global_depth(&self) -> Result<GlobalDepth> {
+    fn global_depth(&self) -> Result<GlobalDepth<'_>> {
         let unlocked = match self.map.write() {
             Err(_) => return Err(anyhow!("Directory lock is probably poisoned.")),
             Ok(l) => l,
         };
-        match unlocked.get(0) {
+        match unlocked.first() {
             None => Err(anyhow!("Unable to read global depth from mmap file.")),
             Some(g) => Ok(GlobalDepth {
                 global_depth: *g,
@@ -160,7 +305,7 @@ impl Directory for MMapDirectory {
             Err(_) => return Err(anyhow!("Directory lock is probably poisoned.")),
             Ok(l) => l,
         };
-        let global_depth = match unlocked.get(0) {
+        let global_depth = match unlocked.first() {
             None => return Err(anyhow!("Unable to read global depth from mmap file.")),
             Some(g) => {
                 if *g > local_depth {
@@ -169,6 +314,12 @@ impl Directory for MMapDirectory {
                 *g
             }
         };
+        if global_depth >= self.max_depth {
+            return Err(anyhow!(
+                "Directory is already at its maximum depth of {}",
+                self.max_depth
+            ));
+        }
         // Create a temporary file, we'll fill this with the contents of the current map, but
         // duplicated per the rules of a MSP extendible hashing directory
         let mut dir_path = self.config.clone();
@@ -182,19 +333,19 @@ impl Directory for MMapDirectory {
         );
         debug!(
             "Old # of dir entries {} increasing to {}",
-            1 << global_depth,
-            1 << (global_depth + 1)
+            1u64 << global_depth,
+            1u64 << (global_depth + 1)
         );
-        f.write(&[global_depth + 1])?;
-        for i in 0..1 << global_depth {
+        f.write_all(&[global_depth + 1])?;
+        for i in 0..1u64 << global_depth {
             let offset = ((i * 4) + 1) as usize;
             trace!("Reading old map at offsets[{}:{}]", offset, offset + 4);
             let data = unlocked
                 .get(offset..offset + 4)
                 .expect("Somehow mmap file is smaller than expected, or this is a bug");
             // Write exactly twice
-            f.write(data)?;
-            f.write(data)?;
+            f.write_all(data)?;
+            f.write_all(data)?;
         }
         f.flush().context("Flushing new dir tempfile")?;
         // We're done preparing our file, tine to upgrade our handle on the memorymap, copy over
@@ -202,10 +353,271 @@ impl Directory for MMapDirectory {
         let f = temporary_file
             .persist(&self.config)
             .context("Copying over new grown directory file over existing filepath.")?;
-        let new_map = unsafe { MmapMut::map_mut(&f)? };
+        let new_map = self.remap(&f)?;
         *unlocked = new_map;
         Ok(global_depth + 1)
     }
+
+    fn segments(&self) -> Result<impl Iterator<Item = (u32, u64, u64, u8)> + '_> {
+        let unlocked = match self.map.read() {
+            Err(_) => return Err(anyhow!("Directory lock is probably poisoned.")),
+            Ok(l) => l,
+        };
+        let global_depth = match unlocked.first() {
+            None => return Err(anyhow!("Unable to read global depth from mmap file.")),
+            Some(g) => *g,
+        };
+        let entries: u64 = 1 << global_depth;
+        if unlocked.len() < ((entries * 4) + 1) as usize {
+            return Err(anyhow!(
+                "Directory is smaller than expected for global depth {}",
+                global_depth
+            ));
+        }
+        Ok(DirectorySegments::new(unlocked, 1, global_depth))
+    }
+
+    fn max_depth(&self) -> u8 {
+        self.max_depth
+    }
+}
+
+impl MMapDirectory {
+    /// Opens (or creates) the directory at `path`, mapping it according to `options`.
+    pub fn with_options(path: PathBuf, options: MapOptions) -> Result<Self> {
+        // TODO: better error handling
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .truncate(false) // Don't clear the file, we need it!
+            .create(true)
+            .open(&path)
+            .context("Opening up mmap file")
+            .expect("Unable to initialize mmap file.");
+        if file.metadata().unwrap().len() == 0 {
+            file.set_len(5)
+                .context("Setting initial mmap file size to 5")?;
+        }
+        let (map, stats) = map_file(&file, &options)?;
+        Ok(Self {
+            map: ShardedLock::new(map),
+            config: path,
+            options,
+            stats: Mutex::new(stats),
+            max_depth: MAX_DEPTH,
+        })
+    }
+
+    /// Limits how deep the directory can grow, `MAX_DEPTH` by default.
+    pub fn with_max_depth(mut self, max_depth: u8) -> Self {
+        assert!(max_depth <= MAX_DEPTH);
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Reports which of the `MapOptions` took effect on the current mapping.
+    pub fn map_stats(&self) -> MapStats {
+        *self.stats.lock()
+    }
+
+    /// Maps a freshly persisted directory file, applying the configured options and recording
+    /// whether they took effect. Callers must hold the write lock they are replacing the map in.
+    fn remap(&self, file: &File) -> Result<MmapMut> {
+        let (map, stats) = map_file(file, &self.options)?;
+        *self.stats.lock() = stats;
+        Ok(map)
+    }
+}
+
+/// A `Directory` that lives only in memory, for running `MehDB` without touching the filesystem,
+/// e.g. together with a `BasicSegmenter` over a `Cursor<Vec<u8>>`. It has the same layout as
+/// `MMapDirectory` but in an anonymous map, which is replaced with a bigger one when it grows.
+pub struct MemoryDirectory {
+    map: ShardedLock<MmapMut>,
+    max_depth: u8,
+}
+
+impl MemoryDirectory {
+    pub fn new() -> Result<Self> {
+        let map = MmapMut::map_anon(5).context("Mapping in-memory directory")?;
+        Ok(Self {
+            map: ShardedLock::new(map),
+            max_depth: MAX_DEPTH,
+        })
+    }
+
+    /// Limits how deep the directory can grow, `MAX_DEPTH` by default.
+    pub fn with_max_depth(mut self, max_depth: u8) -> Self {
+        assert!(max_depth <= MAX_DEPTH);
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Doubles the directory in `map`, which the caller has write locked, and returns the new
+    /// global depth.
+    fn grow_locked(&self, map: &mut MmapMut) -> Result<u8> {
+        let global_depth = map[0];
+        if global_depth >= self.max_depth {
+            return Err(anyhow!(
+                "Directory is already at its maximum depth of {}",
+                self.max_depth
+            ));
+        }
+        info!(
+            "Increase global_depth from {} to {}",
+            global_depth,
+            global_depth + 1
+        );
+        let entries = 1usize << global_depth;
+        let mut grown =
+            MmapMut::map_anon(entries * 8 + 1).context("Mapping grown in-memory directory")?;
+        grown[0] = global_depth + 1;
+        for i in 0..entries {
+            let data = &map[i * 4 + 1..i * 4 + 5];
+            // Write exactly twice
+            let offset = i * 8 + 1;
+            grown[offset..offset + 4].copy_from_slice(data);
+            grown[offset + 4..offset + 8].copy_from_slice(data);
+        }
+        *map = grown;
+        Ok(global_depth + 1)
+    }
+}
+
+impl Directory for MemoryDirectory {
+    type Config = ();
+    fn init(_config: Self::Config) -> Result<Self> {
+        Self::new()
+    }
+
+    fn segment_index(&self, i: u64) -> Result<u32> {
+        let unlocked = match self.map.read() {
+            Err(_) => return Err(anyhow!("Directory lock is probably poisoned.")),
+            Ok(l) => l,
+        };
+        lookup(&unlocked, i)
+    }
+
+    fn set_segment_index(&self, i: u64, index: u32, gd: &mut GlobalDepth<'_>) -> Result<()> {
+        self.set_segment_range(i, 1, index, gd)
+    }
+
+    fn set_segment_range(
+        &self,
+        start: u64,
+        count: u64,
+        index: u32,
+        gd: &mut GlobalDepth<'_>,
+    ) -> Result<()> {
+        fill_range(&mut gd.lock, start, count, index)?;
+        Ok(())
+    }
+
+    fn grow(&self) -> Result<u32> {
+        let mut unlocked = match self.map.write() {
+            Err(_) => return Err(anyhow!("Directory lock is probably poisoned.")),
+            Ok(l) => l,
+        };
+        let entries = grown_entries(unlocked[0], self.max_depth)?;
+        self.grow_locked(&mut unlocked)?;
+        Ok(entries)
+    }
+
+    fn global_depth(&self) -> Result<GlobalDepth<'_>> {
+        let unlocked = match self.map.write() {
+            Err(_) => return Err(anyhow!("Directory lock is probably poisoned.")),
+            Ok(l) => l,
+        };
+        Ok(GlobalDepth {
+            global_depth: unlocked[0],
+            lock: unlocked,
+        })
+    }
+
+    fn grow_if_eq(&self, local_depth: u8) -> Result<u8> {
+        let mut unlocked = match self.map.write() {
+            Err(_) => return Err(anyhow!("Directory lock is probably poisoned.")),
+            Ok(l) => l,
+        };
+        if unlocked[0] > local_depth {
+            return Ok(unlocked[0]);
+        }
+        self.grow_locked(&mut unlocked)
+    }
+
+    fn segments(&self) -> Result<impl Iterator<Item = (u32, u64, u64, u8)> + '_> {
+        let unlocked = match self.map.read() {
+            Err(_) => return Err(anyhow!("Directory lock is probably poisoned.")),
+            Ok(l) => l,
+        };
+        let global_depth = unlocked[0];
+        Ok(DirectorySegments::new(unlocked, 1, global_depth))
+    }
+
+    fn max_depth(&self) -> u8 {
+        self.max_depth
+    }
+}
+
+/// Walks an `MMapDirectory` or a `MemoryDirectory`. Every segment owns a contiguous run of
+/// `2^(G - L)` directory entries that is aligned to its own length, so the length of a run can be
+/// found by doubling a candidate length and only checking its last entry. This means we read
+/// O(log n) entries per segment instead of every entry in the directory.
+pub struct DirectorySegments<'a> {
+    map: ShardedLockReadGuard<'a, MmapMut>,
+    // The offset of the first directory entry in `map`
+    base: usize,
+    global_depth: u8,
+    entries: u64,
+    next: u64,
+}
+
+impl<'a> DirectorySegments<'a> {
+    /// Walks the `2^global_depth` little-endian u32 entries that start at `base` in `map`. The
+    /// caller is responsible for checking that `map` is large enough.
+    pub(crate) fn new(
+        map: ShardedLockReadGuard<'a, MmapMut>,
+        base: usize,
+        global_depth: u8,
+    ) -> Self {
+        Self {
+            map,
+            base,
+            global_depth,
+            entries: 1 << global_depth,
+            next: 0,
+        }
+    }
+
+    fn entry(&self, i: u64) -> u32 {
+        let offset = self.base + (i * 4) as usize;
+        let mut buf: [u8; 4] = [0; 4];
+        buf.copy_from_slice(&self.map[offset..offset + 4]);
+        u32::from_le_bytes(buf)
+    }
+}
+
+impl Iterator for DirectorySegments<'_> {
+    type Item = (u32, u64, u64, u8);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.entries {
+            return None;
+        }
+        let first = self.next;
+        let segment_index = self.entry(first);
+        let mut count: u64 = 1;
+        let mut depth_delta: u8 = 0;
+        while first.is_multiple_of(count * 2)
+            && first + count * 2 <= self.entries
+            && self.entry(first + count * 2 - 1) == segment_index
+        {
+            count *= 2;
+            depth_delta += 1;
+        }
+        self.next = first + count;
+        Some((segment_index, first, count, self.global_depth - depth_delta))
+    }
 }
 
 impl Deref for GlobalDepth<'_> {
@@ -214,3 +626,100 @@ impl Deref for GlobalDepth<'_> {
         &self.global_depth
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn temp_directory() -> (TempDir, MMapDirectory) {
+        let dir = TempDir::new().unwrap();
+        let directory = MMapDirectory::init(dir.path().join("directory.bin")).unwrap();
+        (dir, directory)
+    }
+
+    #[test]
+    fn new_directory_has_one_segment() {
+        let (_dir, directory) = temp_directory();
+        let segments: Vec<_> = directory.segments().unwrap().collect();
+        assert_eq!(segments, vec![(0, 0, 1, 0)]);
+    }
+
+    #[test]
+    fn segments_reports_ranges_and_local_depths() {
+        let (_dir, directory) = temp_directory();
+        directory.grow_if_eq(0).unwrap();
+        directory.grow_if_eq(1).unwrap();
+        directory.grow_if_eq(2).unwrap();
+        // global_depth = 3, 8 entries. Lay out segments with local depths 1, 2, 3, 3
+        let mut gd = directory.global_depth().unwrap();
+        for (i, index) in [0, 0, 0, 0, 1, 1, 2, 3].into_iter().enumerate() {
+            directory
+                .set_segment_index(i as u64, index, &mut gd)
+                .unwrap();
+        }
+        drop(gd);
+        let segments: Vec<_> = directory.segments().unwrap().collect();
+        assert_eq!(
+            segments,
+            vec![(0, 0, 4, 1), (1, 4, 2, 2), (2, 6, 1, 3), (3, 7, 1, 3)]
+        );
+    }
+
+    #[test]
+    fn map_options_are_reapplied_after_growing() {
+        let dir = TempDir::new().unwrap();
+        let options = MapOptions {
+            mlock: false,
+            huge_pages: false,
+            prefault: true,
+        };
+        let directory =
+            MMapDirectory::with_options(dir.path().join("directory.bin"), options).unwrap();
+        let populated = cfg!(any(target_os = "linux", target_os = "android"));
+        assert_eq!(directory.map_stats().prefaulted, populated);
+        directory.grow_if_eq(0).unwrap();
+        assert_eq!(directory.map_stats().prefaulted, populated);
+        assert!(!directory.map_stats().mlocked);
+        assert_eq!(directory.segment_index(u64::MAX).unwrap(), 0);
+    }
+
+    #[test]
+    fn set_segment_range_updates_every_entry() {
+        let (_dir, directory) = temp_directory();
+        directory.grow_if_eq(0).unwrap();
+        directory.grow_if_eq(1).unwrap();
+        let mut gd = directory.global_depth().unwrap();
+        directory.set_segment_range(2, 2, 1, &mut gd).unwrap();
+        drop(gd);
+        let segments: Vec<_> = directory.segments().unwrap().collect();
+        assert_eq!(segments, vec![(0, 0, 2, 1), (1, 2, 2, 1)]);
+        let mut gd = directory.global_depth().unwrap();
+        assert!(directory.set_segment_range(3, 2, 1, &mut gd).is_err());
+    }
+
+    #[test]
+    fn memory_directory_grows_like_a_mapped_one() {
+        let directory = MemoryDirectory::new().unwrap().with_max_depth(2);
+        let segments: Vec<_> = directory.segments().unwrap().collect();
+        assert_eq!(segments, vec![(0, 0, 1, 0)]);
+        assert_eq!(directory.grow_if_eq(0).unwrap(), 1);
+        assert_eq!(directory.grow_if_eq(0).unwrap(), 1);
+        let mut gd = directory.global_depth().unwrap();
+        directory.set_segment_index(1, 1, &mut gd).unwrap();
+        drop(gd);
+        assert_eq!(directory.grow().unwrap(), 4);
+        let segments: Vec<_> = directory.segments().unwrap().collect();
+        assert_eq!(segments, vec![(0, 0, 2, 1), (1, 2, 2, 1)]);
+        assert_eq!(directory.segment_index(u64::MAX).unwrap(), 1);
+        assert!(directory.grow_if_eq(2).is_err());
+    }
+
+    #[test]
+    fn grown_entries_only_fit_up_to_depth_31() {
+        assert_eq!(grown_entries(0, MAX_DEPTH).unwrap(), 2);
+        assert_eq!(grown_entries(30, MAX_DEPTH).unwrap(), 1 << 31);
+        assert!(grown_entries(31, MAX_DEPTH).is_err());
+        assert!(grown_entries(2, 2).is_err());
+    }
+}
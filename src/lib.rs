@@ -3,3 +3,4 @@ pub mod locking;
 pub mod meh;
 pub mod segment;
 pub mod serializer;
+pub mod single_file;
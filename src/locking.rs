@@ -2,7 +2,9 @@ use std::default::Default;
 
 use anyhow::{anyhow, Context, Result};
 use crossbeam::utils::CachePadded;
-use parking_lot::RwLock;
+use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::segment::Geometry;
 
 pub struct StripedLock<T> {
     locks: Vec<CachePadded<RwLock<T>>>,
@@ -10,9 +12,16 @@ pub struct StripedLock<T> {
 
 impl<T: Default> StripedLock<T> {
     pub fn init(capacity: usize) -> Self {
+        Self::init_with(capacity, Default::default)
+    }
+}
+
+impl<T> StripedLock<T> {
+    /// Creates a StripedLock whose values are created by calling `f`.
+    pub fn init_with(capacity: usize, f: impl Fn() -> T) -> Self {
         let mut locks = Vec::with_capacity(capacity);
         for _ in 0..capacity {
-            let padded_mutex = CachePadded::new(RwLock::new(Default::default()));
+            let padded_mutex = CachePadded::new(RwLock::new(f()));
             locks.push(padded_mutex);
         }
         Self { locks }
@@ -21,7 +30,7 @@ impl<T: Default> StripedLock<T> {
     // Retrieves a RwLock from the
     pub fn get(&self, key: u32) -> &RwLock<T> {
         let i = key as usize % self.locks.len();
-        let cached_lock = &self
+        let cached_lock = self
             .locks
             .get(i)
             .with_context(|| {
@@ -31,33 +40,58 @@ impl<T: Default> StripedLock<T> {
                 )
             })
             .unwrap(); // This shouldn't ever be possible
-        &cached_lock
+        cached_lock
     }
 }
 
 pub struct SegmentNode {
-    bucket_locks: [RwLock<()>; 64],
+    bucket_locks: Vec<RwLock<()>>,
 }
 
 impl SegmentNode {
+    /// Creates a node with a lock for each of a segment's `buckets`, including the stash.
+    pub fn new(buckets: u32) -> Self {
+        let mut bucket_locks = Vec::with_capacity(buckets as usize);
+        for _ in 0..buckets {
+            bucket_locks.push(RwLock::new(()));
+        }
+        Self { bucket_locks }
+    }
+
     pub fn get_bucket_lock(&self, bucket_index: u32) -> Result<&RwLock<()>> {
-        if bucket_index > 64 {
-            return Err(anyhow!("Somehow got number larger than 64. This is a bug."));
+        if bucket_index as usize >= self.bucket_locks.len() {
+            return Err(anyhow!(
+                "Somehow got number larger than {}. This is a bug.",
+                self.bucket_locks.len()
+            ));
         }
-        return Ok(&self.bucket_locks[bucket_index as usize]);
+        Ok(&self.bucket_locks[bucket_index as usize])
+    }
+
+    /// Read locks every bucket in `bucket_indexes`. Locks are always taken in ascending bucket
+    /// order so that overlapping probe windows can't deadlock.
+    pub fn read_buckets(&self, bucket_indexes: &[u32]) -> Result<Vec<RwLockReadGuard<'_, ()>>> {
+        let mut sorted = bucket_indexes.to_vec();
+        sorted.sort_unstable();
+        sorted
+            .into_iter()
+            .map(|i| Ok(self.get_bucket_lock(i)?.read()))
+            .collect()
+    }
+
+    /// Write locks every bucket in `bucket_indexes`, in the same order as `read_buckets`.
+    pub fn write_buckets(&self, bucket_indexes: &[u32]) -> Result<Vec<RwLockWriteGuard<'_, ()>>> {
+        let mut sorted = bucket_indexes.to_vec();
+        sorted.sort_unstable();
+        sorted
+            .into_iter()
+            .map(|i| Ok(self.get_bucket_lock(i)?.write()))
+            .collect()
     }
 }
 
 impl Default for SegmentNode {
     fn default() -> Self {
-        let mut locks = Vec::with_capacity(64);
-        for _ in 0..64 {
-            locks.push(RwLock::new(()));
-        }
-        Self {
-            bucket_locks: locks.try_into().unwrap_or_else(|_| {
-                panic!("Unable to cast vec of locks to array when initializing a new SegmentNode.")
-            }),
-        }
+        Self::new(Geometry::DEFAULT.segment_buckets())
     }
 }
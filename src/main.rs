@@ -5,6 +5,7 @@ mod locking;
 pub mod meh;
 pub mod segment;
 pub mod serializer;
+pub mod single_file;
 
 use std::sync::Arc;
 use std::thread::{JoinHandle, spawn};
@@ -13,25 +14,53 @@ use std::time::Instant;
 use crate::directory::{Directory, MMapDirectory};
 use crate::locking::StripedLock;
 use crate::meh::MehDB;
-use crate::segment::ThreadSafeFileSegmenter;
+#[cfg(unix)]
+use crate::segment::DirectSegmenter;
+#[cfg(target_os = "linux")]
+use crate::segment::UringSegmenter;
+use crate::segment::{
+    MmapSegmenter, Placement, Segmenter, StripedSegmenter, ThreadSafeFileSegmenter,
+};
 
 use anyhow::{Context, Result};
-use highway;
 use log::{error, info};
 
 fn main() -> Result<()> {
     pretty_env_logger::init();
-    let segmenter = ThreadSafeFileSegmenter::init("./segment.bin".into())?;
     let directory = MMapDirectory::init("./directory.bin".into())?;
+    // Pick which segmenter to benchmark with MEHDB_SEGMENTER=mmap, direct, uring or striped. They
+    // all share a file format except for the O_DIRECT one, which has its own page-aligned layout.
+    // io_uring is only available on Linux and the O_DIRECT one on unix.
+    // The striped segmenter uses the colon separated files in MEHDB_STRIPES.
+    match std::env::var("MEHDB_SEGMENTER").as_deref() {
+        Ok("mmap") => run(directory, MmapSegmenter::init("./segment.bin".into())?),
+        #[cfg(unix)]
+        Ok("direct") => run(directory, DirectSegmenter::init("./segment.direct".into())?),
+        #[cfg(target_os = "linux")]
+        Ok("uring") => run(directory, UringSegmenter::init("./segment.bin".into())?),
+        Ok("striped") => {
+            let stripes = std::env::var("MEHDB_STRIPES")
+                .unwrap_or_else(|_| "./segment.0.bin:./segment.1.bin".to_string());
+            let paths = stripes.split(':').map(Into::into).collect();
+            let segmenter = StripedSegmenter::init(paths, Placement::LeastFull)?;
+            run(directory, segmenter)
+        }
+        _ => run(
+            directory,
+            ThreadSafeFileSegmenter::init("./segment.bin".into())?,
+        ),
+    }
+}
+
+fn run<S>(directory: MMapDirectory, segmenter: S) -> Result<()>
+where
+    S: Segmenter + Clone + Send + 'static,
+{
     const WRITE_THREADS: usize = 12;
     const READ_THREADS: usize = 16;
     let lock = StripedLock::init((WRITE_THREADS * 50) + 10);
-    let mehdb = MehDB {
-        hasher_key: highway::Key([53252, 2352323, 563956259, 234832]),
-        directory: Arc::new(directory),
-        segmenter: segmenter.clone(),
-        lock: Arc::new(lock),
-    };
+    let mut mehdb: MehDB<_, _> = MehDB::with_parts(directory, segmenter.clone())?;
+    mehdb.lock = Arc::new(lock);
     let mut write_threads: Vec<JoinHandle<()>> = Vec::with_capacity(4);
     const RECORDS: usize = 10_000_000;
     let start_time = Instant::now();
@@ -90,7 +119,7 @@ fn main() -> Result<()> {
                 }
                 //println!("k: {} v: {:?}", i, r);
             }
-            return errors;
+            errors
         }));
     }
     for (thread_id, thread) in read_threads.into_iter().enumerate() {
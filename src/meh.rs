@@ -1,41 +1,115 @@
 use crate::directory::{Directory, MMapDirectory};
 use crate::locking::{SegmentNode, StripedLock};
 use crate::segment::{
-    BUCKETS_PER_SEGMENT, Bucket, Record, Segment, Segmenter, ThreadSafeFileSegmenter,
+    Bucket, FixedValue, Record, Segment, SegmentBuf, Segmenter, ThreadSafeFileSegmenter,
 };
-use anyhow::{Context, Result};
+use crate::single_file::SingleFile;
+use anyhow::{Context, Result, anyhow};
 use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::marker::PhantomData;
 use std::path::Path;
 use std::sync::Arc;
 
 use highway::{self, HighwayHash, HighwayHasher};
-use parking_lot::{RwLockUpgradableReadGuard, RwLockWriteGuard};
+use parking_lot::{RwLockReadGuard, RwLockUpgradableReadGuard, RwLockWriteGuard};
+use thiserror::Error;
 
-// My Extendible Hash Database
-#[derive(Clone)]
-pub struct MehDB {
+// My Extendible Hash Database. Records hold a `V`, which has to be as wide as the segmenter's
+// `Geometry::value_size`.
+pub struct MehDB<D = MMapDirectory, S = ThreadSafeFileSegmenter, V = u64> {
     // TODO: make an init or something so we don't have to deal with this
     pub hasher_key: highway::Key,
-    pub directory: Arc<MMapDirectory>,
-    pub segmenter: ThreadSafeFileSegmenter,
+    pub directory: Arc<D>,
+    pub segmenter: S,
     pub lock: Arc<StripedLock<SegmentNode>>,
+    value: PhantomData<fn() -> V>,
+}
+
+/// Counts of what's stored in a database, gathered by scanning every segment.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub segments: u32,
+    /// The number of records that can be found with `get`.
+    pub records: u64,
+    /// Records that are still in a segment they no longer belong to. Splits clear the records
+    /// they migrate, so these are only left behind by a split that didn't finish.
+    pub stale_records: u64,
+}
+
+/// Errors from splitting a segment that callers may want to handle. They're returned wrapped in
+/// an `anyhow::Error`, use `downcast_ref` to get at them.
+#[derive(Debug, Error)]
+pub enum SplitError {
+    /// A record didn't fit in its segment, and the segment is already as deep as the directory
+    /// allows. This takes a lot of keys whose hashes share a long prefix.
+    #[error(
+        "Segment {segment} is at the maximum depth of {depth} and can't be split to make room for hash key {hash_key:#x}"
+    )]
+    MaxDepth {
+        segment: u32,
+        depth: u8,
+        hash_key: u64,
+    },
+}
+
+impl<D, S: Clone, V> Clone for MehDB<D, S, V> {
+    fn clone(&self) -> Self {
+        Self {
+            hasher_key: self.hasher_key,
+            directory: self.directory.clone(),
+            segmenter: self.segmenter.clone(),
+            lock: self.lock.clone(),
+            value: PhantomData,
+        }
+    }
 }
 
-/// A Extendible hashing implementation that does not support multithreading.
 impl MehDB {
+    /// Opens (or creates) a database made up of `segment.bin` and `directory.bin` inside `dir`.
     pub fn new(dir: impl AsRef<Path>) -> Result<Self> {
         let segmenter = ThreadSafeFileSegmenter::init(dir.as_ref().join("./segment.bin"))?;
         let directory = MMapDirectory::init(dir.as_ref().join("directory.bin"))?;
-        let lock = StripedLock::init(1024);
+        Self::with_parts(directory, segmenter)
+    }
+}
+
+impl MehDB<SingleFile, SingleFile> {
+    /// Opens (or creates) a database where the directory and segments share the single file at
+    /// `path`.
+    pub fn open_single_file(path: impl AsRef<Path>) -> Result<Self> {
+        let file = SingleFile::init(path.as_ref().to_path_buf().into())?;
+        Self::with_parts(file.clone(), file)
+    }
+}
+
+/// A Extendible hashing implementation that does not support multithreading.
+impl<D: Directory, S: Segmenter, V: FixedValue> MehDB<D, S, V> {
+    /// Puts a database together from its parts. Fails if the segmenter's records don't hold
+    /// values as wide as a `V`.
+    pub fn with_parts(directory: D, segmenter: S) -> Result<Self> {
+        let geometry = segmenter.geometry();
+        if geometry.value_size as usize != V::SIZE {
+            return Err(anyhow!(
+                "Segments hold {} byte values, but the database was opened for {} byte values",
+                geometry.value_size,
+                V::SIZE
+            ));
+        }
+        let buckets = geometry.segment_buckets();
+        let lock = StripedLock::init_with(1024, || SegmentNode::new(buckets));
         Ok(MehDB {
             hasher_key: highway::Key([53252, 2352323, 563956259, 234832]),
             directory: Arc::new(directory),
-            segmenter: segmenter.clone(),
+            segmenter,
             lock: Arc::new(lock),
+            value: PhantomData,
         })
     }
 
-    fn bucket_for_key(&mut self, key: &[u64; 4]) -> Result<Bucket> {
+    /// Finds the segment `key` belongs to and read locks it. Returns the segment's index along
+    /// with the lock guard.
+    fn read_lock_segment(&self, key: &[u64; 4]) -> Result<(u32, RwLockReadGuard<'_, SegmentNode>)> {
         let mut segment_index = self
             .directory
             .segment_index(key[0])
@@ -66,28 +140,150 @@ impl MehDB {
         }
         //TODO: remove this assert
         assert_eq!(segment_index_double_check, segment_index);
-        let bucket_index = ((BUCKETS_PER_SEGMENT - 1) as u64 & key[3]) as u32;
-        // Acquire a read lock on the bucket
-        let _bucket_lock = segment_node
-            .get_bucket_lock(bucket_index)
-            .context("Getting bucket lock")?
-            .read();
+        Ok((segment_index, segment_node))
+    }
+
+    fn find(&mut self, key: &[u64; 4]) -> Result<Option<Record<V>>> {
+        let (segment_index, segment_node) = self.read_lock_segment(key)?;
+        let geometry = self.segmenter.geometry();
+        let window = geometry.probe_window(geometry.home_bucket(key[3]));
+        // Acquire a read lock on every bucket the record could be in
+        let _bucket_locks = segment_node
+            .read_buckets(&window)
+            .context("Getting bucket locks")?;
         let segment = self
             .segmenter
             .segment(segment_index)
             .with_context(|| format!("Unable to read segment at offset {}", segment_index))?;
-        debug!("Reading bucket at index: {}", bucket_index);
-        self.segmenter.bucket(&segment, bucket_index)
+        for bucket_index in window {
+            debug!("Reading bucket at index: {}", bucket_index);
+            let record = self.segmenter.find_record(&segment, bucket_index, key[0])?;
+            if record.is_some() {
+                return Ok(record);
+            }
+        }
+        // Records whose probe window was full when they were inserted are in the stash
+        let stash = geometry.stash();
+        let _stash_locks = segment_node
+            .read_buckets(&stash)
+            .context("Getting stash locks")?;
+        for bucket_index in stash {
+            let record = self
+                .segmenter
+                .find_record(&segment, bucket_index, key[0])
+                .context("Reading stash")?;
+            if record.is_some() {
+                return Ok(record);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Inserts or updates a record in `segment`, which must be locked. Returns false
+    /// without changing anything if neither the record's probe window nor the stash has room
+    /// for it, in which case the segment has to be split.
+    fn insert(
+        &self,
+        segment_node: &SegmentNode,
+        segment: &Segment,
+        hash_key: &[u64; 4],
+        value: &V,
+    ) -> Result<bool> {
+        let geometry = self.segmenter.geometry();
+        let window = geometry.probe_window(geometry.home_bucket(hash_key[3]));
+        let _bucket_locks = segment_node
+            .write_buckets(&window)
+            .context("Getting bucket locks")?;
+        let mut buckets = self
+            .segmenter
+            .buckets(segment, &window)
+            .with_context(|| format!("Reading buckets {:?}", window))?;
+        // Update the record if it's already in the probe window
+        if let Some(bucket) = buckets
+            .iter_mut()
+            .find(|b| b.get_value::<V>(hash_key[0]).is_some())
+        {
+            return self.write_record(bucket, hash_key[0], value, segment.depth);
+        }
+        // The record may have overflowed into the stash before. Every put to the segment looks
+        // there, so only read lock it until we know we have to change it.
+        let stash = geometry.stash();
+        let stash_locks = segment_node
+            .read_buckets(&stash)
+            .context("Getting stash locks")?;
+        let stashed = self
+            .segmenter
+            .buckets(segment, &stash)
+            .context("Reading stash")?;
+        if !stashed
+            .iter()
+            .any(|b| b.get_value::<V>(hash_key[0]).is_some())
+            && let Some(bucket) = buckets
+                .iter_mut()
+                .find(|b| b.has_room(hash_key[0], segment.depth))
+        {
+            return self.write_record(bucket, hash_key[0], value, segment.depth);
+        }
+        drop(stash_locks);
+        // We still hold the probe window, so no one else can insert this record while the stash
+        // is unlocked. Other records may have been stashed in the meantime, so read it again.
+        let _stash_locks = segment_node
+            .write_buckets(&stash)
+            .context("Getting stash locks")?;
+        let mut stashed = self
+            .segmenter
+            .buckets(segment, &stash)
+            .context("Reading stash")?;
+        let target = stashed
+            .iter()
+            .position(|b| b.get_value::<V>(hash_key[0]).is_some())
+            .or_else(|| {
+                stashed
+                    .iter()
+                    .position(|b| b.has_room(hash_key[0], segment.depth))
+            });
+        match target {
+            Some(i) => {
+                debug!("Probe window is full, stashing record.");
+                self.write_record(&mut stashed[i], hash_key[0], value, segment.depth)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn write_record(&self, bucket: &mut Bucket, hk: u64, value: &V, depth: u8) -> Result<bool> {
+        let index = bucket
+            .put_value(hk, value, depth)
+            .context("Inserting record into bucket with room")?;
+        debug!("Successfully inserted record to bucket.");
+        info!("Writing record's slot to segment.");
+        // Only the one slot changed, so there's no need to rewrite the whole bucket
+        self.segmenter
+            .write_slot(bucket, index)
+            .with_context(|| format!("Saving updated bucket at offset {}", bucket.offset))?;
+        Ok(true)
     }
 
-    pub fn put(&mut self, key: &[u8], value: u64) -> Result<()> {
+    pub fn put(&mut self, key: &[u8], value: V) -> Result<()> {
         let hasher = HighwayHasher::new(self.hasher_key);
         // We only need the first u64 of the returned value because
         // It's unlikely we have the hard drive space to support a u64 deep directory
         // and we *definitely* don't have the RAM to.
         // TODO: support the full 256 bit keyspace for magical distributed system support
-        let hash_key = hasher.hash256(&key);
-        info!("hash_key: {:?}\tvalue: {}", hash_key, value);
+        let hash_key = hasher.hash256(key);
+        info!("hash_key: {:?}\tvalue: {:?}", hash_key, value);
+        // Every time around splits the segment the record belongs to, which fails once it's at
+        // the maximum depth, so this can't go on forever.
+        while !self.try_put(&hash_key, &value)? {
+            debug!("Record belongs to a new segment after splitting. Re-inserting record.");
+        }
+        Ok(())
+    }
+
+    /// Inserts a record, splitting its segment as many times as it takes to make room for it.
+    /// Returns false if a split moved the record to a new segment, which we don't have locked,
+    /// so the insert has to be retried.
+    fn try_put(&self, hash_key: &[u64; 4], value: &V) -> Result<bool> {
         let mut segment_index = self
             .directory
             .segment_index(hash_key[0])
@@ -119,74 +315,184 @@ impl MehDB {
         //TODO: remove this assert
         assert_eq!(segment_index_double_check, segment_index);
 
-        let bucket_index = ((BUCKETS_PER_SEGMENT - 1) as u64 & hash_key[3]) as u32;
-        let bucket_lock = segment_node
-            .get_bucket_lock(bucket_index)
-            .context("Getting bucket lock")?
-            .write();
         debug!("Segment index {}", segment_index);
+        let mut segment = self
+            .segmenter
+            .segment(segment_index)
+            .with_context(|| format!("Unable to read segment with index {}", segment_index))?;
+        debug!("Inserting record into bucket...");
+        if self.insert(&segment_node, &segment, hash_key, value)? {
+            return Ok(true);
+        }
+        // Overflowed the probe window and the stash!
+        info!("Stash overflowed. Allocating new segment and splitting.");
+        let write_lock = RwLockUpgradableReadGuard::upgrade(segment_node);
+        // Reused by every split of this segment
+        let geometry = self.segmenter.geometry();
+        let mut bufs = [SegmentBuf::new(geometry), SegmentBuf::new(geometry)];
+        loop {
+            let offset = segment.offset;
+            self.split_segment(
+                segment_index,
+                &mut segment,
+                hash_key[0],
+                &mut bufs,
+                &write_lock,
+            )
+            .with_context(|| format!("Splitting segement at offset {}", offset))?;
+            // The upper half of the records went to the new segment
+            if (hash_key[0] >> (64 - segment.depth)) & 1 == 1 {
+                return Ok(false);
+            }
+            // The record stays here, but if none of its neighbours moved it still won't fit. We
+            // still hold the segment, so we can split it again straight away.
+            if self.insert(&write_lock, &segment, hash_key, value)? {
+                return Ok(true);
+            }
+            debug!("Record still doesn't fit after splitting, splitting again.");
+        }
+    }
+
+    /// Deletes `key`, returning whether it was present.
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool> {
+        let hasher = HighwayHasher::new(self.hasher_key);
+        let hash_key = hasher.hash256(key);
+        // Deletes never split, so a read lock on the segment is enough
+        let (segment_index, segment_node) = self.read_lock_segment(&hash_key)?;
+        let geometry = self.segmenter.geometry();
+        let window = geometry.probe_window(geometry.home_bucket(hash_key[3]));
+        let _bucket_locks = segment_node
+            .write_buckets(&window)
+            .context("Getting bucket locks")?;
         let segment = self
             .segmenter
             .segment(segment_index)
             .with_context(|| format!("Unable to read segment with index {}", segment_index))?;
-        debug!("Reading bucket at index: {}", bucket_index);
-        let mut bucket = self
+        for bucket_index in window {
+            let mut bucket = self
+                .segmenter
+                .bucket(&segment, bucket_index)
+                .with_context(|| format!("Reading bucket at index {}", bucket_index))?;
+            if let Some(index) = bucket.remove(hash_key[0]) {
+                self.segmenter
+                    .write_slot(&mut bucket, index)
+                    .with_context(|| format!("Saving bucket at offset {}", bucket.offset))?;
+                return Ok(true);
+            }
+        }
+        let stash = geometry.stash();
+        let _stash_locks = segment_node
+            .write_buckets(&stash)
+            .context("Getting stash locks")?;
+        let mut stashed = self
             .segmenter
-            .bucket(&segment, bucket_index)
-            .with_context(|| format!("Reading bucket at index {}", bucket_index))?;
-        debug!("Inserting record into bucket...");
-        match bucket.put(hash_key[0], value, segment.depth) {
-            // Overflowed the bucket!
-            Err(_) => {
-                info!("Bucket overflowed. Allocating new segment and splitting.");
-                // Drop the bucket lock before we split, we don't need it
-                // segment and maybe directory
-                drop(bucket_lock);
-                let offset = segment.offset;
-                let write_lock = RwLockUpgradableReadGuard::upgrade(segment_node);
-                self.split_segment(segment, hash_key[0], write_lock)
-                    .with_context(|| format!("Splitting segement at offset {}", offset))?;
-                // TODO: don't be so inneficient. We already know the hash_key!
-                // Call put again, it may end up in a new bucket or in the same one that's now had
-                // some records migrated to a new segment.
-                debug!("Re-inserting record.");
-                return self.put(key, value);
-            }
-            _ => {
-                debug!("Successfully inserted record to bucket.");
-            }
-        }
-        info!("Writing bucket to segment.");
-        self.segmenter
-            .write_bucket(&bucket)
-            .with_context(|| format!("Saving updated bucket at offset {}", bucket.offset))
+            .buckets(&segment, &stash)
+            .context("Reading stash")?;
+        for bucket in &mut stashed {
+            if let Some(index) = bucket.remove(hash_key[0]) {
+                self.segmenter
+                    .write_slot(bucket, index)
+                    .with_context(|| format!("Saving bucket at offset {}", bucket.offset))?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
     }
-    pub fn get(&mut self, key: &[u8]) -> Option<Record> {
+
+    /// Looks up every key in `keys`, returning the results in the same order. All of the keys
+    /// that are in the same segment are read with one batch of bucket reads, which a `Segmenter`
+    /// may be able to submit at once.
+    pub fn multi_get(&mut self, keys: &[&[u8]]) -> Result<Vec<Option<Record<V>>>> {
+        let hash_keys: Vec<[u64; 4]> = keys
+            .iter()
+            .map(|key| HighwayHasher::new(self.hasher_key).hash256(key))
+            .collect();
+        let mut results: Vec<Option<Record<V>>> = keys.iter().map(|_| None).collect();
+        let geometry = self.segmenter.geometry();
+        let stash = geometry.stash();
+        let mut pending: Vec<usize> = (0..keys.len()).collect();
+        while !pending.is_empty() {
+            let mut by_segment: HashMap<u32, Vec<usize>> = HashMap::new();
+            for i in pending.drain(..) {
+                let segment_index = self.directory.segment_index(hash_keys[i][0])?;
+                by_segment.entry(segment_index).or_default().push(i);
+            }
+            for (segment_index, batch) in by_segment {
+                let segment_node = self.lock.get(segment_index).read();
+                // A split may have moved some of the keys since we looked them up, they get
+                // retried in the next round
+                let mut batch_keys = Vec::with_capacity(batch.len());
+                for i in batch {
+                    match self.directory.segment_index(hash_keys[i][0])? == segment_index {
+                        true => batch_keys.push(i),
+                        false => pending.push(i),
+                    }
+                }
+                // The stash is read along with the probe windows, every key may be in it
+                let mut bucket_indexes: Vec<u32> = batch_keys
+                    .iter()
+                    .flat_map(|&i| geometry.probe_window(geometry.home_bucket(hash_keys[i][3])))
+                    .chain(stash.iter().copied())
+                    .collect();
+                bucket_indexes.sort_unstable();
+                bucket_indexes.dedup();
+                let _bucket_locks = segment_node
+                    .read_buckets(&bucket_indexes)
+                    .context("Getting bucket locks")?;
+                let segment = self.segmenter.segment(segment_index)?;
+                let buckets = self
+                    .segmenter
+                    .buckets(&segment, &bucket_indexes)
+                    .with_context(|| format!("Reading buckets of segment {}", segment_index))?;
+                for i in batch_keys {
+                    let window = geometry.probe_window(geometry.home_bucket(hash_keys[i][3]));
+                    results[i] = window.iter().chain(&stash).find_map(|bucket_index| {
+                        let b = bucket_indexes.binary_search(bucket_index).unwrap();
+                        buckets[b].get_value(hash_keys[i][0])
+                    });
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Option<Record<V>> {
         let hasher = HighwayHasher::new(self.hasher_key);
         // We only need the first u64 of the returned value because
         // It's unlikely we have the hard drive space to support a u64 deep directory
         // and we *definitely* don't have the RAM to.
         // TODO: support the full 256 bit keyspace for magical distributed system support
-        let hash_key = hasher.hash256(&key);
+        let hash_key = hasher.hash256(key);
         info!("hash_key: {:?}", hash_key);
-        let bucket = match self.bucket_for_key(&hash_key) {
-            Ok(b) => b,
+        match self.find(&hash_key) {
+            Ok(r) => r,
             Err(_) => {
                 warn!("No bucket found for key {:?}", key);
-                return None;
+                None
             }
-        };
-        bucket.get(hash_key[0])
+        }
     }
 
+    /// Splits the segment at `segment_index` in two, moving the records whose next bit is set to
+    /// a new segment. `segment`'s depth is updated to match. Both segments are read and written
+    /// whole through `bufs`.
     fn split_segment(
         &self,
-        segment: Segment,
+        segment_index: u32,
+        segment: &mut Segment,
         hk: u64,
-        _lock: RwLockWriteGuard<SegmentNode>,
+        bufs: &mut [SegmentBuf; 2],
+        _lock: &RwLockWriteGuard<SegmentNode>,
     ) -> Result<()> {
         info!("Splitting segment");
-        let mut segment = segment;
+        if segment.depth >= self.directory.max_depth() {
+            return Err(SplitError::MaxDepth {
+                segment: segment_index,
+                depth: segment.depth,
+                hash_key: hk,
+            }
+            .into());
+        }
         // If we need to expand the directory size
         let global_depth = self
             .directory
@@ -194,36 +500,48 @@ impl MehDB {
             .context("Getting global depth, growing if local_depth == global_depth")?;
         debug!("gobal_depth: {}", global_depth);
         let new_depth = segment.depth + 1;
-        // The buckets that are being allocated to the new segment
-        let mut new_buckets = Vec::<Bucket>::with_capacity(BUCKETS_PER_SEGMENT);
+        let [old, new] = bufs;
+        self.segmenter
+            .read_segment(segment, old)
+            .context("Reading segment to split")?;
+        new.clear(new_depth);
+        let geometry = self.segmenter.geometry();
         let mask = (hk >> (64 - new_depth)) | 1;
-        for bi in 0..BUCKETS_PER_SEGMENT {
-            let old_bucket = self
-                .segmenter
-                .bucket(&segment, bi as u32)
-                .context("Reading old bucket for segment split")?;
-            let mut new_bucket = Bucket::new();
-            for record in old_bucket.iter() {
-                if record.hash_key >> (64 - new_depth) == mask {
+        // Stashed records stay in the stash of whichever segment they end up in
+        for index in 0..geometry.segment_buckets() {
+            let mut old_bucket = old.bucket(index);
+            let mut new_bucket = Bucket::with_layout(geometry.bucket_layout());
+            let migrated: Vec<u64> = old_bucket
+                .iter_values::<V>()
+                .filter(|record| record.hash_key >> (64 - new_depth) == mask)
+                .map(|record| {
                     debug!(
                         "Insering record with hk {} into new bucket",
                         record.hash_key
                     );
                     new_bucket
-                        .put(record.hash_key, record.value, new_depth)
-                        .context("Inserting record in new bucket.")?;
-                }
+                        .put_value(record.hash_key, &record.value, new_depth)
+                        .context("Inserting record in new bucket.")
+                        .map(|_| record.hash_key)
+                })
+                .collect::<Result<_>>()?;
+            if migrated.is_empty() {
+                continue;
             }
-            new_buckets.insert(bi, new_bucket);
+            // The originals are cleared out of the old segment once the new one is live, when
+            // the old segment is written back
+            for hk in &migrated {
+                old_bucket.remove(*hk);
+            }
+            old.set_bucket(index, &old_bucket);
+            new.set_bucket(index, &new_bucket);
         }
 
-        //TODO: refactor this to be more idiomatic
         info!("Allocating new segment with depth {}", new_depth);
-        let (new_segment_index, _) =
-            match self.segmenter.allocate_with_buckets(new_buckets, new_depth) {
-                Ok(s) => s,
-                Err(e) => return Err(e.context("Allocating new segment with populated buckets.")),
-            };
+        let (new_segment_index, _) = self
+            .segmenter
+            .allocate_with_segment(new)
+            .context("Allocating new segment with populated buckets.")?;
         let mut global_depth = self
             .directory
             .global_depth()
@@ -232,23 +550,340 @@ impl MehDB {
         let mut start_dir_entry = if segment.depth == 0 {
             0
         } else {
-            hk >> 64 - segment.depth
+            hk >> (64 - segment.depth)
         };
-        start_dir_entry = start_dir_entry << (*global_depth - segment.depth);
-        start_dir_entry = start_dir_entry - (start_dir_entry % 2);
-        for i in 0..step {
-            self.directory.set_segment_index(
-                start_dir_entry + i + step,
+        start_dir_entry <<= *global_depth - segment.depth;
+        start_dir_entry -= start_dir_entry % 2;
+        self.directory
+            .set_segment_range(
+                start_dir_entry + step,
+                step,
                 new_segment_index,
                 &mut global_depth,
-            )?;
-        }
+            )
+            .context("Pointing directory entries at new segment")?;
         drop(global_depth);
-        // Update the original segment
+        // The new segment is live, so update the original segment's depth and clear the
+        // records that were copied out of it in one go
         segment.depth += 1;
+        old.set_depth(segment.depth);
         self.segmenter
-            .update_segment(segment)
-            .context("Updating existing segment depth")?;
+            .write_segment(segment, old)
+            .context("Updating split segment")?;
         Ok(())
     }
+
+    /// Writes back anything the segmenter is buffering, like a `CachedSegmenter`'s dirty
+    /// buckets.
+    pub fn sync(&self) -> Result<()> {
+        self.segmenter.sync()
+    }
+
+    /// Scans every segment to count the live and stale records in the database.
+    pub fn stats(&self) -> Result<Stats> {
+        // Collect the segments up front, splits take the directory lock while holding a segment
+        // lock so we can't hold on to the directory while locking segments.
+        let segments: Vec<_> = self.directory.segments()?.collect();
+        let geometry = self.segmenter.geometry();
+        let all_buckets: Vec<u32> = (0..geometry.segment_buckets()).collect();
+        let mut stats = Stats::default();
+        let mut buf = SegmentBuf::new(geometry);
+        for (segment_index, first_entry, entry_count, depth) in segments {
+            let segment_node = self.lock.get(segment_index).read();
+            let _bucket_locks = segment_node.read_buckets(&all_buckets)?;
+            let segment = self.segmenter.segment(segment_index)?;
+            // The segment may have split since we read the directory. Splits keep the lower half
+            // of the segment's range in the original segment.
+            let global_depth = depth + entry_count.trailing_zeros() as u8;
+            let prefix = match depth {
+                0 => 0,
+                _ => first_entry >> (global_depth - depth),
+            } << (segment.depth - depth);
+            self.segmenter.read_segment(&segment, &mut buf)?;
+            for bucket_index in 0..geometry.segment_buckets() {
+                for record in buf.bucket_ref(bucket_index).iter_values::<V>() {
+                    if segment.depth == 0 || record.hash_key >> (64 - segment.depth) == prefix {
+                        stats.records += 1;
+                    } else {
+                        stats.stale_records += 1;
+                    }
+                }
+            }
+            stats.segments += 1;
+        }
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::segment::{Geometry, SegmentFileOptions};
+    use tempfile::TempDir;
+
+    const EXERCISE_RECORDS: u64 = 20_000;
+
+    /// Puts enough records in `db` to split it many times over and checks them with
+    /// `check_exercised`. Every backend's tests run it, so they only need to check what's
+    /// specific to the backend.
+    pub(crate) fn exercise<D: Directory, S: Segmenter>(mut db: MehDB<D, S>) -> MehDB<D, S> {
+        for i in 0..EXERCISE_RECORDS {
+            db.put(&i.to_le_bytes(), i * 2).unwrap();
+        }
+        check_exercised(&mut db);
+        db
+    }
+
+    /// Checks that `db` holds exactly the records `exercise` put in it, e.g. after reopening it.
+    pub(crate) fn check_exercised<D: Directory, S: Segmenter>(db: &mut MehDB<D, S>) {
+        for i in 0..EXERCISE_RECORDS {
+            let record = db
+                .get(&i.to_le_bytes())
+                .unwrap_or_else(|| panic!("Missing record for {}", i));
+            assert_eq!(record.value, i * 2);
+        }
+        let keys: Vec<[u8; 8]> = (0..EXERCISE_RECORDS + 100)
+            .map(|i| i.to_le_bytes())
+            .collect();
+        let keys: Vec<&[u8]> = keys.iter().map(|k| &k[..]).collect();
+        for (i, record) in db.multi_get(&keys).unwrap().into_iter().enumerate() {
+            match record {
+                Some(r) => assert_eq!(r.value, i as u64 * 2),
+                None => assert!(i as u64 >= EXERCISE_RECORDS, "Missing record for {}", i),
+            }
+        }
+        let stats = db.stats().unwrap();
+        assert!(stats.segments > 1);
+        assert_eq!((stats.records, stats.stale_records), (EXERCISE_RECORDS, 0));
+    }
+
+    #[test]
+    fn can_put_and_get_across_splits() {
+        let dir = TempDir::new().unwrap();
+        let db = exercise(MehDB::new(dir.path()).unwrap());
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.segments, db.segmenter.num_segments().unwrap());
+    }
+
+    #[test]
+    fn stats_count_stale_records() {
+        let dir = TempDir::new().unwrap();
+        let db = MehDB::new(dir.path()).unwrap();
+        // Split segment 0 by hand without clearing anything from it
+        db.directory.grow_if_eq(0).unwrap();
+        let (new_index, _) = db.segmenter.allocate_segment(1).unwrap();
+        let mut gd = db.directory.global_depth().unwrap();
+        db.directory
+            .set_segment_index(1, new_index, &mut gd)
+            .unwrap();
+        drop(gd);
+        let mut segment = db.segmenter.segment(0).unwrap();
+        segment.depth = 1;
+        db.segmenter.update_segment(segment).unwrap();
+        let segment = db.segmenter.segment(0).unwrap();
+        let mut bucket = db.segmenter.bucket(&segment, 0).unwrap();
+        bucket.put(1, 1, 0).unwrap();
+        bucket.put(1 << 63, 2, 0).unwrap();
+        db.segmenter.write_bucket(&mut bucket).unwrap();
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.segments, 2);
+        assert_eq!((stats.records, stats.stale_records), (1, 1));
+    }
+
+    #[test]
+    fn multi_get_matches_get() {
+        let dir = TempDir::new().unwrap();
+        let mut db = MehDB::new(dir.path()).unwrap();
+        for i in 0..5_000u64 {
+            db.put(&i.to_le_bytes(), i + 1).unwrap();
+        }
+        let keys: Vec<[u8; 8]> = (0..5_050u64).rev().map(|i| i.to_le_bytes()).collect();
+        let keys: Vec<&[u8]> = keys.iter().map(|k| &k[..]).collect();
+        let records = db.multi_get(&keys).unwrap();
+        assert_eq!(records.len(), keys.len());
+        for (key, record) in keys.iter().zip(records) {
+            assert_eq!(
+                record.map(|r| r.value),
+                db.get(key).map(|r| r.value),
+                "Mismatch for {:?}",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn can_delete_records() {
+        let dir = TempDir::new().unwrap();
+        let mut db = MehDB::new(dir.path()).unwrap();
+        for i in 0..100u64 {
+            db.put(&i.to_le_bytes(), 0).unwrap();
+        }
+        assert!(db.delete(&7u64.to_le_bytes()).unwrap());
+        assert!(!db.delete(&7u64.to_le_bytes()).unwrap());
+        assert!(db.get(&7u64.to_le_bytes()).is_none());
+        assert_eq!(db.get(&8u64.to_le_bytes()).unwrap().value, 0);
+        db.put(&7u64.to_le_bytes(), 1).unwrap();
+        assert_eq!(db.get(&7u64.to_le_bytes()).unwrap().value, 1);
+    }
+
+    #[test]
+    fn values_can_be_wider_than_a_u64() {
+        type Value = (u128, u32, u32);
+        let dir = TempDir::new().unwrap();
+        let geometry = Geometry {
+            value_size: Value::SIZE as u32,
+            ..Geometry::DEFAULT
+        };
+        let open = |geometry| {
+            let segmenter = ThreadSafeFileSegmenter::with_options(
+                dir.path().join("segment.bin"),
+                SegmentFileOptions {
+                    geometry,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            let directory = MMapDirectory::init(dir.path().join("directory.bin")).unwrap();
+            (directory, segmenter)
+        };
+        let value = |i: u64| ((i as u128) << 64 | i as u128, i as u32, !(i as u32));
+        let (directory, segmenter) = open(Some(geometry));
+        let mut db: MehDB<_, _, Value> = MehDB::with_parts(directory, segmenter).unwrap();
+        for i in 0..5_000u64 {
+            db.put(&i.to_le_bytes(), value(i)).unwrap();
+        }
+        assert!(db.segmenter.num_segments().unwrap() > 1);
+        db.put(&7u64.to_le_bytes(), value(8)).unwrap();
+        drop(db);
+        // The width is in the header, and has to match the database's value type
+        let (directory, segmenter) = open(None);
+        assert_eq!(segmenter.geometry().value_size, 24);
+        assert!(MehDB::<_, _, u64>::with_parts(directory, segmenter).is_err());
+        let (directory, segmenter) = open(None);
+        let mut db: MehDB<_, _, Value> = MehDB::with_parts(directory, segmenter).unwrap();
+        for i in 0..5_000u64 {
+            let expected = if i == 7 { value(8) } else { value(i) };
+            assert_eq!(db.get(&i.to_le_bytes()).unwrap().value, expected);
+        }
+        assert_eq!(db.stats().unwrap().records, 5_000);
+    }
+
+    fn stash_db(dir: &TempDir, stash_buckets: u32) -> MehDB {
+        let geometry = Geometry {
+            buckets_per_segment: 16,
+            probe_distance: 1,
+            stash_buckets,
+            ..Geometry::DEFAULT
+        };
+        let segmenter =
+            ThreadSafeFileSegmenter::init_with_geometry(dir.path().join("segment.bin"), geometry)
+                .unwrap();
+        let directory = MMapDirectory::init(dir.path().join("directory.bin")).unwrap();
+        MehDB::with_parts(directory, segmenter).unwrap()
+    }
+
+    #[test]
+    fn stash_delays_splits() {
+        // How many records fit in the first segment before it's split
+        let records_before_split = |stash_buckets| {
+            let dir = TempDir::new().unwrap();
+            let mut db = stash_db(&dir, stash_buckets);
+            let mut i = 0u64;
+            while db.segmenter.num_segments().unwrap() == 1 {
+                db.put(&i.to_le_bytes(), i).unwrap();
+                i += 1;
+            }
+            i - 1
+        };
+        assert!(records_before_split(4) > records_before_split(0));
+    }
+
+    #[test]
+    fn stashed_records_can_be_updated_and_deleted() {
+        let dir = TempDir::new().unwrap();
+        let mut db = stash_db(&dir, 8);
+        for i in 0..500u64 {
+            db.put(&i.to_le_bytes(), i).unwrap();
+        }
+        let geometry = db.segmenter.geometry();
+        let stashed: usize = (0..db.segmenter.num_segments().unwrap())
+            .map(|index| {
+                let segment = db.segmenter.segment(index).unwrap();
+                let stash = db.segmenter.buckets(&segment, &geometry.stash()).unwrap();
+                stash.iter().map(|b| b.len()).sum::<usize>()
+            })
+            .sum();
+        assert!(stashed > 0);
+        for i in 0..500u64 {
+            db.put(&i.to_le_bytes(), i + 1).unwrap();
+        }
+        for i in (0..500u64).step_by(2) {
+            assert!(db.delete(&i.to_le_bytes()).unwrap());
+        }
+        for i in 0..500u64 {
+            match db.get(&i.to_le_bytes()) {
+                Some(r) => assert_eq!(r.value, i + 1),
+                None => assert!(i % 2 == 0, "Missing record for {}", i),
+            }
+        }
+        assert_eq!(db.stats().unwrap().records, 250);
+    }
+
+    /// Puts records into `db`, whose directory can only be split twice, until it runs out of
+    /// depth.
+    fn run_out_of_depth<D: Directory, S: Segmenter>(mut db: MehDB<D, S>) {
+        let mut inserted = Vec::new();
+        let err = (0..100u64)
+            .find_map(|i| match db.put(&i.to_le_bytes(), i) {
+                Ok(()) => {
+                    inserted.push(i);
+                    None
+                }
+                Err(e) => Some(e),
+            })
+            .expect("Expected the directory to run out of depth");
+        match err.downcast_ref::<SplitError>() {
+            Some(SplitError::MaxDepth { depth, .. }) => assert_eq!(*depth, 2),
+            None => panic!("Unexpected error: {:?}", err),
+        }
+        assert!(inserted.len() <= 12);
+        for i in inserted {
+            assert_eq!(db.get(&i.to_le_bytes()).unwrap().value, i);
+        }
+    }
+
+    // Segments that hold 3 records
+    const TINY_SEGMENTS: Geometry = Geometry {
+        buckets_per_segment: 1,
+        probe_distance: 1,
+        stash_buckets: 0,
+        ..Geometry::DEFAULT
+    };
+
+    #[test]
+    fn splitting_past_the_maximum_depth_is_an_error() {
+        use crate::single_file::SingleFileConfig;
+        let dir = TempDir::new().unwrap();
+        let file = SingleFile::init(SingleFileConfig {
+            path: dir.path().join("db.meh"),
+            max_global_depth: 2,
+            geometry: Some(TINY_SEGMENTS),
+        })
+        .unwrap();
+        run_out_of_depth(MehDB::with_parts(file.clone(), file).unwrap());
+    }
+
+    #[test]
+    fn mmap_directories_have_a_maximum_depth_too() {
+        let dir = TempDir::new().unwrap();
+        let segmenter = ThreadSafeFileSegmenter::init_with_geometry(
+            dir.path().join("segment.bin"),
+            TINY_SEGMENTS,
+        )
+        .unwrap();
+        let directory = MMapDirectory::init(dir.path().join("directory.bin"))
+            .unwrap()
+            .with_max_depth(2);
+        run_out_of_depth(MehDB::with_parts(directory, segmenter).unwrap());
+    }
 }
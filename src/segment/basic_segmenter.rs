@@ -1,52 +1,161 @@
-pub struct BasicSegmenter<B: Read + Write + Seek> {
+use std::io::{self, BufWriter, Read, Seek, Write};
+use std::mem::size_of;
+use std::sync::Arc;
+
+use crate::segment::bucket::{Bucket, occupied_records};
+use crate::segment::segment::{
+    Geometry, PaddedHeader, Segment, SegmentBuf, SegmentMeta, SegmentSlots, SegmentTable,
+    Segmenter, load_geometry,
+};
+use crate::serializer::Serializable;
+
+use anyhow::{Context, Result, anyhow};
+use log::{debug, info, trace};
+use parking_lot::Mutex;
+
+const SEGMENTS_START: u64 = size_of::<PaddedHeader>() as u64;
+
+/// The buffer and the header fields that change with it. They're behind one lock because every
+/// read or write has to seek the buffer first.
+struct State<B> {
     buffer: B,
-    num_segments: u32,
-    header_dirty: bool,
-    // Maps segment index to depth
-    segment_depth_cache: HashMap<u32, u8>,
+    slots: SegmentSlots,
+}
+
+/// A `Segmenter` over anything that's `Read + Write + Seek`, like a `Cursor<Vec<u8>>` to keep the
+/// whole database in memory together with a `MemoryDirectory`. It uses the same format as
+/// `ThreadSafeFileSegmenter`'s segment file, so a `segment.bin` can be read into a buffer and used
+/// from there.
+///
+/// Every operation locks the buffer, so unlike the file backed segmenters there's no I/O
+/// concurrency. Clones share the buffer.
+pub struct BasicSegmenter<B: Read + Write + Seek> {
+    state: Arc<Mutex<State<B>>>,
+    table: Arc<SegmentTable>,
+    geometry: Geometry,
+}
+
+impl<B: Read + Write + Seek> Clone for BasicSegmenter<B> {
+    fn clone(&self) -> Self {
+        Self {
+            state: self.state.clone(),
+            table: self.table.clone(),
+            geometry: self.geometry,
+        }
+    }
 }
 
 impl<B: Read + Write + Seek> BasicSegmenter<B> {
-    pub fn init(mut buffer: B) -> Result<Self> {
+    pub fn init(buffer: B) -> Result<Self> {
+        Self::open(buffer, None)
+    }
+
+    /// The same as `init`, but an empty buffer is initialized with `geometry`. Opening a buffer
+    /// that already has a different geometry is an error.
+    pub fn init_with_geometry(buffer: B, geometry: Geometry) -> Result<Self> {
+        Self::open(buffer, Some(geometry))
+    }
+
+    fn open(mut buffer: B, geometry: Option<Geometry>) -> Result<Self> {
         buffer
             .seek(io::SeekFrom::Start(0))
-            .context("Seeking to beginning of segment file.")?;
+            .context("Seeking to beginning of segment buffer.")?;
         // Attempt to read the header and use it, otherwise initialize as new
         let mut first_time = false;
         let num_segments = match u32::unpack(&mut buffer) {
             Ok(n) => n,
-            Err(e) => {
+            Err(_) => {
                 first_time = true;
                 0
             }
         };
-        let mut out = Self {
-            num_segments,
-            buffer,
-            header_dirty: false,
-            segment_depth_cache: HashMap::new(),
+        if first_time {
+            // Write the whole header so the first segment lands at the right offset
+            buffer.seek(io::SeekFrom::Start(0))?;
+            buffer
+                .write_all(&[0; SEGMENTS_START as usize])
+                .context("Writing segment buffer header")?;
+        }
+        let geometry = load_geometry(&mut buffer, geometry)?;
+        let free = SegmentSlots::read_free_list(&mut buffer)?;
+        if let Some(index) = free.iter().find(|&&i| i >= num_segments) {
+            return Err(anyhow!("Free list contains unallocated segment {}", index));
+        }
+        let out = Self {
+            state: Arc::new(Mutex::new(State {
+                buffer,
+                slots: SegmentSlots { num_segments, free },
+            })),
+            table: Default::default(),
+            geometry,
         };
+        out.load_table(num_segments)
+            .context("Loading segment metadata table")?;
         if first_time {
             out.allocate_segment(0)
-                .with_context(|| format!("Error initializing initial segment."))?;
+                .context("Error initializing initial segment.")?;
         }
         Ok(out)
     }
 
-    fn write_num_segments(&mut self) -> Result<()> {
-        self.buffer
-            .seek(io::SeekFrom::Start(0))
-            .context("Seeking to beginning of segment file.")?;
-        self.buffer
-            .write_all(&self.num_segments.to_le_bytes())
-            .context("Syncing num_segments")?;
-        self.buffer.flush()?;
+    /// Returns the underlying buffer. Fails if there are other clones of this segmenter.
+    pub fn into_inner(self) -> Result<B> {
+        match Arc::try_unwrap(self.state) {
+            Ok(state) => Ok(state.into_inner().buffer),
+            Err(_) => Err(anyhow!("The segment buffer is still shared with a clone")),
+        }
+    }
+
+    /// The indexes of the segments that are free to be reused.
+    pub fn free_segments(&self) -> Vec<u32> {
+        self.state.lock().slots.free.clone()
+    }
+
+    /// Returns a snapshot of the in-memory metadata for the segment at `index`.
+    pub fn segment_meta(&self, index: u32) -> Option<SegmentMeta> {
+        self.table.meta(index)
+    }
+
+    /// Records a newly written segment in the segment table, committing it to the free list
+    /// first if it reused a freed segment.
+    fn commit_allocation(
+        &self,
+        state: &mut State<B>,
+        index: u32,
+        reused: bool,
+        depth: u8,
+        records: u32,
+    ) -> Result<()> {
+        if !reused {
+            self.table.push(depth, records, self.segment_offset(index));
+            return Ok(());
+        }
+        state.slots.write_free_list(&mut state.buffer)?;
+        self.table.reset(index, depth, records)
+    }
+
+    fn load_table(&self, num_segments: u32) -> Result<()> {
+        let mut state = self.state.lock();
+        let mut buf = vec![0; self.geometry.segment_size()];
+        for index in 0..num_segments {
+            let offset = self.segment_offset(index);
+            state.buffer.seek(io::SeekFrom::Start(offset))?;
+            state
+                .buffer
+                .read_exact(&mut buf)
+                .with_context(|| format!("Reading segment {} at offset {}", index, offset))?;
+            let records = occupied_records(&buf[1..], self.geometry.bucket_layout()) as u32;
+            self.table.push(buf[0], records, offset);
+        }
         Ok(())
     }
 
-    fn segment_offset_to_index(segment: &Segment) -> u32 {
-        return ((segment.offset as usize - size_of::<<BasicSegmenter<B> as Segmenter>::Header>())
-            / SEGMENT_SIZE) as u32;
+    fn segment_offset(&self, index: u32) -> u64 {
+        SEGMENTS_START + (index as usize * self.geometry.segment_size()) as u64
+    }
+
+    fn segment_index_of(&self, offset: u64) -> u32 {
+        ((offset - SEGMENTS_START) as usize / self.geometry.segment_size()) as u32
     }
 }
 
@@ -54,135 +163,212 @@ impl<B> Segmenter for BasicSegmenter<B>
 where
     B: Write + Read + Seek,
 {
-    // For this implementation, the header is simply the u32 num_segments
-    type Header = u32;
-    fn segment(&mut self, index: u32) -> Result<Segment> {
-        let offset = ((index as usize * SEGMENT_SIZE) + size_of::<Self::Header>()) as u64;
-        if let Some(cached_depth) = self.segment_depth_cache.get(&index) {
-            return Ok(Segment {
-                depth: *cached_depth,
-                offset,
-            });
-        }
-        self.buffer.seek(io::SeekFrom::Start(offset))?;
-        let mut buf: [u8; 1] = [0; 1];
-        self.buffer.read_exact(&mut buf).with_context(|| {
-            format!(
-                "Error reading segment local depth for segment index {}/{} with offset {}",
-                index, self.num_segments, offset
-            )
-        })?;
-        let depth = u8::from_le_bytes(buf);
-        self.segment_depth_cache.insert(index, depth);
-        Ok(Segment { depth, offset })
-    }
-
-    fn allocate_segment(&mut self, depth: u8) -> Result<(u32, Segment)> {
+    type Header = PaddedHeader;
+    fn segment(&self, index: u32) -> Result<Segment> {
+        self.table.segment(index)
+    }
+
+    fn allocate_segment(&self, depth: u8) -> Result<(u32, Segment)> {
         debug!("Allocating empty segment with depth {}", depth);
-        // Seek to the proper offset
-        let index = self.num_segments;
-        //------------------------------------------👇 for num_segments header in segments file
-        let offset = ((index as usize * SEGMENT_SIZE) + size_of::<Self::Header>()) as u64;
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        let (index, reused) = state.slots.take(&mut state.buffer)?;
+        let offset = self.segment_offset(index);
         debug!("New segment offset: {}", offset);
-        self.buffer
+        state
+            .buffer
             .seek(io::SeekFrom::Start(offset))
             .with_context(|| format!("Seeking to new segment's offset {}", offset))?;
-        let mut buf: [u8; SEGMENT_SIZE] = [0; SEGMENT_SIZE];
+        let mut buf = vec![0; self.geometry.segment_size()];
         buf[..1].copy_from_slice(&depth.to_le_bytes());
-        self.buffer
+        state
+            .buffer
             .write_all(&buf)
             .context("Writing new segment bytes")?;
-        self.buffer
+        state
+            .buffer
             .flush()
             .context("Flushing buffer after segment allocate.")?;
-        self.num_segments += 1;
-        self.write_num_segments()
-            .context("Syncronizing num_segments after allocating.")?;
+        self.commit_allocation(state, index, reused, depth, 0)?;
         Ok((index, Segment { depth, offset }))
     }
 
-    fn allocate_with_buckets(&mut self, buckets: Vec<Bucket>, depth: u8) -> Result<(u32, Segment)> {
+    fn allocate_with_buckets(&self, buckets: Vec<Bucket>, depth: u8) -> Result<(u32, Segment)> {
         // The number of buckets passed in *must* be the entire segment's buckets
-        assert!(buckets.len() == BUCKETS_PER_SEGMENT);
-
-        let index = self.num_segments;
-        // ------------------------------------------👇 for num_segments header in segments file
-        let offset = ((index as usize * SEGMENT_SIZE) + size_of::<Self::Header>()) as u64;
+        assert!(buckets.len() == self.geometry.segment_buckets() as usize);
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        let (index, reused) = state.slots.take(&mut state.buffer)?;
+        let offset = self.segment_offset(index);
         info!("New segment offset: {}", offset);
         // We create a BufWriter because we're going to be writing a lot and don't want to flush it
         // until we're done.
-        let mut buffer = BufWriter::with_capacity(SEGMENT_SIZE as usize, &mut self.buffer);
+        let mut buffer = BufWriter::with_capacity(self.geometry.segment_size(), &mut state.buffer);
         buffer
             .seek(io::SeekFrom::Start(offset))
             .context("Seeking to new segment location")?;
-        let mut buf: [u8; 1] = [0; 1];
-        buf[..].copy_from_slice(&depth.to_le_bytes());
         buffer
-            .write_all(&buf)
+            .write_all(&depth.to_le_bytes())
             .context("Writing new segment's depth.")?;
+        let mut records = 0;
         for (i, bucket) in buckets.iter().enumerate() {
-            debug!("Writing bucket with index {}", i);
+            trace!("Writing bucket with index {}", i);
             bucket
                 .pack(&mut buffer)
                 .with_context(|| format!("Writing bucket with index {}", i))?;
+            records += bucket.len() as u32;
         }
         buffer
             .flush()
             .context("Flushing outer buffer after segment allocate.")?;
         drop(buffer);
-        let index = self.num_segments;
-        self.num_segments += 1;
-        self.write_num_segments()
-            .context("Syncronizing num_segments after allocating.")?;
-        let end_of_buffer = self
+        self.commit_allocation(state, index, reused, depth, records)?;
+        Ok((index, Segment { offset, depth }))
+    }
+
+    fn allocate_with_segment(&self, buf: &SegmentBuf) -> Result<(u32, Segment)> {
+        assert_eq!(buf.geometry(), self.geometry);
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        let (index, reused) = state.slots.take(&mut state.buffer)?;
+        let offset = self.segment_offset(index);
+        info!("New segment offset: {}", offset);
+        state
+            .buffer
+            .seek(io::SeekFrom::Start(offset))
+            .context("Seeking to new segment location")?;
+        state
             .buffer
-            .seek(io::SeekFrom::End(0))
-            .context("Seeking to end of buffer")?;
-        trace!("End of buffer: {}", end_of_buffer);
+            .write_all(buf.bytes())
+            .context("Writing new segment bytes")?;
+        state
+            .buffer
+            .flush()
+            .context("Flushing buffer after segment allocate.")?;
+        let depth = buf.depth();
+        self.commit_allocation(state, index, reused, depth, buf.len() as u32)?;
         Ok((index, Segment { offset, depth }))
     }
 
-    fn bucket(&mut self, segment: &Segment, index: u32) -> Result<Bucket> {
-        assert!(index < BUCKETS_PER_SEGMENT as u32);
-        //----------------------------------------------------------👇 for segment_depth
-        let offset = segment.offset + (index as usize * BUCKET_SIZE) as u64 + 1;
-        self.buffer
+    fn read_segment(&self, segment: &Segment, buf: &mut SegmentBuf) -> Result<()> {
+        let mut state = self.state.lock();
+        state
+            .buffer
+            .seek(io::SeekFrom::Start(segment.offset))
+            .context("Seeking to segment's offset")?;
+        state
+            .buffer
+            .read_exact(buf.prepare(self.geometry))
+            .with_context(|| format!("Reading segment at offset {}", segment.offset))?;
+        buf.loaded(segment.offset + 1);
+        Ok(())
+    }
+
+    fn write_segment(&self, segment: &Segment, buf: &SegmentBuf) -> Result<()> {
+        assert_eq!(buf.geometry(), self.geometry);
+        let mut state = self.state.lock();
+        state
+            .buffer
+            .seek(io::SeekFrom::Start(segment.offset))
+            .context("Seeking to segment's offset")?;
+        state
+            .buffer
+            .write_all(buf.bytes())
+            .with_context(|| format!("Writing segment at offset {}", segment.offset))?;
+        state.buffer.flush()?;
+        let index = self.segment_index_of(segment.offset);
+        self.table.set_depth(index, buf.depth())?;
+        self.table.add_records(index, buf.len_delta())
+    }
+
+    fn bucket(&self, segment: &Segment, index: u32) -> Result<Bucket> {
+        assert!(index < self.geometry.segment_buckets());
+        let offset = self.bucket_offset(segment, index);
+        let mut state = self.state.lock();
+        state
+            .buffer
             .seek(io::SeekFrom::Start(offset))
             .context("Seeking to bucket's offset")?;
         debug!("Reading bucket at offset {}", offset);
-        return Bucket::unpack(&mut self.buffer);
+        Bucket::unpack(&mut state.buffer, self.geometry.bucket_layout())
+    }
+
+    fn bucket_offset(&self, segment: &Segment, index: u32) -> u64 {
+        //-------------------------------------------------------------------👇 for segment_depth
+        segment.offset + index as u64 * self.geometry.bucket_size as u64 + 1
     }
 
-    fn write_bucket(&mut self, bucket: &Bucket) -> Result<()> {
-        self.buffer
+    fn write_bucket(&self, bucket: &mut Bucket) -> Result<()> {
+        let mut state = self.state.lock();
+        state
+            .buffer
             .seek(io::SeekFrom::Start(bucket.offset))
             .context("Seeking to bucket's offset")?;
-        bucket.pack(&mut self.buffer)?;
-        self.buffer.flush()?;
+        bucket.pack(&mut state.buffer)?;
+        state.buffer.flush()?;
+        self.table
+            .add_records(self.segment_index_of(bucket.offset), bucket.len_delta())?;
+        bucket.mark_clean();
+        Ok(())
+    }
+
+    fn write_slot(&self, bucket: &mut Bucket, index: usize) -> Result<()> {
+        let mut state = self.state.lock();
+        for (offset, bytes) in bucket.slot_writes(index) {
+            state
+                .buffer
+                .seek(io::SeekFrom::Start(offset))
+                .context("Seeking to slot's offset")?;
+            state
+                .buffer
+                .write_all(bytes)
+                .with_context(|| format!("Writing slot {} at offset {}", index, offset))?;
+        }
+        state.buffer.flush()?;
+        self.table
+            .add_records(self.segment_index_of(bucket.offset), bucket.len_delta())?;
+        bucket.mark_clean();
         Ok(())
     }
 
-    fn num_segments(&mut self) -> Result<u32> {
-        Ok(self.num_segments)
+    fn free_segment(&self, index: u32) -> Result<()> {
+        let mut state = self.state.lock();
+        let state = &mut *state;
+        state.slots.release(index)?;
+        state.slots.write_free_list(&mut state.buffer)?;
+        self.table.reset(index, 0, 0)
     }
 
-    fn update_segment(&mut self, segment: Segment) -> Result<()> {
+    fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    fn num_segments(&self) -> Result<u32> {
+        Ok(self.state.lock().slots.num_segments)
+    }
+
+    fn update_segment(&self, segment: Segment) -> Result<()> {
         debug!("Updating segment depth to {}", segment.depth);
-        self.buffer.seek(io::SeekFrom::Start(segment.offset))?;
-        self.buffer.write_all(&segment.depth.to_le_bytes())?;
-        self.buffer.flush()?;
-        let index = Self::segment_offset_to_index(&segment);
-        if self.segment_depth_cache.contains_key(&index) {
-            self.segment_depth_cache.insert(index, segment.depth);
-        }
-        Ok(())
+        let mut state = self.state.lock();
+        state.buffer.seek(io::SeekFrom::Start(segment.offset))?;
+        state.buffer.write_all(&segment.depth.to_le_bytes())?;
+        state.buffer.flush()?;
+        self.table
+            .set_depth(self.segment_index_of(segment.offset), segment.depth)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use io::{self, Cursor, Read, Seek, Write};
+    use crate::directory::MemoryDirectory;
+    use crate::meh::MehDB;
+    use crate::meh::tests::exercise;
+    use crate::segment::bucket::DEFAULT_BUCKET_LAYOUT;
+    use std::io::Cursor;
+
+    const BUCKETS_PER_SEGMENT: usize = Geometry::DEFAULT.segment_buckets() as usize;
+    const HEADER_SIZE: usize = size_of::<<BasicSegmenter<Cursor<Vec<u8>>> as Segmenter>::Header>();
 
     fn inmemory_segmenter() -> BasicSegmenter<Cursor<Vec<u8>>> {
         let buffer = Cursor::new(Vec::new());
@@ -212,16 +398,15 @@ mod tests {
 
     #[test]
     fn segmenter_init_syncs_num_segments() {
-        let mut segmenter = inmemory_segmenter();
+        let segmenter = inmemory_segmenter();
         let num_segments = segmenter.num_segments().unwrap();
         assert_eq!(num_segments, 1);
     }
 
     #[test]
     fn segmenter_init_initializes_first_segment() {
-        let mut segmenter = inmemory_segmenter();
-        const HEADER_SIZE: usize =
-            size_of::<<BasicSegmenter<Cursor<Vec<u8>>> as Segmenter>::Header>();
+        let segmenter = inmemory_segmenter();
+        let segment_size = segmenter.geometry().segment_size();
         let first_segment = segmenter.segment(0).unwrap();
         // Get the last bucket
         let mut bucket = segmenter
@@ -231,7 +416,7 @@ mod tests {
             .put(123, 456, 0)
             .expect("Unable to insert record into bucket");
         segmenter
-            .write_bucket(&bucket)
+            .write_bucket(&mut bucket)
             .expect("Unable to write bucket to segment");
         let bucket = segmenter
             .bucket(&first_segment, BUCKETS_PER_SEGMENT as u32 - 1)
@@ -239,32 +424,34 @@ mod tests {
         let r = bucket.get(123).unwrap();
         assert_eq!(r.hash_key, 123);
         assert_eq!(r.value, 456);
+        assert_eq!(segmenter.segment_meta(0).unwrap().records, 1);
 
-        let expected_size = SEGMENT_SIZE + HEADER_SIZE;
-        assert_eq!(segmenter.buffer.into_inner().len(), expected_size);
+        let expected_size = segment_size + HEADER_SIZE;
+        assert_eq!(
+            segmenter.into_inner().unwrap().into_inner().len(),
+            expected_size
+        );
     }
 
     #[test]
     fn segmenter_initialize_segment_correct_size() {
-        let mut segmenter = inmemory_segmenter();
-        const HEADER_SIZE: usize =
-            size_of::<<BasicSegmenter<Cursor<Vec<u8>>> as Segmenter>::Header>();
-        let first_segment = segmenter.segment(0).unwrap();
+        let segmenter = inmemory_segmenter();
+        let segment_size = segmenter.geometry().segment_size();
         let second_segment = segmenter.allocate_segment(3).unwrap().1;
         assert_eq!(second_segment.depth, 3);
-        let expected_offset = HEADER_SIZE + SEGMENT_SIZE;
+        let expected_offset = HEADER_SIZE + segment_size;
         assert_eq!(second_segment.offset, expected_offset as u64);
         let another_second_segment = segmenter.segment(1).unwrap();
         assert_eq!(another_second_segment.offset, second_segment.offset);
         assert_eq!(another_second_segment.depth, second_segment.depth);
-        let expected_buffer_length = HEADER_SIZE + 2 * (SEGMENT_SIZE);
-        let actual_len = segmenter.buffer.into_inner().len();
+        let expected_buffer_length = HEADER_SIZE + 2 * segment_size;
+        let actual_len = segmenter.into_inner().unwrap().into_inner().len();
         assert_eq!(actual_len, expected_buffer_length);
     }
 
     #[test]
     fn allocate_segment_doesnt_overwrite_previous_bucket() {
-        let mut segmenter = inmemory_segmenter();
+        let segmenter = inmemory_segmenter();
         let segment = segmenter.segment(0).expect("Unable to get segment.");
         let mut last_bucket_first_segment = segmenter
             .bucket(&segment, BUCKETS_PER_SEGMENT as u32 - 1)
@@ -273,7 +460,7 @@ mod tests {
             .put(123, 456, 0)
             .expect("Unable to insert record into bucket.");
         segmenter
-            .write_bucket(&last_bucket_first_segment)
+            .write_bucket(&mut last_bucket_first_segment)
             .expect("Saving bucket back to ");
         segmenter
             .allocate_segment(0)
@@ -286,7 +473,7 @@ mod tests {
 
     #[test]
     fn allocate_segment_with_buckets_doesnt_overwrite_previous_bucket() {
-        let mut segmenter = inmemory_segmenter();
+        let segmenter = inmemory_segmenter();
         let segment = segmenter.segment(0).expect("Unable to get segment.");
         let mut last_bucket_first_segment = segmenter
             .bucket(&segment, BUCKETS_PER_SEGMENT as u32 - 1)
@@ -295,7 +482,7 @@ mod tests {
             .put(123, 456, 0)
             .expect("Unable to insert record into bucket.");
         segmenter
-            .write_bucket(&last_bucket_first_segment)
+            .write_bucket(&mut last_bucket_first_segment)
             .expect("Saving bucket back to ");
         let mut buckets = Vec::<Bucket>::with_capacity(BUCKETS_PER_SEGMENT);
         for bi in 0..BUCKETS_PER_SEGMENT {
@@ -311,15 +498,15 @@ mod tests {
         assert_eq!(last_bucket_first_segment.get(123).unwrap().value, 456);
     }
 
-    use crate::segment::bucket::BUCKET_RECORDS;
     #[test]
     fn allocate_many_segments() {
-        let mut segmenter = inmemory_segmenter();
+        let segmenter = inmemory_segmenter();
+        let bucket_records = DEFAULT_BUCKET_LAYOUT.records();
         for z in 1..10 {
             let mut buckets = Vec::<Bucket>::with_capacity(BUCKETS_PER_SEGMENT);
             for i in 0..BUCKETS_PER_SEGMENT {
                 let mut bucket: Bucket = Default::default();
-                for y in 0..BUCKET_RECORDS {
+                for y in 0..bucket_records {
                     let k: u64 = ((z + 1) * (i + 1) * (y + 1)) as u64;
                     let v: u64 = k * 2;
                     bucket.put(k, v, 0).unwrap();
@@ -331,15 +518,12 @@ mod tests {
         let first_segment = segmenter.segment(0).unwrap();
         for i in 0..BUCKETS_PER_SEGMENT {
             let bucket = segmenter.bucket(&first_segment, i as u32).unwrap();
-            for y in 0..BUCKET_RECORDS {
-                match bucket.get(y as u64) {
-                    Some(r) => {
-                        panic!(
-                            "We aren't supposed to have anything here!? Found: {}: {}",
-                            r.hash_key, r.value
-                        );
-                    }
-                    None => (),
+            for y in 0..bucket_records {
+                if let Some(r) = bucket.get(y as u64) {
+                    panic!(
+                        "We aren't supposed to have anything here!? Found: {}: {}",
+                        r.hash_key, r.value
+                    );
                 }
             }
         }
@@ -347,7 +531,7 @@ mod tests {
             let segment = segmenter.segment(z).unwrap();
             for i in 0..BUCKETS_PER_SEGMENT {
                 let bucket = segmenter.bucket(&segment, i as u32).unwrap();
-                for y in 0..BUCKET_RECORDS {
+                for y in 0..bucket_records {
                     // Why the FUCK does this need to be different from the above
                     // The compiler complains about multiplying usize by u64, but there's *no*
                     // reason for z, i, or y to be anything *but* usize.
@@ -359,4 +543,31 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn reopens_an_existing_buffer() {
+        let segmenter = inmemory_segmenter();
+        let (index, segment) = segmenter.allocate_segment(2).unwrap();
+        let mut bucket = segmenter.bucket(&segment, 7).unwrap();
+        bucket.put(123, 456, 2).unwrap();
+        segmenter.write_bucket(&mut bucket).unwrap();
+        segmenter.free_segment(0).unwrap();
+
+        let segmenter = BasicSegmenter::init(segmenter.into_inner().unwrap()).unwrap();
+        assert_eq!(segmenter.num_segments().unwrap(), 2);
+        assert_eq!(segmenter.free_segments(), vec![0]);
+        let meta = segmenter.segment_meta(index).unwrap();
+        assert_eq!((meta.depth, meta.records), (2, 1));
+        let bucket = segmenter.bucket(&segment, 7).unwrap();
+        assert_eq!(bucket.get(123).unwrap().value, 456);
+        // The freed segment is reused before the buffer grows
+        assert_eq!(segmenter.allocate_segment(1).unwrap().0, 0);
+        assert_eq!(segmenter.num_segments().unwrap(), 2);
+    }
+
+    #[test]
+    fn mehdb_works_in_memory() {
+        let directory = MemoryDirectory::new().unwrap();
+        exercise(MehDB::with_parts(directory, inmemory_segmenter()).unwrap());
+    }
 }
@@ -1,138 +1,445 @@
-use crate::serializer::Serializable;
 use anyhow::{Context, Result};
-use log::{debug, trace, warn};
+use bitvec::prelude::*;
+use log::{debug, trace};
 use std::default::Default;
 use std::error::Error;
 use std::fmt;
-use std::io::{self, Read, Seek, Write};
+use std::io::{Read, Seek, Write};
+use std::marker::PhantomData;
 use std::mem::size_of;
+use std::ops::Range;
 
-// The number of records in each bucket.
-// This may be adatped to be parametrizable or dynamic in the future.
-pub const BUCKET_RECORDS: usize = 256;
-pub const BUCKET_SIZE: usize = BUCKET_RECORDS * size_of::<Record>();
+// By default buckets are a single cache line, per the CCEH paper. A lookup only ever has to read
+// the handful of buckets in a key's probe window instead of one large bucket.
+pub const CACHE_LINE_SIZE: usize = 64;
+pub const DEFAULT_BUCKET_LAYOUT: BucketLayout = BucketLayout::new(CACHE_LINE_SIZE);
+
+/// Where things are inside a bucket of a given size. A bucket starts with a header holding a
+/// bitmap with an occupancy bit for every slot followed by a tombstone bit for every slot, then a
+/// 1-byte fingerprint for every slot. The records follow the header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BucketLayout {
+    size: usize,
+    value_size: usize,
+    records: usize,
+    bitmap_size: usize,
+    header_size: usize,
+}
+
+impl BucketLayout {
+    /// Lays out a bucket of `size` bytes holding records with u64 values.
+    pub const fn new(size: usize) -> Self {
+        Self::with_value_size(size, size_of::<u64>())
+    }
+
+    /// Lays out a bucket of `size` bytes with as many records with `value_size` byte values as
+    /// fit alongside the header.
+    pub const fn with_value_size(size: usize, value_size: usize) -> Self {
+        let record_size = size_of::<u64>() + value_size;
+        let mut records = size / record_size;
+        while Self::header_size_for(records) + records * record_size > size {
+            records -= 1;
+        }
+        Self {
+            size,
+            value_size,
+            records,
+            bitmap_size: Self::bitmap_size_for(records),
+            header_size: Self::header_size_for(records),
+        }
+    }
+
+    const fn bitmap_size_for(records: usize) -> usize {
+        (records * 2).div_ceil(8)
+    }
+
+    const fn header_size_for(records: usize) -> usize {
+        Self::bitmap_size_for(records) + records
+    }
+
+    /// The size of the bucket on disk, in bytes.
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The number of records that fit in the bucket.
+    pub const fn records(&self) -> usize {
+        self.records
+    }
+
+    pub const fn header_size(&self) -> usize {
+        self.header_size
+    }
+
+    /// The size of the value in each record, in bytes.
+    pub const fn value_size(&self) -> usize {
+        self.value_size
+    }
+
+    /// The size of each record, its hash key and value, in bytes.
+    pub const fn record_size(&self) -> usize {
+        size_of::<u64>() + self.value_size
+    }
+
+    /// The offset of the record in slot `index` from the start of the bucket.
+    pub const fn record_offset(&self, index: usize) -> usize {
+        self.header_size + index * self.record_size()
+    }
+
+    /// Panics unless records in this layout hold values of type `V`.
+    #[inline]
+    pub(crate) fn check_value<V: FixedValue>(&self) {
+        assert_eq!(
+            self.value_size,
+            V::SIZE,
+            "Bucket holds {} byte values, not {} byte ones",
+            self.value_size,
+            V::SIZE
+        );
+    }
+}
+
+/// Returns the indexes of the occupied slots whose fingerprint matches `hk`, given only the
+/// bucket's `header`. This is all it takes to know which records have to be read to find `hk`.
+pub(crate) fn matching_slots(
+    header: &[u8],
+    layout: BucketLayout,
+    hk: u64,
+) -> impl Iterator<Item = usize> + '_ {
+    let fp = fingerprint(hk);
+    let bits = header[..layout.bitmap_size].view_bits::<Lsb0>();
+    header[layout.bitmap_size..layout.header_size]
+        .chunks(64)
+        .enumerate()
+        .flat_map(move |(chunk_index, chunk)| {
+            // Branchless so the comparison can be vectorized
+            let mut mask: u64 = 0;
+            for (i, f) in chunk.iter().enumerate() {
+                mask |= ((*f == fp) as u64) << i;
+            }
+            std::iter::from_fn(move || {
+                if mask == 0 {
+                    return None;
+                }
+                let i = mask.trailing_zeros() as usize;
+                mask &= mask - 1;
+                Some(chunk_index * 64 + i)
+            })
+        })
+        .filter(move |i| bits[*i])
+}
+
+/// The 1-byte fingerprint stored in the bucket header for `hk`. The high bits of a hash key pick
+/// its segment, so every key in a bucket tends to share them. Use the low bits instead.
+#[inline]
+pub fn fingerprint(hk: u64) -> u8 {
+    hk as u8
+}
+
+/// A value with a fixed size in bytes, which is what a record can hold. The size of a database's
+/// values is chosen when it's created, see `Geometry::value_size`.
+pub trait FixedValue: Sized + fmt::Debug {
+    /// The size of the value on disk, in bytes.
+    const SIZE: usize;
+    /// Writes the value to the first `SIZE` bytes of `buf`.
+    fn write_to(&self, buf: &mut [u8]);
+    /// Reads a value from the first `SIZE` bytes of `buf`.
+    fn read_from(buf: &[u8]) -> Self;
+}
+
+macro_rules! impl_fixed_value_for_int {
+    ($($t:ty),*) => {
+        $(
+            impl FixedValue for $t {
+                const SIZE: usize = size_of::<$t>();
+
+                fn write_to(&self, buf: &mut [u8]) {
+                    buf[..Self::SIZE].copy_from_slice(&self.to_le_bytes());
+                }
+
+                fn read_from(buf: &[u8]) -> Self {
+                    Self::from_le_bytes(buf[..Self::SIZE].try_into().unwrap())
+                }
+            }
+        )*
+    };
+}
+
+impl_fixed_value_for_int!(u32, u64, u128);
+
+impl<const N: usize> FixedValue for [u8; N] {
+    const SIZE: usize = N;
+
+    fn write_to(&self, buf: &mut [u8]) {
+        buf[..N].copy_from_slice(self);
+    }
+
+    fn read_from(buf: &[u8]) -> Self {
+        buf[..N].try_into().unwrap()
+    }
+}
+
+impl<A: FixedValue, B: FixedValue> FixedValue for (A, B) {
+    const SIZE: usize = A::SIZE + B::SIZE;
+
+    fn write_to(&self, buf: &mut [u8]) {
+        self.0.write_to(buf);
+        self.1.write_to(&mut buf[A::SIZE..]);
+    }
+
+    fn read_from(buf: &[u8]) -> Self {
+        (A::read_from(buf), B::read_from(&buf[A::SIZE..]))
+    }
+}
+
+impl<A: FixedValue, B: FixedValue, C: FixedValue> FixedValue for (A, B, C) {
+    const SIZE: usize = A::SIZE + B::SIZE + C::SIZE;
+
+    fn write_to(&self, buf: &mut [u8]) {
+        self.0.write_to(buf);
+        self.1.write_to(&mut buf[A::SIZE..]);
+        self.2.write_to(&mut buf[A::SIZE + B::SIZE..]);
+    }
+
+    fn read_from(buf: &[u8]) -> Self {
+        (
+            A::read_from(buf),
+            B::read_from(&buf[A::SIZE..]),
+            C::read_from(&buf[A::SIZE + B::SIZE..]),
+        )
+    }
+}
 
 #[derive(Debug)]
-pub struct Record {
+pub struct Record<V = u64> {
     pub hash_key: u64,
-    pub value: u64,
+    pub value: V,
 }
 
-impl Record {
+impl<V: FixedValue> Record<V> {
     pub const fn hash_key_size() -> usize {
         size_of::<u64>()
     }
     pub const fn value_size() -> usize {
-        size_of::<u64>()
+        V::SIZE
+    }
+    /// The size of the record on disk, in bytes.
+    pub const fn size() -> usize {
+        Self::hash_key_size() + Self::value_size()
+    }
+
+    /// Writes the record to the first `Record::size` bytes of `buf`.
+    pub fn write_to(&self, buf: &mut [u8]) {
+        buf[0..Self::hash_key_size()].copy_from_slice(&self.hash_key.to_le_bytes());
+        self.value
+            .write_to(&mut buf[Self::hash_key_size()..Self::size()]);
     }
 
-    pub fn to_bytes(&self) -> [u8; size_of::<Self>()] {
-        const SIZE: usize = size_of::<Record>();
-        let mut buf: [u8; SIZE] = [0; SIZE];
-        buf[0..Record::hash_key_size()].copy_from_slice(&self.hash_key.to_le_bytes());
-        buf[Record::hash_key_size()..].copy_from_slice(&self.value.to_le_bytes());
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = vec![0; Self::size()];
+        self.write_to(&mut buf);
         buf
     }
 
-    pub fn from_bytes(buf: [u8; size_of::<Self>()]) -> Self {
+    pub fn from_bytes(buf: &[u8]) -> Self {
         let hash_key = u64::from_le_bytes(buf[0..8].try_into().unwrap());
-        let value = u64::from_le_bytes(buf[8..16].try_into().unwrap());
+        let value = V::read_from(&buf[Self::hash_key_size()..Self::size()]);
         Self { hash_key, value }
     }
 }
 
+#[derive(Clone)]
 pub struct Bucket {
     pub offset: u64,
-    buf: [u8; BUCKET_SIZE],
+    buf: Vec<u8>,
+    layout: BucketLayout,
+    // The number of occupied records when the bucket was read, so a Segmenter can tell how many
+    // records a write adds without reading the old bucket back.
+    loaded_len: usize,
 }
 
 impl Default for Bucket {
     fn default() -> Self {
+        Self::with_layout(DEFAULT_BUCKET_LAYOUT)
+    }
+}
+
+/// Counts the occupied records in one or more consecutive packed buckets.
+pub(crate) fn occupied_records(buf: &[u8], layout: BucketLayout) -> usize {
+    buf.chunks_exact(layout.size)
+        .map(|b| b[..layout.bitmap_size].view_bits::<Lsb0>()[..layout.records].count_ones())
+        .sum()
+}
+
+/// Gets the effective key
+fn normalize_key(hk: u64, local_depth: u8) -> u64 {
+    assert!(local_depth <= 64);
+    if local_depth == 0 {
+        return 0;
+    }
+    hk >> (64 - local_depth)
+}
+
+impl Bucket {
+    /// Creates an empty bucket with the default layout.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_layout(layout: BucketLayout) -> Self {
         Self {
             offset: 0,
-            buf: [0; BUCKET_SIZE],
+            buf: vec![0; layout.size],
+            layout,
+            loaded_len: 0,
         }
     }
-}
 
-impl Serializable for Bucket {
-    fn pack<W: Write + Seek>(&self, buffer: &mut W) -> Result<u64> {
-        let offset = buffer.seek(io::SeekFrom::Current(0))?;
+    /// Packs the bucket into `buffer` at its current position, returning that position. Buckets
+    /// don't implement `Serializable` because they can't be unpacked without knowing their
+    /// layout.
+    pub fn pack<W: Write + Seek>(&self, buffer: &mut W) -> Result<u64> {
+        let offset = buffer.stream_position()?;
         buffer
             .write_all(&self.buf)
             .context("Error packing bucket into buffer")?;
         Ok(offset)
     }
 
-    fn unpack<R: Read + Seek>(buffer: &mut R) -> Result<Self> {
-        let offset = buffer.seek(io::SeekFrom::Current(0)).unwrap();
-        let mut bucket = Self {
-            offset,
-            buf: [0; BUCKET_SIZE],
-        };
+    /// Reads a bucket with `layout` from `buffer`'s current position.
+    pub fn unpack<R: Read + Seek>(buffer: &mut R, layout: BucketLayout) -> Result<Self> {
+        let offset = buffer.stream_position()?;
+        let mut bucket = Self::with_layout(layout);
+        bucket.offset = offset;
         buffer.read_exact(&mut bucket.buf).with_context(|| {
             format!(
                 "Error reading buffer when unpacking bucket at offset {}",
                 offset
             )
         })?;
+        bucket.loaded_len = bucket.len();
         Ok(bucket)
     }
-}
 
-/// Gets the effective key
-fn normalize_key(hk: u64, local_depth: u8) -> u64 {
-    assert!(local_depth <= 64);
-    if local_depth == 0 {
-        return 0;
+    /// Copies a bucket with `layout` out of the start of `bytes`. `loaded_len` is the number of
+    /// records the bucket had when it was read from the Segmenter.
+    pub(crate) fn from_bytes(
+        offset: u64,
+        bytes: &[u8],
+        layout: BucketLayout,
+        loaded_len: usize,
+    ) -> Self {
+        let mut bucket = Self::with_layout(layout);
+        bucket.offset = offset;
+        bucket.buf.copy_from_slice(&bytes[..layout.size]);
+        bucket.loaded_len = loaded_len;
+        bucket
     }
-    return hk >> (64 - local_depth);
-}
 
-impl Bucket {
-    pub fn new() -> Self {
-        Bucket {
-            offset: 0,
-            buf: [0; BUCKET_SIZE],
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn layout(&self) -> BucketLayout {
+        self.layout
+    }
+
+    /// The number of occupied records in the bucket.
+    pub fn len(&self) -> usize {
+        self.view().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of records added (or, if negative, removed) since the bucket was read.
+    pub fn len_delta(&self) -> i64 {
+        self.len() as i64 - self.loaded_len as i64
+    }
+
+    /// Resets `len_delta` to zero, for when the bucket's records have been counted by the
+    /// Segmenter that wrote it.
+    pub(crate) fn mark_clean(&mut self) {
+        self.loaded_len = self.len();
+    }
+
+    /// Counts `len_delta` from where `other` was read, for when this bucket replaces `other`
+    /// before `other` was written.
+    pub(crate) fn keep_baseline(&mut self, other: &Bucket) {
+        self.loaded_len = other.loaded_len;
+    }
+
+    /// Borrows the bucket as a `BucketRef`.
+    pub fn view(&self) -> BucketRef<'_> {
+        BucketRef {
+            offset: self.offset,
+            buf: &self.buf,
+            layout: self.layout,
         }
     }
 
+    fn bits_mut(&mut self) -> &mut BitSlice<u8> {
+        self.buf[..self.layout.bitmap_size].view_bits_mut::<Lsb0>()
+    }
+
+    /// Whether the slot at `index` holds a live record.
+    #[inline]
+    pub fn is_occupied(&self, index: usize) -> bool {
+        self.view().is_occupied(index)
+    }
+
+    /// Whether the record at `index` has been deleted. A tombstoned slot is never occupied.
+    #[inline]
+    pub fn is_tombstone(&self, index: usize) -> bool {
+        self.view().is_tombstone(index)
+    }
+
     #[inline]
     pub fn get(&self, hk: u64) -> Option<Record> {
-        debug!("Searching bucket for {}", hk);
-        for record in self.iter() {
-            warn!("Found hk: {}\tvalue: {}", record.hash_key, record.value);
-            // Records where both hash_key and values are set to 0 are considered empty
-            if record.hash_key == hk {
-                return Some(record);
-            }
-        }
-        None
+        self.view().get(hk)
+    }
+
+    /// The same as `get` for buckets whose records hold `V`s.
+    #[inline]
+    pub fn get_value<V: FixedValue>(&self, hk: u64) -> Option<Record<V>> {
+        self.view().get_value(hk)
+    }
+
+    /// Returns true if `hk` can be put in this bucket, either because it is already in it or
+    /// because there is an empty or stale slot.
+    #[inline]
+    pub fn has_room(&self, hk: u64, local_depth: u8) -> bool {
+        self.maybe_index_to_insert(hk, local_depth).is_some()
     }
 
     #[inline]
     fn maybe_index_to_insert(&self, hk: u64, local_depth: u8) -> Option<usize> {
+        if let Some(i) = self.view().find(hk) {
+            return Some(i);
+        }
         let local_mask = normalize_key(hk, local_depth);
-        for (i, record) in self.iter().enumerate() {
+        // The first empty or stale slot
+        let mut free = None;
+        for i in 0..self.layout.records {
+            if !self.is_occupied(i) {
+                if free.is_none() {
+                    debug!("Found free slot to insert record at index {}.", i);
+                    free = Some(i);
+                }
+                continue;
+            }
+            let hash_key = self.view().record_key(i);
             trace!(
-                "Index: {}\t hk: {}\tvalue: {}\tlocal_depth: {}",
-                i,
-                record.hash_key,
-                record.value,
-                local_depth
+                "Index: {}\t hk: {}\tlocal_depth: {}",
+                i, hash_key, local_depth
             );
-            if record.hash_key == 0 && record.value == 0 {
-                debug!("Found empty slot to insert record at index {}.", i);
-                return Some(i);
-            } else if record.hash_key == hk {
-                return Some(i);
-            } else if normalize_key(record.hash_key, local_depth) != local_mask {
-                debug!("Replacing {} with new record", record.hash_key);
-                // return the index we're inserting at
-                return Some(i);
+            if free.is_none() && normalize_key(hash_key, local_depth) != local_mask {
+                debug!("Replacing {} with new record", hash_key);
+                free = Some(i);
             }
         }
-        None
+        free
     }
 
     /// Attempts to insert a record in the bucket. Returns the index it was inserted at if
@@ -141,8 +448,20 @@ impl Bucket {
     /// record can be inserted.
     #[inline]
     pub fn put(&mut self, hk: u64, value: u64, local_depth: u8) -> Result<usize, BucketFullError> {
+        self.put_value(hk, &value, local_depth)
+    }
+
+    /// The same as `put` for buckets whose records hold `V`s.
+    #[inline]
+    pub fn put_value<V: FixedValue>(
+        &mut self,
+        hk: u64,
+        value: &V,
+        local_depth: u8,
+    ) -> Result<usize, BucketFullError> {
+        self.layout.check_value::<V>();
         debug!(
-            "Inserting hk: {}\tvalue: {}\t local depth: {}",
+            "Inserting hk: {}\tvalue: {:?}\t local depth: {}",
             hk, value, local_depth
         );
         let index = match self.maybe_index_to_insert(hk, local_depth) {
@@ -155,52 +474,212 @@ impl Bucket {
             }
             Some(i) => i,
         };
-        let record = Record {
-            hash_key: hk,
-            value,
-        };
-        let bytes = record.to_bytes();
-        trace!("Record bytes: {:?}", &bytes);
-        let offset = index * size_of::<Record>();
+        let offset = self.record_offset(index);
         trace!("Record offset: {}", offset);
-        self.buf[offset..offset + size_of::<Record>()].copy_from_slice(&bytes);
+        let record = &mut self.buf[offset..offset + self.layout.record_size()];
+        record[..Record::<V>::hash_key_size()].copy_from_slice(&hk.to_le_bytes());
+        value.write_to(&mut record[Record::<V>::hash_key_size()..]);
+        trace!("Record bytes: {:?}", record);
+        self.buf[self.layout.bitmap_size + index] = fingerprint(hk);
+        let records = self.layout.records;
+        let bits = self.bits_mut();
+        bits.set(index, true);
+        bits.set(records + index, false);
         Ok(index)
     }
 
-    pub fn iter(&self) -> BucketIter {
+    /// Deletes the record for `hk`, leaving a tombstone in its slot. Returns the index of the
+    /// deleted record if it was in the bucket.
+    pub fn remove(&mut self, hk: u64) -> Option<usize> {
+        let index = self.view().find(hk)?;
+        let offset = self.record_offset(index);
+        self.buf[offset..offset + self.layout.record_size()].fill(0);
+        self.buf[self.layout.bitmap_size + index] = 0;
+        let records = self.layout.records;
+        let bits = self.bits_mut();
+        bits.set(index, false);
+        bits.set(records + index, true);
+        Some(index)
+    }
+
+    /// The byte ranges of the bucket that `put` or `remove` change for the slot at `index`: the
+    /// bitmap bytes holding its occupancy and tombstone bits, its fingerprint and its record.
+    /// Ranges are relative to the start of the bucket, in order, and adjacent ones are merged.
+    pub fn slot_ranges(&self, index: usize) -> Vec<Range<usize>> {
+        let occupied = index / 8;
+        let tombstone = (self.layout.records + index) / 8;
+        let fingerprint = self.layout.bitmap_size + index;
+        let record = self.record_offset(index);
+        let mut ranges: Vec<Range<usize>> = Vec::with_capacity(4);
+        for range in [
+            occupied..occupied + 1,
+            tombstone..tombstone + 1,
+            fingerprint..fingerprint + 1,
+            record..record + self.layout.record_size(),
+        ] {
+            match ranges.last_mut() {
+                Some(last) if last.end >= range.start => last.end = last.end.max(range.end),
+                _ => ranges.push(range),
+            }
+        }
+        ranges
+    }
+
+    /// The writes that persist a change to the slot at `index` alone, as pairs of the offset to
+    /// write at and the bytes to write there. See `slot_ranges`.
+    pub fn slot_writes(&self, index: usize) -> impl Iterator<Item = (u64, &[u8])> + '_ {
+        self.slot_ranges(index)
+            .into_iter()
+            .map(|range| (self.offset + range.start as u64, &self.buf[range]))
+    }
+
+    #[inline]
+    fn record_offset(&self, index: usize) -> usize {
+        self.layout.record_offset(index)
+    }
+
+    pub fn iter(&self) -> BucketIter<'_> {
+        self.view().iter()
+    }
+
+    /// The same as `iter` for buckets whose records hold `V`s.
+    pub fn iter_values<V: FixedValue>(&self) -> BucketIter<'_, V> {
+        self.view().iter_values()
+    }
+}
+
+/// A read-only view of a bucket that borrows its bytes from wherever they already are, like a
+/// memory map, a buffer pool entry or a `SegmentBuf`, instead of copying them into a `Bucket`.
+/// It can do everything `Bucket` can other than change the bucket.
+#[derive(Clone, Copy)]
+pub struct BucketRef<'a> {
+    pub offset: u64,
+    buf: &'a [u8],
+    layout: BucketLayout,
+}
+
+impl<'a> BucketRef<'a> {
+    /// Views the start of `bytes`, which must hold at least a whole bucket with `layout`, as the
+    /// bucket at `offset`.
+    pub fn new(offset: u64, bytes: &'a [u8], layout: BucketLayout) -> Self {
+        Self {
+            offset,
+            buf: &bytes[..layout.size],
+            layout,
+        }
+    }
+
+    pub fn layout(&self) -> BucketLayout {
+        self.layout
+    }
+
+    /// The number of occupied records in the bucket.
+    pub fn len(&self) -> usize {
+        occupied_records(self.buf, self.layout)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Copies the bucket into an owned `Bucket` that can be changed and written back.
+    pub fn to_bucket(&self) -> Bucket {
+        Bucket::from_bytes(self.offset, self.buf, self.layout, self.len())
+    }
+
+    fn bits(&self) -> &'a BitSlice<u8> {
+        self.buf[..self.layout.bitmap_size].view_bits::<Lsb0>()
+    }
+
+    /// Returns the indexes of the occupied slots whose fingerprint matches `hk`. Only these
+    /// records need to be decoded to find `hk`.
+    fn matching_slots(&self, hk: u64) -> impl Iterator<Item = usize> + 'a {
+        matching_slots(self.buf, self.layout, hk)
+    }
+
+    /// The index of the slot holding `hk`, if it's in the bucket.
+    fn find(&self, hk: u64) -> Option<usize> {
+        self.matching_slots(hk).find(|i| self.record_key(*i) == hk)
+    }
+
+    /// Whether the slot at `index` holds a live record.
+    #[inline]
+    pub fn is_occupied(&self, index: usize) -> bool {
+        self.bits()[index]
+    }
+
+    /// Whether the record at `index` has been deleted. A tombstoned slot is never occupied.
+    #[inline]
+    pub fn is_tombstone(&self, index: usize) -> bool {
+        self.bits()[self.layout.records + index]
+    }
+
+    #[inline]
+    pub fn get(&self, hk: u64) -> Option<Record> {
+        self.get_value(hk)
+    }
+
+    /// The same as `get` for buckets whose records hold `V`s.
+    #[inline]
+    pub fn get_value<V: FixedValue>(&self, hk: u64) -> Option<Record<V>> {
+        self.layout.check_value::<V>();
+        trace!("Searching bucket for {}", hk);
+        self.find(hk).map(|i| self.at(i))
+    }
+
+    pub fn iter(&self) -> BucketIter<'a> {
+        self.iter_values()
+    }
+
+    /// The same as `iter` for buckets whose records hold `V`s.
+    pub fn iter_values<V: FixedValue>(&self) -> BucketIter<'a, V> {
+        self.layout.check_value::<V>();
         BucketIter {
             index: 0,
-            bucket: &self,
+            bucket: *self,
+            value: PhantomData,
         }
     }
 
-    /// Returns the bucket at index. This is not part of `Bucket`'s interface and is private, so it
-    /// may panic if you give it an index that is not valid. Index should be 0 <= i <= BUCKET_RECORDS
-    fn at(&self, index: usize) -> Record {
-        let offset = index * size_of::<Record>();
-        let mut buf: [u8; 8] = [0; 8];
-        buf.copy_from_slice(&self.buf[offset..offset + 8]);
-        let hash_key = u64::from_le_bytes(buf);
-        buf.copy_from_slice(&self.buf[offset + 8..offset + 16]);
-        let value = u64::from_le_bytes(buf);
-        Record { hash_key, value }
+    #[inline]
+    fn record_offset(&self, index: usize) -> usize {
+        self.layout.record_offset(index)
+    }
+
+    /// Decodes only the hash key of the record at `index`.
+    #[inline]
+    fn record_key(&self, index: usize) -> u64 {
+        let offset = self.record_offset(index);
+        u64::from_le_bytes(self.buf[offset..offset + 8].try_into().unwrap())
+    }
+
+    /// Returns the record at index. This is not part of the bucket's interface and is private, so
+    /// it may panic if you give it an index that is not valid. Index should be 0 <= i < the number
+    /// of records in the layout
+    fn at<V: FixedValue>(&self, index: usize) -> Record<V> {
+        let offset = self.record_offset(index);
+        Record::from_bytes(&self.buf[offset..offset + self.layout.record_size()])
     }
 }
 
-pub struct BucketIter<'b> {
+/// Iterates over the occupied records of a bucket, skipping empty and tombstoned slots.
+pub struct BucketIter<'b, V = u64> {
     index: usize, // this could be a u16
-    bucket: &'b Bucket,
+    bucket: BucketRef<'b>,
+    value: PhantomData<fn() -> V>,
 }
 
-impl<'b> Iterator for BucketIter<'b> {
-    type Item = Record;
+impl<'b, V: FixedValue> Iterator for BucketIter<'b, V> {
+    type Item = Record<V>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.index >= BUCKET_RECORDS {
-            return None;
+        while self.index < self.bucket.layout.records {
+            self.index += 1;
+            if self.bucket.is_occupied(self.index - 1) {
+                return Some(self.bucket.at(self.index - 1));
+            }
         }
-        self.index += 1;
-        Some(self.bucket.at(self.index - 1))
+        None
     }
 }
 
@@ -221,48 +700,44 @@ impl fmt::Display for BucketFullError {
     }
 }
 
-impl Error for BucketFullError {
-    fn source(&self) -> Option<&(dyn Error + 'static)> {
-        Some(self)
-    }
-}
+impl Error for BucketFullError {}
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use io::{self, Cursor, Seek};
+    use std::io::{self, Cursor, Seek};
 
     #[test]
     fn bucket_can_pack() {
         let mut bucket = Bucket {
             offset: 5,
-            buf: [0; BUCKET_SIZE],
+            ..Default::default()
         };
         // change this so we have something to check for
         bucket.buf[0] = 255;
         let mut buf: Cursor<Vec<u8>> = Cursor::new(Vec::new());
         buf.seek(io::SeekFrom::Start(5)).unwrap();
-        let _ = bucket.pack::<Cursor<Vec<u8>>>(&mut buf);
         let _ = match bucket.pack::<Cursor<Vec<u8>>>(&mut buf) {
             Err(e) => panic!("Unable to pack bucket: {}", e),
             Ok(r) => r,
         };
-        let mut expected_buf: [u8; BUCKET_SIZE + 5] = [0; BUCKET_SIZE + 5];
+        let size = DEFAULT_BUCKET_LAYOUT.size();
+        let mut expected_buf = vec![0; size + 5];
         // this will only match up with the output buffer if the Seek is performed
         // propery
         expected_buf[5] = 255;
         let inner_buf = buf.into_inner();
-        assert_eq!(&inner_buf.len(), &(BUCKET_SIZE + bucket.offset as usize));
+        assert_eq!(&inner_buf.len(), &(size + bucket.offset as usize));
         assert_eq!(&expected_buf[..], &inner_buf[..]);
     }
 
     #[test]
     fn bucket_can_unpack() {
-        let mut fixture: Vec<u8> = Vec::from([0; 2 * BUCKET_SIZE + 5]);
+        let mut fixture: Vec<u8> = vec![0; 2 * DEFAULT_BUCKET_LAYOUT.size() + 5];
         fixture[1] = 0x12;
         let mut buf = Cursor::new(fixture);
         buf.seek(io::SeekFrom::Start(1)).unwrap();
-        let bucket = match Bucket::unpack(&mut buf) {
+        let bucket = match Bucket::unpack(&mut buf, DEFAULT_BUCKET_LAYOUT) {
             Err(e) => panic!("Unable to unpack Bucket: {}", e),
             Ok(h) => h,
         };
@@ -277,18 +752,34 @@ mod test {
         fixture[1] = 0x12;
         let mut buf = Cursor::new(fixture);
         buf.seek(io::SeekFrom::Start(1)).unwrap();
-        match Bucket::unpack(&mut buf) {
+        match Bucket::unpack(&mut buf, DEFAULT_BUCKET_LAYOUT) {
             Err(_) => (),
             Ok(_) => panic!("Improperly was able to unpack Bucket"),
         }
     }
 
+    #[test]
+    fn larger_buckets_hold_more_records() {
+        assert_eq!(DEFAULT_BUCKET_LAYOUT.records(), 3);
+        let layout = BucketLayout::new(256);
+        assert_eq!(layout.records(), 14);
+        assert!(layout.header_size() + layout.records() * layout.record_size() <= layout.size());
+        let mut bucket = Bucket::with_layout(layout);
+        for i in 1..=layout.records() as u64 {
+            bucket.put(i, i * 2, 0).unwrap();
+        }
+        assert!(!bucket.has_room(1234, 0));
+        assert_eq!(bucket.len(), layout.records());
+        let mut buf = Cursor::new(Vec::new());
+        bucket.pack(&mut buf).unwrap();
+        buf.set_position(0);
+        let unpacked = Bucket::unpack(&mut buf, layout).unwrap();
+        assert_eq!(unpacked.get(7).unwrap().value, 14);
+    }
+
     #[test]
     fn can_insert_and_index_bucket() {
-        let mut bucket = Bucket {
-            offset: 0,
-            buf: [0; BUCKET_SIZE],
-        };
+        let mut bucket = Bucket::new();
         let index = match bucket.put(123, 456, 0) {
             Err(e) => panic!("Unable to insert record: {}", e),
             Ok(i) => i,
@@ -297,7 +788,7 @@ mod test {
         // record
         bucket.put(789, 666, 0).unwrap();
         // Check that we can index it
-        let record = bucket.at(index);
+        let record: Record = bucket.view().at(index);
         assert_eq!(record.hash_key, 123);
         assert_eq!(record.value, 456);
         // Check that we can .get the value
@@ -308,22 +799,18 @@ mod test {
 
     #[test]
     fn can_put_and_get_records_from_bucket() {
-        let mut bucket = Bucket {
-            offset: 0,
-            buf: [0; BUCKET_SIZE],
-        };
-        for i in 1..=16 {
+        let mut bucket = Bucket::new();
+        for i in 1..=DEFAULT_BUCKET_LAYOUT.records() as u64 {
             let _ = match bucket.put(i * 60, i * 2, 0) {
                 Err(e) => panic!("Unable to insert record: {}", e),
                 Ok(o) => o,
             };
         }
         // Bucket overflow
-        match bucket.put(1234, 666, 0) {
-            Ok(_) => panic!("Bucket should have overflown, but didn't"),
-            Err(_) => (),
+        if bucket.put(1234, 666, 0).is_ok() {
+            panic!("Bucket should have overflown, but didn't");
         }
-        for i in 1..=16 {
+        for i in 1..=DEFAULT_BUCKET_LAYOUT.records() as u64 {
             let record = match bucket.get(i * 60) {
                 None => panic!("Unable to fetch record from bucket"),
                 Some(r) => r,
@@ -335,10 +822,7 @@ mod test {
 
     #[test]
     fn can_overwrite_soft_deleted_record() {
-        let mut bucket = Bucket {
-            offset: 0,
-            buf: [0; BUCKET_SIZE],
-        };
+        let mut bucket = Bucket::new();
         let hash_key: u64 = 0xF000000000000000;
         let i = bucket.put(123, 456, 0).unwrap();
         assert_eq!(i, 0);
@@ -349,13 +833,23 @@ mod test {
         assert_eq!(i, new_index);
     }
 
+    #[test]
+    fn put_updates_existing_record_instead_of_stale_slot() {
+        let mut bucket = Bucket::new();
+        bucket.put(123, 456, 0).unwrap();
+        let hash_key: u64 = 0xF000000000000000;
+        let i = bucket.put(hash_key, 1, 0).unwrap();
+        // At local_depth=1 the first record is stale, but hash_key is already in the bucket so
+        // it must be updated where it is
+        assert_eq!(bucket.put(hash_key, 2, 1).unwrap(), i);
+        assert_eq!(bucket.get(hash_key).unwrap().value, 2);
+        assert_eq!(bucket.iter().filter(|r| r.hash_key == hash_key).count(), 1);
+    }
+
     #[test]
     fn can_iterate_over_bucket() {
-        let mut bucket = Bucket {
-            offset: 0,
-            buf: [0; BUCKET_SIZE],
-        };
-        for i in 1..=16 {
+        let mut bucket = Bucket::new();
+        for i in 1..=DEFAULT_BUCKET_LAYOUT.records() as u64 {
             let _ = match bucket.put(i * 60, i * 2, 0) {
                 Err(e) => panic!("Unable to insert record: {}", e),
                 Ok(o) => o,
@@ -369,6 +863,109 @@ mod test {
         }
     }
 
+    #[test]
+    fn zero_key_and_value_are_not_empty() {
+        let mut bucket = Bucket::new();
+        assert!(bucket.get(0).is_none());
+        let i = bucket.put(0, 0, 0).unwrap();
+        assert!(bucket.is_occupied(i));
+        assert_eq!(bucket.get(0).unwrap().value, 0);
+        assert_eq!(bucket.len(), 1);
+        // The zeroed record must not be handed out as a free slot
+        let j = bucket.put(1, 1, 0).unwrap();
+        assert_ne!(i, j);
+    }
+
+    #[test]
+    fn slot_writes_cover_every_changed_byte() {
+        let layout = BucketLayout::new(4096);
+        let mut bucket = Bucket::with_layout(layout);
+        bucket.offset = 1000;
+        bucket.put(1, 1, 0).unwrap();
+        let before = bucket.clone();
+        let index = bucket.put(0xABCD, 42, 0).unwrap();
+        let mut patched = before.buf.clone();
+        let mut written = 0;
+        for (offset, bytes) in bucket.slot_writes(index) {
+            let start = (offset - bucket.offset) as usize;
+            patched[start..start + bytes.len()].copy_from_slice(bytes);
+            written += bytes.len();
+        }
+        assert_eq!(patched, bucket.buf);
+        // In a big bucket the occupancy bit, tombstone bit, fingerprint and record are all apart
+        assert_eq!(bucket.slot_ranges(index).len(), 4);
+        assert_eq!(written, 3 + layout.record_size());
+
+        bucket.remove(0xABCD).unwrap();
+        let mut patched = patched.clone();
+        for (offset, bytes) in bucket.slot_writes(index) {
+            let start = (offset - bucket.offset) as usize;
+            patched[start..start + bytes.len()].copy_from_slice(bytes);
+        }
+        assert_eq!(patched, bucket.buf);
+        // In a single cache line the whole bitmap is one byte, right before the fingerprints
+        let bucket = Bucket::new();
+        assert_eq!(bucket.slot_ranges(0), vec![0..2, 4..20]);
+    }
+
+    #[test]
+    fn bucket_refs_read_records_in_place() {
+        let layout = BucketLayout::new(256);
+        let mut bucket = Bucket::with_layout(layout);
+        for i in 1..=5u64 {
+            bucket.put(i, i * 10, 0).unwrap();
+        }
+        bucket.remove(3).unwrap();
+        let mut bytes = vec![0xFF; 7];
+        bytes.extend_from_slice(&bucket.buf);
+        let view = BucketRef::new(42, &bytes[7..], layout);
+        assert_eq!(view.len(), 4);
+        assert_eq!(view.get(4).unwrap().value, 40);
+        assert!(view.get(3).is_none());
+        assert!(view.is_tombstone(2));
+        let keys: Vec<u64> = view.iter().map(|r| r.hash_key).collect();
+        assert_eq!(keys, vec![1, 2, 4, 5]);
+        let owned = view.to_bucket();
+        assert_eq!(owned.offset, 42);
+        assert_eq!(owned.len_delta(), 0);
+        assert_eq!(owned.get(5).unwrap().value, 50);
+    }
+
+    #[test]
+    fn remove_leaves_a_reusable_tombstone() {
+        let mut bucket = Bucket::new();
+        for i in 0..DEFAULT_BUCKET_LAYOUT.records() as u64 {
+            bucket.put(i + 1, i, 0).unwrap();
+        }
+        assert!(!bucket.has_room(1234, 0));
+        let removed = bucket.remove(1).unwrap();
+        assert!(bucket.is_tombstone(removed));
+        assert!(!bucket.is_occupied(removed));
+        assert!(bucket.get(1).is_none());
+        assert!(bucket.remove(1).is_none());
+        assert_eq!(bucket.iter().count(), DEFAULT_BUCKET_LAYOUT.records() - 1);
+        assert_eq!(bucket.put(1234, 5, 0).unwrap(), removed);
+        assert!(!bucket.is_tombstone(removed));
+        assert_eq!(bucket.get(1234).unwrap().value, 5);
+    }
+
+    #[test]
+    fn records_with_the_same_fingerprint_are_told_apart() {
+        let mut bucket = Bucket::new();
+        let a: u64 = 0x1200_0000_0000_00AB;
+        let b: u64 = 0x3400_0000_0000_00AB;
+        assert_eq!(fingerprint(a), fingerprint(b));
+        bucket.put(a, 1, 0).unwrap();
+        assert!(bucket.get(b).is_none());
+        bucket.put(b, 2, 0).unwrap();
+        assert_eq!(bucket.get(a).unwrap().value, 1);
+        assert_eq!(bucket.get(b).unwrap().value, 2);
+        assert_eq!(bucket.view().matching_slots(a).count(), 2);
+        bucket.remove(a).unwrap();
+        assert_eq!(bucket.view().matching_slots(b).count(), 1);
+        assert_eq!(bucket.get(b).unwrap().value, 2);
+    }
+
     #[test]
     fn record_can_go_to_from_bytes() {
         let record = Record {
@@ -376,8 +973,42 @@ mod test {
             value: 1234,
         };
         let bytes = record.to_bytes();
-        let de_record = Record::from_bytes(bytes);
+        assert_eq!(bytes.len(), 16);
+        let de_record: Record = Record::from_bytes(&bytes);
         assert_eq!(de_record.hash_key, record.hash_key);
         assert_eq!(de_record.value, record.value);
     }
+
+    #[test]
+    fn wider_values_shrink_the_bucket() {
+        type Value = (u64, u64, u32);
+        assert_eq!(Value::SIZE, 20);
+        let layout = BucketLayout::with_value_size(256, Value::SIZE);
+        assert_eq!(layout.record_size(), 28);
+        assert_eq!(layout.records(), 8);
+        let mut bucket = Bucket::with_layout(layout);
+        for i in 1..=layout.records() as u64 {
+            bucket
+                .put_value(i, &(i, u64::MAX - i, i as u32 * 3), 0)
+                .unwrap();
+        }
+        assert!(!bucket.has_room(1234, 0));
+        let record = bucket.get_value::<Value>(5).unwrap();
+        assert_eq!(record.value, (5, u64::MAX - 5, 15));
+        let keys: Vec<u64> = bucket.iter_values::<Value>().map(|r| r.hash_key).collect();
+        assert_eq!(keys, (1..=8).collect::<Vec<u64>>());
+        let bytes = Record {
+            hash_key: 1,
+            value: [7u8; 3],
+        }
+        .to_bytes();
+        assert_eq!(bytes, vec![1, 0, 0, 0, 0, 0, 0, 0, 7, 7, 7]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn values_of_the_wrong_width_are_rejected() {
+        let mut bucket = Bucket::with_layout(BucketLayout::with_value_size(256, 16));
+        bucket.put(1, 1, 0).unwrap();
+    }
 }
@@ -0,0 +1,592 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::segment::bucket::{Bucket, BucketRef};
+use crate::segment::segment::{Geometry, Segment, SegmentBuf, Segmenter};
+
+use anyhow::Result;
+use log::{debug, warn};
+use parking_lot::Mutex;
+
+/// Counters for a `CachedSegmenter`'s buffer pool.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Bucket reads answered from the pool.
+    pub hits: u64,
+    /// Bucket reads that had to go to the wrapped Segmenter.
+    pub misses: u64,
+    /// Buckets dropped from the pool to make room for others.
+    pub evictions: u64,
+    /// Dirty buckets written to the wrapped Segmenter, on eviction or `sync`.
+    pub write_backs: u64,
+}
+
+struct Entry {
+    bucket: Bucket,
+    dirty: bool,
+    // When the entry was last used, its key in `BufferPool::lru`
+    used: u64,
+}
+
+/// Decoded buckets keyed by their offset.
+struct BufferPool {
+    capacity: usize,
+    entries: BTreeMap<u64, Entry>,
+    // Bucket offsets ordered by when they were last used, least recently used first
+    lru: BTreeMap<u64, u64>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+impl BufferPool {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            entries: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            stats: Default::default(),
+        }
+    }
+
+    /// Returns a copy of the bucket at `offset` and marks it as the most recently used.
+    fn get(&mut self, offset: u64) -> Option<Bucket> {
+        self.touch(offset).cloned()
+    }
+
+    /// Marks the bucket at `offset` as the most recently used and returns it.
+    fn touch(&mut self, offset: u64) -> Option<&Bucket> {
+        let entry = self.entries.get_mut(&offset)?;
+        self.lru.remove(&entry.used);
+        self.clock += 1;
+        entry.used = self.clock;
+        self.lru.insert(self.clock, offset);
+        Some(&entry.bucket)
+    }
+
+    /// The least recently used buckets that have to be evicted before `incoming` buckets can be
+    /// added. Buckets that are about to be replaced are never picked.
+    fn victims(&self, incoming: &[Bucket]) -> Vec<u64> {
+        let added = incoming
+            .iter()
+            .filter(|b| !self.entries.contains_key(&b.offset))
+            .count();
+        let excess = (self.entries.len() + added).saturating_sub(self.capacity);
+        self.lru
+            .values()
+            .filter(|&&offset| !incoming.iter().any(|b| b.offset == offset))
+            .take(excess)
+            .copied()
+            .collect()
+    }
+
+    /// Adds or replaces a bucket without checking the capacity. A replaced bucket's records are
+    /// still counted from when the wrapped Segmenter last saw it.
+    fn put(&mut self, mut bucket: Bucket, dirty: bool) {
+        if let Some(old) = self.entries.get(&bucket.offset) {
+            bucket.keep_baseline(&old.bucket);
+        }
+        self.clock += 1;
+        let entry = Entry {
+            bucket,
+            dirty,
+            used: self.clock,
+        };
+        self.lru.insert(self.clock, entry.bucket.offset);
+        if let Some(old) = self.entries.insert(entry.bucket.offset, entry) {
+            self.lru.remove(&old.used);
+        }
+    }
+
+    fn remove(&mut self, offset: u64) -> Option<Entry> {
+        let entry = self.entries.remove(&offset)?;
+        self.lru.remove(&entry.used);
+        Some(entry)
+    }
+
+    /// The offsets of the pooled buckets that belong to `segment`. Only `inner` knows where its
+    /// buckets are, the segment's buckets aren't necessarily contiguous.
+    fn segment_offsets<S: Segmenter>(&self, inner: &S, segment: &Segment) -> Vec<u64> {
+        (0..inner.geometry().segment_buckets())
+            .map(|index| inner.bucket_offset(segment, index))
+            .filter(|offset| self.entries.contains_key(offset))
+            .collect()
+    }
+
+    /// Writes the dirty buckets among `offsets` (or every pooled bucket if `None`) to `inner`
+    /// and marks them clean.
+    fn write_back<S: Segmenter>(&mut self, inner: &S, offsets: Option<&[u64]>) -> Result<()> {
+        let mut dirty: Vec<Bucket> = self
+            .entries
+            .iter()
+            .filter(|(offset, e)| e.dirty && offsets.is_none_or(|o| o.contains(offset)))
+            .map(|(_, e)| e.bucket.clone())
+            .collect();
+        if dirty.is_empty() {
+            return Ok(());
+        }
+        debug!("Writing back {} dirty buckets", dirty.len());
+        inner.write_buckets(&mut dirty)?;
+        self.stats.write_backs += dirty.len() as u64;
+        for bucket in &dirty {
+            let entry = self.entries.get_mut(&bucket.offset).unwrap();
+            entry.dirty = false;
+            // The wrapped Segmenter has counted these records now
+            entry.bucket.mark_clean();
+        }
+        Ok(())
+    }
+}
+
+/// The part of a `CachedSegmenter` that's shared between clones. The last clone to go drops it,
+/// which writes back whatever is still dirty.
+struct Shared<S: Segmenter> {
+    pool: Mutex<BufferPool>,
+    // Only used for the final write-back. The Segmenters this wraps are cloned for each thread
+    // rather than shared, so it's behind a lock of its own.
+    inner: Mutex<S>,
+}
+
+impl<S: Segmenter> Drop for Shared<S> {
+    fn drop(&mut self) {
+        let inner = self.inner.get_mut();
+        let result = self
+            .pool
+            .get_mut()
+            .write_back(&*inner, None)
+            .and_then(|_| inner.sync());
+        if let Err(e) = result {
+            warn!("Unable to write back cached buckets: {}", e);
+        }
+    }
+}
+
+/// A `Segmenter` that keeps recently used buckets in a bounded buffer pool in front of another
+/// Segmenter. Reads of pooled buckets don't touch storage and writes only update the pool, dirty
+/// buckets are written back when they're evicted, on `sync`, or when the last clone is dropped.
+///
+/// The pool is shared between clones. Everything other than bucket reads and writes goes
+/// straight to the wrapped Segmenter. Like the Segmenters it wraps, the caller has to keep two
+/// threads from writing the same bucket at once.
+pub struct CachedSegmenter<S: Segmenter> {
+    inner: S,
+    shared: Arc<Shared<S>>,
+}
+
+impl<S: Segmenter + Clone> Clone for CachedSegmenter<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<S: Segmenter + Clone> CachedSegmenter<S> {
+    /// Wraps `inner` with a pool that holds up to `cache_bytes` worth of buckets, but always at
+    /// least one.
+    pub fn new(inner: S, cache_bytes: usize) -> Self {
+        let capacity = cache_bytes / inner.geometry().bucket_size as usize;
+        debug!("Caching up to {} buckets", capacity);
+        Self {
+            shared: Arc::new(Shared {
+                pool: Mutex::new(BufferPool::new(capacity)),
+                inner: Mutex::new(inner.clone()),
+            }),
+            inner,
+        }
+    }
+}
+
+impl<S: Segmenter> CachedSegmenter<S> {
+    pub fn cache_stats(&self) -> CacheStats {
+        self.pool().lock().stats
+    }
+
+    /// The number of buckets in the pool and how many of them are dirty.
+    pub fn cached_buckets(&self) -> (usize, usize) {
+        let pool = self.pool().lock();
+        let dirty = pool.entries.values().filter(|e| e.dirty).count();
+        (pool.entries.len(), dirty)
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    fn pool(&self) -> &Mutex<BufferPool> {
+        &self.shared.pool
+    }
+
+    /// Writes back the pooled buckets of `segment` so the wrapped Segmenter's copy is up to
+    /// date, for when the whole segment is about to be read or written behind the pool's back.
+    /// If `evict` is set they're dropped from the pool too.
+    fn write_back_segment(&self, segment: &Segment, evict: bool) -> Result<()> {
+        let mut pool = self.pool().lock();
+        let offsets = pool.segment_offsets(&self.inner, segment);
+        pool.write_back(&self.inner, Some(&offsets))?;
+        if evict {
+            for offset in offsets {
+                pool.remove(offset);
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds buckets to the pool, evicting (and writing back) as many as it takes to make room.
+    /// The victims are only dropped once they've been written, so a failed write-back doesn't
+    /// lose them.
+    fn insert(&self, pool: &mut BufferPool, buckets: Vec<Bucket>, dirty: bool) -> Result<()> {
+        let victims = pool.victims(&buckets);
+        let mut write_backs: Vec<Bucket> = victims
+            .iter()
+            .filter_map(|offset| pool.entries.get(offset))
+            .filter(|e| e.dirty)
+            .map(|e| e.bucket.clone())
+            .collect();
+        if !write_backs.is_empty() {
+            debug!("Writing back {} evicted buckets", write_backs.len());
+            self.inner.write_buckets(&mut write_backs)?;
+            pool.stats.write_backs += write_backs.len() as u64;
+        }
+        for offset in victims {
+            pool.remove(offset);
+            pool.stats.evictions += 1;
+        }
+        for bucket in buckets {
+            pool.put(bucket, dirty);
+        }
+        Ok(())
+    }
+}
+
+impl<S: Segmenter> Segmenter for CachedSegmenter<S> {
+    type Header = S::Header;
+
+    fn segment(&self, index: u32) -> Result<Segment> {
+        self.inner.segment(index)
+    }
+
+    fn allocate_segment(&self, depth: u8) -> Result<(u32, Segment)> {
+        self.inner.allocate_segment(depth)
+    }
+
+    fn allocate_with_buckets(&self, buckets: Vec<Bucket>, depth: u8) -> Result<(u32, Segment)> {
+        self.inner.allocate_with_buckets(buckets, depth)
+    }
+
+    fn allocate_with_segment(&self, buf: &SegmentBuf) -> Result<(u32, Segment)> {
+        // Freed segments are dropped from the pool, so nothing pooled can be in the new one
+        self.inner.allocate_with_segment(buf)
+    }
+
+    fn read_segment(&self, segment: &Segment, buf: &mut SegmentBuf) -> Result<()> {
+        // Otherwise the buffer would start from records the wrapped Segmenter hasn't counted
+        self.write_back_segment(segment, false)?;
+        self.inner.read_segment(segment, buf)
+    }
+
+    fn write_segment(&self, segment: &Segment, buf: &SegmentBuf) -> Result<()> {
+        // The pooled buckets are about to be stale
+        self.write_back_segment(segment, true)?;
+        self.inner.write_segment(segment, buf)
+    }
+
+    fn bucket(&self, segment: &Segment, index: u32) -> Result<Bucket> {
+        Ok(self.buckets(segment, &[index])?.remove(0))
+    }
+
+    fn with_bucket<R>(
+        &self,
+        segment: &Segment,
+        index: u32,
+        f: impl FnOnce(BucketRef<'_>) -> R,
+    ) -> Result<R> {
+        let offset = self.inner.bucket_offset(segment, index);
+        {
+            // `f` runs with the pool locked so the pooled bucket can be lent out as is
+            let mut pool = self.pool().lock();
+            if let Some(bucket) = pool.touch(offset) {
+                let out = f(bucket.view());
+                pool.stats.hits += 1;
+                return Ok(out);
+            }
+        }
+        let bucket = self.bucket(segment, index)?;
+        Ok(f(bucket.view()))
+    }
+
+    fn bucket_offset(&self, segment: &Segment, index: u32) -> u64 {
+        self.inner.bucket_offset(segment, index)
+    }
+
+    fn write_bucket(&self, bucket: &mut Bucket) -> Result<()> {
+        let mut pool = self.pool().lock();
+        self.insert(&mut pool, vec![bucket.clone()], true)?;
+        // The pooled copy carries the change until it's written back
+        bucket.mark_clean();
+        Ok(())
+    }
+
+    fn buckets(&self, segment: &Segment, indexes: &[u32]) -> Result<Vec<Bucket>> {
+        let (mut found, missing) = {
+            let mut pool = self.pool().lock();
+            let found: Vec<Option<Bucket>> = indexes
+                .iter()
+                .map(|&i| pool.get(self.inner.bucket_offset(segment, i)))
+                .collect();
+            let missing: Vec<u32> = indexes
+                .iter()
+                .zip(&found)
+                .filter(|(_, b)| b.is_none())
+                .map(|(&i, _)| i)
+                .collect();
+            pool.stats.hits += (indexes.len() - missing.len()) as u64;
+            pool.stats.misses += missing.len() as u64;
+            (found, missing)
+        };
+        if missing.is_empty() {
+            return Ok(found.into_iter().flatten().collect());
+        }
+        // Read the misses without holding the pool so hits on other buckets aren't held up
+        let loaded = self.inner.buckets(segment, &missing)?;
+        let mut pool = self.pool().lock();
+        let mut fresh = Vec::with_capacity(loaded.len());
+        let mut loaded = loaded.into_iter();
+        for slot in found.iter_mut().filter(|b| b.is_none()) {
+            let bucket = loaded.next().expect("Segmenter returned too few buckets");
+            // Another reader may have loaded it first, its copy is just as good
+            if !pool.entries.contains_key(&bucket.offset) {
+                fresh.push(bucket.clone());
+            }
+            *slot = Some(bucket);
+        }
+        self.insert(&mut pool, fresh, false)?;
+        Ok(found.into_iter().flatten().collect())
+    }
+
+    fn write_buckets(&self, buckets: &mut [Bucket]) -> Result<()> {
+        let mut pool = self.pool().lock();
+        self.insert(&mut pool, buckets.to_vec(), true)?;
+        buckets.iter_mut().for_each(Bucket::mark_clean);
+        Ok(())
+    }
+
+    fn free_segment(&self, index: u32) -> Result<()> {
+        let segment = self.inner.segment(index)?;
+        {
+            // The segment's contents are garbage now, so dirty buckets are dropped unwritten
+            let mut pool = self.pool().lock();
+            let offsets = pool.segment_offsets(&self.inner, &segment);
+            for offset in offsets {
+                pool.remove(offset);
+            }
+        }
+        self.inner.free_segment(index)
+    }
+
+    fn geometry(&self) -> Geometry {
+        self.inner.geometry()
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.pool().lock().write_back(&self.inner, None)?;
+        self.inner.sync()
+    }
+
+    fn num_segments(&self) -> Result<u32> {
+        self.inner.num_segments()
+    }
+
+    fn update_segment(&self, segment: Segment) -> Result<()> {
+        self.inner.update_segment(segment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::directory::{Directory, MMapDirectory};
+    use crate::meh::MehDB;
+    use crate::meh::tests::{check_exercised, exercise};
+    #[cfg(unix)]
+    use crate::segment::DirectSegmenter;
+    use crate::segment::ThreadSafeFileSegmenter;
+    use tempfile::TempDir;
+
+    fn cached(dir: &TempDir, buckets: usize) -> CachedSegmenter<ThreadSafeFileSegmenter> {
+        let inner = ThreadSafeFileSegmenter::init(dir.path().join("segment.bin")).unwrap();
+        let bucket_size = inner.geometry().bucket_size as usize;
+        CachedSegmenter::new(inner, buckets * bucket_size)
+    }
+
+    #[test]
+    fn dirty_buckets_are_written_back_on_eviction() {
+        let dir = TempDir::new().unwrap();
+        let segmenter = cached(&dir, 2);
+        let segment = segmenter.segment(0).unwrap();
+        let mut bucket = segmenter.bucket(&segment, 0).unwrap();
+        bucket.put(123, 456, 0).unwrap();
+        segmenter.write_bucket(&mut bucket).unwrap();
+        // Still only in the pool
+        let on_disk = segmenter.inner().bucket(&segment, 0).unwrap();
+        assert!(on_disk.get(123).is_none());
+        assert_eq!(segmenter.cached_buckets(), (1, 1));
+
+        assert!(segmenter.bucket(&segment, 0).unwrap().get(123).is_some());
+        segmenter.bucket(&segment, 1).unwrap();
+        segmenter.bucket(&segment, 2).unwrap();
+        let stats = segmenter.cache_stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 3);
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.write_backs, 1);
+        let on_disk = segmenter.inner().bucket(&segment, 0).unwrap();
+        assert_eq!(on_disk.get(123).unwrap().value, 456);
+        assert_eq!(segmenter.inner().segment_meta(0).unwrap().records, 1);
+    }
+
+    #[test]
+    fn pooled_buckets_are_lent_out_without_a_read() {
+        let dir = TempDir::new().unwrap();
+        let segmenter = cached(&dir, 4);
+        let segment = segmenter.segment(0).unwrap();
+        let mut bucket = segmenter.bucket(&segment, 2).unwrap();
+        bucket.put(123, 456, 0).unwrap();
+        segmenter.write_bucket(&mut bucket).unwrap();
+        let record = segmenter
+            .with_bucket(&segment, 2, |bucket| bucket.get(123))
+            .unwrap();
+        assert_eq!(record.unwrap().value, 456);
+        let len = segmenter
+            .with_bucket(&segment, 3, |bucket| bucket.len())
+            .unwrap();
+        assert_eq!(len, 0);
+        let stats = segmenter.cache_stats();
+        assert_eq!((stats.hits, stats.misses), (1, 2));
+        assert_eq!(segmenter.cached_buckets(), (2, 1));
+    }
+
+    #[test]
+    fn sync_writes_back_and_keeps_buckets_cached() {
+        let dir = TempDir::new().unwrap();
+        let segmenter = cached(&dir, 16);
+        let segment = segmenter.segment(0).unwrap();
+        let mut bucket = segmenter.bucket(&segment, 3).unwrap();
+        bucket.put(1, 2, 0).unwrap();
+        segmenter.write_bucket(&mut bucket).unwrap();
+        segmenter.sync().unwrap();
+        assert_eq!(segmenter.cached_buckets(), (1, 0));
+        assert_eq!(segmenter.inner().segment_meta(0).unwrap().records, 1);
+
+        // The records written before the sync aren't counted twice
+        let mut bucket = segmenter.bucket(&segment, 3).unwrap();
+        bucket.put(3, 4, 0).unwrap();
+        segmenter.write_bucket(&mut bucket).unwrap();
+        segmenter.sync().unwrap();
+        assert_eq!(segmenter.inner().segment_meta(0).unwrap().records, 2);
+        assert_eq!(segmenter.cache_stats().write_backs, 2);
+        assert_eq!(segmenter.cache_stats().misses, 1);
+    }
+
+    #[test]
+    fn rewriting_a_pooled_bucket_only_counts_new_records() {
+        let dir = TempDir::new().unwrap();
+        let segmenter = cached(&dir, 16);
+        let segment = segmenter.segment(0).unwrap();
+        let mut bucket = segmenter.bucket(&segment, 3).unwrap();
+        bucket.put(1, 2, 0).unwrap();
+        segmenter.write_bucket(&mut bucket).unwrap();
+        // The pooled copy still has the first record to count when it's written back
+        bucket.put(3, 4, 0).unwrap();
+        segmenter.write_bucket(&mut bucket).unwrap();
+        segmenter.write_bucket(&mut bucket).unwrap();
+        segmenter.sync().unwrap();
+        assert_eq!(segmenter.inner().segment_meta(0).unwrap().records, 2);
+    }
+
+    #[test]
+    fn splits_keep_dirty_record_counts() {
+        let dir = TempDir::new().unwrap();
+        // Big enough that reading the whole segment doesn't evict the dirty bucket
+        let segmenter = cached(&dir, 1024);
+        let segment = segmenter.segment(0).unwrap();
+        let mut bucket = segmenter.bucket(&segment, 5).unwrap();
+        bucket.put(1, 1, 0).unwrap();
+        bucket.put(1 << 63, 2, 0).unwrap();
+        segmenter.write_bucket(&mut bucket).unwrap();
+        assert_eq!(segmenter.cached_buckets(), (1, 1));
+
+        // Split segment 0 the way MehDB does, moving the second record to a new segment
+        let geometry = segmenter.geometry();
+        let mut old = SegmentBuf::new(geometry);
+        let mut new = SegmentBuf::new(geometry);
+        segmenter.read_segment(&segment, &mut old).unwrap();
+        new.clear(1);
+        let mut old_bucket = old.bucket(5);
+        let mut new_bucket = Bucket::with_layout(geometry.bucket_layout());
+        new_bucket.put(1 << 63, 2, 1).unwrap();
+        old_bucket.remove(1 << 63).unwrap();
+        old.set_bucket(5, &old_bucket);
+        new.set_bucket(5, &new_bucket);
+        let (new_index, _) = segmenter.allocate_with_segment(&new).unwrap();
+        old.set_depth(1);
+        segmenter.write_segment(&segment, &old).unwrap();
+        segmenter.sync().unwrap();
+
+        assert_eq!(segmenter.inner().segment_meta(0).unwrap().records, 1);
+        assert_eq!(
+            segmenter.inner().segment_meta(new_index).unwrap().records,
+            1
+        );
+        let segment = segmenter.segment(0).unwrap();
+        assert_eq!(
+            segmenter.bucket(&segment, 5).unwrap().get(1).unwrap().value,
+            1
+        );
+    }
+
+    #[test]
+    fn mehdb_works_with_cached_segmenter() {
+        let dir = TempDir::new().unwrap();
+        {
+            let directory = MMapDirectory::init(dir.path().join("directory.bin")).unwrap();
+            let db = exercise(MehDB::with_parts(directory, cached(&dir, 512)).unwrap());
+            assert!(db.segmenter.cache_stats().evictions > 0);
+            // Dropping the database writes back whatever is still dirty
+        }
+        check_exercised(&mut MehDB::new(dir.path()).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wraps_segmenters_with_other_layouts() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("segment.direct");
+        let inner = DirectSegmenter::init(path.clone()).unwrap();
+        let segmenter = CachedSegmenter::new(inner, 512 * Geometry::DEFAULT.bucket_size as usize);
+        let segment = segmenter.segment(0).unwrap();
+        // A regular bucket and a stash bucket, which the direct layout keeps in the metadata page
+        for index in [3, 257] {
+            let mut bucket = segmenter.bucket(&segment, index).unwrap();
+            bucket.put(index as u64, 1, 0).unwrap();
+            segmenter.write_bucket(&mut bucket).unwrap();
+            let pooled = segmenter.bucket(&segment, index).unwrap();
+            assert_eq!(pooled.get(index as u64).unwrap().value, 1);
+        }
+        assert_eq!(segmenter.cache_stats().hits, 2);
+        drop(segmenter);
+        let inner = DirectSegmenter::init(path).unwrap();
+        let segment = inner.segment(0).unwrap();
+        assert!(inner.bucket(&segment, 257).unwrap().get(257).is_some());
+
+        let path = dir.path().join("mehdb.direct");
+        let directory = MMapDirectory::init(dir.path().join("directory.bin")).unwrap();
+        let inner = DirectSegmenter::init(path.clone()).unwrap();
+        let segmenter = CachedSegmenter::new(inner, 512 * Geometry::DEFAULT.bucket_size as usize);
+        drop(exercise(MehDB::with_parts(directory, segmenter).unwrap()));
+        let directory = MMapDirectory::init(dir.path().join("directory.bin")).unwrap();
+        let inner = DirectSegmenter::init(path).unwrap();
+        check_exercised(&mut MehDB::with_parts(directory, inner).unwrap());
+    }
+}
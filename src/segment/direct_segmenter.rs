@@ -0,0 +1,612 @@
+use std::alloc::{self, Layout};
+use std::fs::{File, OpenOptions};
+use std::io::Cursor;
+use std::ops::{Deref, DerefMut, Range};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::locking::StripedLock;
+use crate::segment::bucket::{Bucket, occupied_records};
+use crate::segment::segment::{
+    Geometry, PaddedHeader, Segment, SegmentBuf, SegmentMeta, SegmentSlots, SegmentTable,
+    Segmenter, load_geometry, punch_hole,
+};
+use crate::serializer::Serializable;
+
+use anyhow::{Context, Result, anyhow};
+use log::{debug, warn};
+use parking_lot::Mutex;
+
+// A direct segment file is laid out in pages so that every read and write can bypass the page
+// cache with O_DIRECT:
+//
+// | header page | segment 0 | segment 1 | ...
+//
+// and each segment is
+//
+// | metadata page (local depth in the first byte) | buckets, padded to a whole page |
+//
+// When the stash fits in the rest of the metadata page it's kept at the end of that page, right
+// before the regular buckets, so it doesn't cost a data page of its own.
+//
+// The header page holds `num_segments`, the geometry and the free list at the same offsets as
+// `segment.bin`, plus `MAGIC` so that the two layouts can't be mixed up.
+
+/// The alignment of every offset, length and buffer used for I/O.
+pub const PAGE_SIZE: usize = 4096;
+const MAGIC: &[u8; 8] = b"MEHDBDIO";
+
+/// A zeroed, page-aligned heap buffer as required by O_DIRECT.
+struct AlignedBuf {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// The buffer is uniquely owned, the same as a Vec<u8>
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
+
+impl AlignedBuf {
+    fn zeroed(len: usize) -> Self {
+        assert!(len > 0 && len.is_multiple_of(PAGE_SIZE));
+        let layout = Self::layout(len);
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        if ptr.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        Self { ptr, len }
+    }
+
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(len, PAGE_SIZE).unwrap()
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, Self::layout(self.len)) }
+    }
+}
+
+impl Deref for AlignedBuf {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+// The header page is kept in memory and written out in full whenever it changes. `take` updates
+// `num_segments` in the page as well as in `slots`.
+struct DirectHeader {
+    slots: SegmentSlots,
+    page: AlignedBuf,
+}
+
+/// A `Segmenter` that opens its file with `O_DIRECT` and a page-aligned layout, so segment I/O
+/// skips the page cache entirely. Buckets smaller than a page are written with a
+/// read-modify-write of their page, serialized by a striped lock per page. Clones share the file
+/// and all state.
+#[derive(Clone)]
+pub struct DirectSegmenter {
+    file: Arc<File>,
+    direct: bool,
+    header: Arc<Mutex<DirectHeader>>,
+    table: Arc<SegmentTable>,
+    page_locks: Arc<StripedLock<()>>,
+    geometry: Geometry,
+}
+
+fn round_to_page(len: usize) -> usize {
+    len.next_multiple_of(PAGE_SIZE)
+}
+
+impl DirectSegmenter {
+    pub fn init(path: PathBuf) -> Result<Self> {
+        Self::open(path, None)
+    }
+
+    /// The same as `init`, but a new segment file is created with `geometry`. Opening an existing
+    /// file with a different geometry is an error.
+    pub fn init_with_geometry(path: PathBuf, geometry: Geometry) -> Result<Self> {
+        Self::open(path, Some(geometry))
+    }
+
+    /// Opens `path` with `O_DIRECT`, falling back to buffered I/O if the filesystem doesn't
+    /// support it (tmpfs doesn't, for one).
+    fn open_file(path: &PathBuf) -> Result<(File, bool)> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).truncate(false).create(true);
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            let mut direct = options.clone();
+            direct.custom_flags(libc::O_DIRECT);
+            match direct.open(path) {
+                Ok(file) => return Ok((file, true)),
+                Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+                    warn!("{:?} doesn't support O_DIRECT, using buffered I/O", path);
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Opening segment file {:?}", path));
+                }
+            }
+        }
+        let file = options
+            .open(path)
+            .with_context(|| format!("Opening segment file {:?}", path))?;
+        Ok((file, false))
+    }
+
+    fn open(path: PathBuf, geometry: Option<Geometry>) -> Result<Self> {
+        let (file, direct) = Self::open_file(&path)?;
+        let mut page = AlignedBuf::zeroed(PAGE_SIZE);
+        let new = file.metadata()?.len() == 0;
+        if new {
+            page[4..12].copy_from_slice(MAGIC);
+        } else {
+            file.read_exact_at(&mut page, 0)
+                .context("Reading segment file header")?;
+            if &page[4..12] != MAGIC {
+                return Err(anyhow!(
+                    "{:?} is not a direct segment file, bad magic",
+                    path
+                ));
+            }
+        }
+        let (num_segments, geometry, free) = {
+            let mut header = Cursor::new(&mut page[..]);
+            let num_segments = u32::unpack(&mut header)?;
+            let geometry = load_geometry(&mut header, geometry)?;
+            (
+                num_segments,
+                geometry,
+                SegmentSlots::read_free_list(&mut header)?,
+            )
+        };
+        if !(PAGE_SIZE.is_multiple_of(geometry.bucket_size as usize)
+            || (geometry.bucket_size as usize).is_multiple_of(PAGE_SIZE))
+        {
+            return Err(anyhow!(
+                "Bucket size {} must divide or be a multiple of the {} byte page size",
+                geometry.bucket_size,
+                PAGE_SIZE
+            ));
+        }
+        if let Some(index) = free.iter().find(|&&i| i >= num_segments) {
+            return Err(anyhow!("Free list contains unallocated segment {}", index));
+        }
+        if new {
+            file.write_all_at(&page, 0)
+                .context("Writing segment file header")?;
+        }
+        let out = Self {
+            file: Arc::new(file),
+            direct,
+            header: Arc::new(Mutex::new(DirectHeader {
+                slots: SegmentSlots { num_segments, free },
+                page,
+            })),
+            table: Default::default(),
+            page_locks: Arc::new(StripedLock::init(1024)),
+            geometry,
+        };
+        out.load_table(num_segments)
+            .context("Loading segment metadata table")?;
+        if num_segments == 0 {
+            out.allocate_segment(0)
+                .context("Error initializing first segment.")?;
+        }
+        Ok(out)
+    }
+
+    /// Whether the file was opened with `O_DIRECT`.
+    pub fn is_direct(&self) -> bool {
+        self.direct
+    }
+
+    /// Returns a snapshot of the in-memory metadata for the segment at `index`.
+    pub fn segment_meta(&self, index: u32) -> Option<SegmentMeta> {
+        self.table.meta(index)
+    }
+
+    /// The indexes of the segments that are free to be reused.
+    pub fn free_segments(&self) -> Vec<u32> {
+        self.header.lock().slots.free.clone()
+    }
+
+    /// The size of a segment on disk, including its metadata page.
+    fn segment_stride(&self) -> usize {
+        PAGE_SIZE + round_to_page(self.data_len())
+    }
+
+    /// The size of the stash when it's packed into the metadata page, otherwise 0.
+    fn packed_stash_len(&self) -> usize {
+        let len = self.geometry.bucket_size as usize * self.geometry.stash_buckets as usize;
+        // The first byte of the page is the local depth
+        if len < PAGE_SIZE { len } else { 0 }
+    }
+
+    /// The size of the buckets that are kept in the data pages.
+    fn data_len(&self) -> usize {
+        self.geometry.bucket_size as usize * self.geometry.segment_buckets() as usize
+            - self.packed_stash_len()
+    }
+
+    /// The range of a segment, relative to its offset, that holds its buckets. With a packed stash
+    /// the buckets are stored stash first.
+    fn buckets_range(&self) -> Range<usize> {
+        PAGE_SIZE - self.packed_stash_len()..PAGE_SIZE + self.data_len()
+    }
+
+    /// The offset of bucket `index` relative to its segment's offset.
+    fn relative_bucket_offset(&self, index: u32) -> u64 {
+        let bucket_size = self.geometry.bucket_size as u64;
+        match index.checked_sub(self.geometry.buckets_per_segment) {
+            Some(stashed) if self.packed_stash_len() > 0 => {
+                (PAGE_SIZE - self.packed_stash_len()) as u64 + stashed as u64 * bucket_size
+            }
+            _ => PAGE_SIZE as u64 + index as u64 * bucket_size,
+        }
+    }
+
+    /// Lays out `buckets`, every bucket of a segment in index order as in a `SegmentBuf`, in a
+    /// segment sized `page`.
+    fn pack_buckets(&self, page: &mut [u8], depth: u8, buckets: &[u8]) {
+        let data = self.data_len();
+        page[0] = depth;
+        page[PAGE_SIZE..PAGE_SIZE + data].copy_from_slice(&buckets[..data]);
+        page[PAGE_SIZE - self.packed_stash_len()..PAGE_SIZE].copy_from_slice(&buckets[data..]);
+    }
+
+    /// The reverse of `pack_buckets`.
+    fn unpack_buckets(&self, page: &[u8], buckets: &mut [u8]) {
+        let data = self.data_len();
+        buckets[..data].copy_from_slice(&page[PAGE_SIZE..PAGE_SIZE + data]);
+        buckets[data..].copy_from_slice(&page[PAGE_SIZE - self.packed_stash_len()..PAGE_SIZE]);
+    }
+
+    fn segment_offset(&self, index: u32) -> u64 {
+        (PAGE_SIZE + index as usize * self.segment_stride()) as u64
+    }
+
+    fn segment_index_of(&self, offset: u64) -> u32 {
+        ((offset as usize - PAGE_SIZE) / self.segment_stride()) as u32
+    }
+
+    fn load_table(&self, num_segments: u32) -> Result<()> {
+        let mut buf = AlignedBuf::zeroed(self.segment_stride());
+        let layout = self.geometry.bucket_layout();
+        for index in 0..num_segments {
+            let offset = self.segment_offset(index);
+            self.file
+                .read_exact_at(&mut buf, offset)
+                .with_context(|| format!("Reading segment {} at offset {}", index, offset))?;
+            let records = occupied_records(&buf[self.buckets_range()], layout) as u32;
+            self.table.push(buf[0], records, offset);
+        }
+        Ok(())
+    }
+
+    fn write_header(&self, header: &mut DirectHeader) -> Result<()> {
+        let DirectHeader { slots, page } = header;
+        slots.write_free_list(&mut Cursor::new(&mut page[..]))?;
+        self.file
+            .write_all_at(page, 0)
+            .context("Writing segment file header")
+    }
+
+    /// Writes a whole segment and records it, reusing a freed segment if there is one.
+    fn allocate(&self, buf: AlignedBuf, depth: u8, records: u32) -> Result<(u32, Segment)> {
+        let mut header = self.header.lock();
+        let (index, reused) = {
+            let DirectHeader { slots, page } = &mut *header;
+            slots.take(&mut Cursor::new(&mut page[..]))?
+        };
+        let offset = self.segment_offset(index);
+        debug!("New segment offset: {}", offset);
+        self.file
+            .write_all_at(&buf, offset)
+            .with_context(|| format!("Writing new segment at offset {}", offset))?;
+        // Commit the segment
+        self.write_header(&mut header)?;
+        if reused {
+            self.table.reset(index, depth, records)?;
+        } else {
+            self.table.push(depth, records, offset);
+        }
+        Ok((index, Segment { depth, offset }))
+    }
+
+    /// The page-aligned range of the file that holds the bucket at `offset`.
+    fn bucket_pages(&self, offset: u64) -> (u64, usize) {
+        let start = offset - offset % PAGE_SIZE as u64;
+        let end = round_to_page(offset as usize + self.geometry.bucket_size as usize);
+        (start, end - start as usize)
+    }
+}
+
+impl Segmenter for DirectSegmenter {
+    type Header = PaddedHeader;
+
+    fn segment(&self, index: u32) -> Result<Segment> {
+        self.table.segment(index)
+    }
+
+    fn allocate_segment(&self, depth: u8) -> Result<(u32, Segment)> {
+        debug!("Allocating empty segment with depth {}", depth);
+        let mut buf = AlignedBuf::zeroed(self.segment_stride());
+        buf[0] = depth;
+        self.allocate(buf, depth, 0)
+    }
+
+    fn allocate_with_buckets(&self, buckets: Vec<Bucket>, depth: u8) -> Result<(u32, Segment)> {
+        // The number of buckets passed in *must* be the entire segment's buckets
+        let mut bytes = Cursor::new(Vec::new());
+        let mut records = 0;
+        for (i, bucket) in buckets.iter().enumerate() {
+            bucket
+                .pack(&mut bytes)
+                .with_context(|| format!("Writing bucket with index {}", i))?;
+            records += bucket.len() as u32;
+        }
+        let mut buf = AlignedBuf::zeroed(self.segment_stride());
+        self.pack_buckets(&mut buf, depth, bytes.get_ref());
+        self.allocate(buf, depth, records)
+    }
+
+    fn allocate_with_segment(&self, buf: &SegmentBuf) -> Result<(u32, Segment)> {
+        assert_eq!(buf.geometry(), self.geometry);
+        let mut page = AlignedBuf::zeroed(self.segment_stride());
+        self.pack_buckets(&mut page, buf.depth(), &buf.bytes()[1..]);
+        self.allocate(page, buf.depth(), buf.len() as u32)
+    }
+
+    fn read_segment(&self, segment: &Segment, buf: &mut SegmentBuf) -> Result<()> {
+        let mut page = AlignedBuf::zeroed(self.segment_stride());
+        self.file
+            .read_exact_at(&mut page, segment.offset)
+            .with_context(|| format!("Reading segment at offset {}", segment.offset))?;
+        let bytes = buf.prepare(self.geometry);
+        bytes[0] = page[0];
+        self.unpack_buckets(&page, &mut bytes[1..]);
+        let stash = self.relative_bucket_offset(self.geometry.buckets_per_segment);
+        buf.loaded_at(segment.offset + PAGE_SIZE as u64, segment.offset + stash);
+        Ok(())
+    }
+
+    fn write_segment(&self, segment: &Segment, buf: &SegmentBuf) -> Result<()> {
+        assert_eq!(buf.geometry(), self.geometry);
+        // The segment's pages aren't shared with any other segment, and the caller has the
+        // whole segment locked, so there's no need for the page locks
+        let mut page = AlignedBuf::zeroed(self.segment_stride());
+        self.pack_buckets(&mut page, buf.depth(), &buf.bytes()[1..]);
+        self.file
+            .write_all_at(&page, segment.offset)
+            .with_context(|| format!("Writing segment at offset {}", segment.offset))?;
+        let index = self.segment_index_of(segment.offset);
+        self.table.set_depth(index, buf.depth())?;
+        self.table.add_records(index, buf.len_delta())
+    }
+
+    fn bucket(&self, segment: &Segment, index: u32) -> Result<Bucket> {
+        assert!(index < self.geometry.segment_buckets());
+        let offset = self.bucket_offset(segment, index);
+        let (start, len) = self.bucket_pages(offset);
+        let mut buf = AlignedBuf::zeroed(len);
+        self.file
+            .read_exact_at(&mut buf, start)
+            .with_context(|| format!("Reading bucket at offset {}", offset))?;
+        let from = (offset - start) as usize;
+        let mut cursor = Cursor::new(&buf[from..from + self.geometry.bucket_size as usize]);
+        let mut bucket = Bucket::unpack(&mut cursor, self.geometry.bucket_layout())?;
+        bucket.offset = offset;
+        Ok(bucket)
+    }
+
+    fn bucket_offset(&self, segment: &Segment, index: u32) -> u64 {
+        segment.offset + self.relative_bucket_offset(index)
+    }
+
+    fn write_bucket(&self, bucket: &mut Bucket) -> Result<()> {
+        let (start, len) = self.bucket_pages(bucket.offset);
+        let mut buf = AlignedBuf::zeroed(len);
+        let from = (bucket.offset - start) as usize;
+        {
+            // Other buckets may share the page, so the page has to be read, patched and written
+            // back without another writer getting in between
+            let shared = len != self.geometry.bucket_size as usize;
+            let _page_lock = shared.then(|| {
+                self.page_locks
+                    .get((start / PAGE_SIZE as u64) as u32)
+                    .write()
+            });
+            if shared {
+                self.file
+                    .read_exact_at(&mut buf, start)
+                    .context("Reading bucket's page")?;
+            }
+            bucket.pack(&mut Cursor::new(&mut buf[from..]))?;
+            self.file
+                .write_all_at(&buf, start)
+                .with_context(|| format!("Writing bucket at offset {}", bucket.offset))?;
+        }
+        self.table
+            .add_records(self.segment_index_of(bucket.offset), bucket.len_delta())?;
+        bucket.mark_clean();
+        Ok(())
+    }
+
+    fn free_segment(&self, index: u32) -> Result<()> {
+        let mut header = self.header.lock();
+        header.slots.release(index)?;
+        self.write_header(&mut header)?;
+        self.table.reset(index, 0, 0)?;
+        let offset = self.segment_offset(index);
+        if let Err(e) = punch_hole(&self.file, offset, self.segment_stride() as u64) {
+            warn!("Unable to punch hole for freed segment {}: {}", index, e);
+        }
+        Ok(())
+    }
+
+    fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    fn num_segments(&self) -> Result<u32> {
+        Ok(self.header.lock().slots.num_segments)
+    }
+
+    fn update_segment(&self, segment: Segment) -> Result<()> {
+        debug!("Updating segment depth to {}", segment.depth);
+        let mut page = AlignedBuf::zeroed(PAGE_SIZE);
+        {
+            // Without a packed stash the metadata page only holds the depth, so it can be
+            // rewritten without reading it
+            let packed = self.packed_stash_len() > 0;
+            let _page_lock = packed.then(|| {
+                self.page_locks
+                    .get((segment.offset / PAGE_SIZE as u64) as u32)
+                    .write()
+            });
+            if packed {
+                self.file
+                    .read_exact_at(&mut page, segment.offset)
+                    .context("Reading segment metadata page")?;
+            }
+            page[0] = segment.depth;
+            self.file
+                .write_all_at(&page, segment.offset)
+                .context("Writing segment metadata page")?;
+        }
+        self.table
+            .set_depth(self.segment_index_of(segment.offset), segment.depth)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::directory::{Directory, MMapDirectory};
+    use crate::meh::MehDB;
+    use crate::meh::tests::{check_exercised, exercise};
+    use std::thread;
+    use tempfile::TempDir;
+
+    #[test]
+    fn segments_and_buckets_are_page_aligned() {
+        let dir = TempDir::new().unwrap();
+        let segmenter = DirectSegmenter::init(dir.path().join("segment.direct")).unwrap();
+        let (_, segment) = segmenter.allocate_segment(1).unwrap();
+        assert!(segment.offset.is_multiple_of(PAGE_SIZE as u64));
+        // 256 buckets of 64 bytes fill four pages, the stash is packed into the metadata page
+        assert_eq!(segmenter.segment_stride(), PAGE_SIZE + 4 * PAGE_SIZE);
+        let bucket = segmenter.bucket(&segment, 64).unwrap();
+        assert!((bucket.offset - segment.offset).is_multiple_of(PAGE_SIZE as u64));
+        let stashed = segmenter.bucket(&segment, 256).unwrap();
+        assert_eq!(stashed.offset - segment.offset, (PAGE_SIZE - 4 * 64) as u64);
+        // Other layouts are refused
+        assert!(crate::segment::ThreadSafeFileSegmenter::init(dir.path().join("x")).is_ok());
+        assert!(DirectSegmenter::init(dir.path().join("x")).is_err());
+        let geometry = Geometry {
+            bucket_size: 96,
+            ..Geometry::DEFAULT
+        };
+        assert!(DirectSegmenter::init_with_geometry(dir.path().join("y"), geometry).is_err());
+    }
+
+    #[test]
+    fn packed_stash_survives_depth_updates_and_reopening() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("segment.direct");
+        let segmenter = DirectSegmenter::init(path.clone()).unwrap();
+        let segment = segmenter.segment(0).unwrap();
+        let mut stashed = segmenter.bucket(&segment, 259).unwrap();
+        stashed.put(123, 456, 0).unwrap();
+        segmenter.write_bucket(&mut stashed).unwrap();
+        segmenter
+            .update_segment(Segment {
+                depth: 1,
+                ..segment
+            })
+            .unwrap();
+        let segment = segmenter.segment(0).unwrap();
+        assert_eq!(segment.depth, 1);
+
+        let mut buf = SegmentBuf::new(segmenter.geometry());
+        segmenter.read_segment(&segment, &mut buf).unwrap();
+        assert_eq!(buf.bucket_ref(259).get(123).unwrap().value, 456);
+        // The buffer's buckets have the same offsets as the ones read one at a time
+        for index in [0, 255, 256, 259] {
+            let offset = segmenter.bucket_offset(&segment, index);
+            assert_eq!(buf.bucket(index).offset, offset);
+            assert_eq!(buf.bucket_ref(index).offset, offset);
+        }
+        let mut first = buf.bucket(0);
+        first.put(789, 1, 1).unwrap();
+        buf.set_bucket(0, &first);
+        segmenter.write_segment(&segment, &buf).unwrap();
+        drop(segmenter);
+
+        let segmenter = DirectSegmenter::init(path).unwrap();
+        assert_eq!(segmenter.segment_meta(0).unwrap().records, 2);
+        let segment = segmenter.segment(0).unwrap();
+        assert_eq!(segment.depth, 1);
+        let stashed = segmenter.bucket(&segment, 259).unwrap();
+        assert_eq!(stashed.get(123).unwrap().value, 456);
+        let first = segmenter.bucket(&segment, 0).unwrap();
+        assert_eq!(first.get(789).unwrap().value, 1);
+    }
+
+    #[test]
+    fn concurrent_writes_to_a_shared_page_are_not_lost() {
+        let dir = TempDir::new().unwrap();
+        let segmenter = DirectSegmenter::init(dir.path().join("segment.direct")).unwrap();
+        let threads: Vec<_> = (0..8u32)
+            .map(|t| {
+                let segmenter = segmenter.clone();
+                thread::spawn(move || {
+                    let segment = segmenter.segment(0).unwrap();
+                    // Every thread owns 8 of the 64 buckets in the first page
+                    for i in 0..8 {
+                        let mut bucket = segmenter.bucket(&segment, t * 8 + i).unwrap();
+                        bucket.put((t * 8 + i) as u64, t as u64, 0).unwrap();
+                        segmenter.write_bucket(&mut bucket).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let segment = segmenter.segment(0).unwrap();
+        for i in 0..64 {
+            let bucket = segmenter.bucket(&segment, i).unwrap();
+            assert_eq!(bucket.get(i as u64).unwrap().value, (i / 8) as u64);
+        }
+        assert_eq!(segmenter.segment_meta(0).unwrap().records, 64);
+    }
+
+    #[test]
+    fn mehdb_works_with_direct_segmenter() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("segment.direct");
+        let directory = MMapDirectory::init(dir.path().join("directory.bin")).unwrap();
+        let segmenter = DirectSegmenter::init(path.clone()).unwrap();
+        let db = exercise(MehDB::with_parts(directory, segmenter).unwrap());
+        let segments = db.segmenter.num_segments().unwrap();
+        drop(db);
+
+        let directory = MMapDirectory::init(dir.path().join("directory.bin")).unwrap();
+        let segmenter = DirectSegmenter::init(path).unwrap();
+        let mut db = MehDB::with_parts(directory, segmenter).unwrap();
+        assert_eq!(db.segmenter.num_segments().unwrap(), segments);
+        check_exercised(&mut db);
+    }
+}
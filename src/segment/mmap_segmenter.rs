@@ -0,0 +1,414 @@
+use std::fs::{File, OpenOptions};
+use std::io::Cursor;
+use std::mem::size_of;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::segment::bucket::{Bucket, BucketRef, occupied_records};
+use crate::segment::segment::{
+    Geometry, PaddedHeader, Segment, SegmentBuf, SegmentMeta, SegmentSlots, SegmentTable,
+    Segmenter, load_geometry, punch_hole,
+};
+use crate::serializer::Serializable;
+
+use anyhow::{Context, Result, anyhow};
+use log::{debug, info, warn};
+use memmap2::{MmapOptions, MmapRaw};
+use parking_lot::{Mutex, RwLock};
+
+// The number of segments the file grows by whenever it runs out of room, so that allocating a
+// segment only has to remap the file every so often.
+pub const GROW_SEGMENTS: u32 = 64;
+const SEGMENTS_START: u64 = size_of::<PaddedHeader>() as u64;
+
+/// A `Segmenter` that memory maps `segment.bin` instead of reading and writing it through a
+/// `File`, so reading a bucket is a copy out of the map with no syscalls. It uses exactly the same
+/// file format as `ThreadSafeFileSegmenter`.
+///
+/// The map is shared between clones. It's only write-locked to grow and remap the file, reads
+/// and writes of buckets go through a read lock. Concurrent writes to the same bucket have to be
+/// prevented by the caller, the same as with `ThreadSafeFileSegmenter`.
+#[derive(Clone)]
+pub struct MmapSegmenter {
+    file: Arc<File>,
+    map: Arc<RwLock<MmapRaw>>,
+    slots: Arc<Mutex<SegmentSlots>>,
+    table: Arc<SegmentTable>,
+    geometry: Geometry,
+}
+
+/// Checks that `len` bytes starting at `offset` are inside `map`, returning `offset`.
+fn check_range(map: &MmapRaw, offset: u64, len: usize) -> Result<usize> {
+    let offset = offset as usize;
+    if offset + len > map.len() {
+        return Err(anyhow!(
+            "Range {}..{} is past the end of the {} byte map",
+            offset,
+            offset + len,
+            map.len()
+        ));
+    }
+    Ok(offset)
+}
+
+/// Returns `len` bytes of `map` starting at `offset` to read.
+// Safety: the map is never shrunk while it's read locked, and the callers hold at least a read
+// lock on what they read (the segment slots lock for the header, bucket locks for buckets), so
+// nothing writes the bytes while the slice is alive.
+fn bytes(map: &MmapRaw, offset: u64, len: usize) -> Result<&[u8]> {
+    let offset = check_range(map, offset, len)?;
+    Ok(unsafe { std::slice::from_raw_parts(map.as_ptr().add(offset), len) })
+}
+
+/// Returns `len` bytes of `map` starting at `offset` to write.
+// Safety: as for `bytes`, except the callers must hold the write locks (the segment slots lock
+// for the header, bucket write locks for buckets) that keep any other thread from touching the
+// same bytes at once.
+#[allow(clippy::mut_from_ref)]
+fn bytes_mut(map: &MmapRaw, offset: u64, len: usize) -> Result<&mut [u8]> {
+    let offset = check_range(map, offset, len)?;
+    Ok(unsafe { std::slice::from_raw_parts_mut(map.as_mut_ptr().add(offset), len) })
+}
+
+fn header(map: &MmapRaw) -> Result<Cursor<&mut [u8]>> {
+    Ok(Cursor::new(bytes_mut(map, 0, SEGMENTS_START as usize)?))
+}
+
+impl MmapSegmenter {
+    pub fn init(path: PathBuf) -> Result<Self> {
+        Self::open(path, None)
+    }
+
+    /// The same as `init`, but a new segment file is created with `geometry`. Opening an existing
+    /// file with a different geometry is an error.
+    pub fn init_with_geometry(path: PathBuf, geometry: Geometry) -> Result<Self> {
+        Self::open(path, Some(geometry))
+    }
+
+    fn open(path: PathBuf, geometry: Option<Geometry>) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .create(true)
+            .open(&path)
+            .with_context(|| format!("Opening segment file {:?}", path))?;
+        if file.metadata()?.len() < SEGMENTS_START {
+            file.set_len(SEGMENTS_START)
+                .context("Reserving segment file header")?;
+        }
+        let map = MmapOptions::new()
+            .map_raw(&file)
+            .with_context(|| format!("Mapping segment file {:?}", path))?;
+        let (num_segments, geometry, free) = {
+            let mut header = header(&map)?;
+            let num_segments = u32::unpack(&mut header)?;
+            let geometry = load_geometry(&mut header, geometry)?;
+            (
+                num_segments,
+                geometry,
+                SegmentSlots::read_free_list(&mut header)?,
+            )
+        };
+        if let Some(index) = free.iter().find(|&&i| i >= num_segments) {
+            return Err(anyhow!("Free list contains unallocated segment {}", index));
+        }
+        let out = Self {
+            file: Arc::new(file),
+            map: Arc::new(RwLock::new(map)),
+            slots: Arc::new(Mutex::new(SegmentSlots { num_segments, free })),
+            table: Default::default(),
+            geometry,
+        };
+        out.load_table(num_segments)
+            .context("Loading segment metadata table")?;
+        if num_segments == 0 {
+            out.allocate_segment(0)
+                .context("Error initializing first segment.")?;
+        }
+        Ok(out)
+    }
+
+    /// Returns a snapshot of the in-memory metadata for the segment at `index`.
+    pub fn segment_meta(&self, index: u32) -> Option<SegmentMeta> {
+        self.table.meta(index)
+    }
+
+    /// The indexes of the segments that are free to be reused.
+    pub fn free_segments(&self) -> Vec<u32> {
+        self.slots.lock().free.clone()
+    }
+
+    fn load_table(&self, num_segments: u32) -> Result<()> {
+        let map = self.map.read();
+        let size = self.geometry.segment_size();
+        for index in 0..num_segments {
+            let offset = self.segment_offset(index);
+            let buf = bytes(&map, offset, size)
+                .with_context(|| format!("Reading segment {} at offset {}", index, offset))?;
+            let records = occupied_records(&buf[1..], self.geometry.bucket_layout()) as u32;
+            self.table.push(buf[0], records, offset);
+        }
+        Ok(())
+    }
+
+    /// Grows the file and remaps it if segment `index` doesn't fit in the map yet.
+    fn ensure_capacity(&self, index: u32) -> Result<()> {
+        let needed = self.segment_offset(index + 1);
+        if self.map.read().len() as u64 >= needed {
+            return Ok(());
+        }
+        let mut map = self.map.write();
+        if map.len() as u64 >= needed {
+            return Ok(());
+        }
+        let segments = (index + 1).next_multiple_of(GROW_SEGMENTS);
+        let len = self.segment_offset(segments);
+        info!("Growing segment file to {} segments", segments);
+        self.file.set_len(len).context("Growing segment file")?;
+        *map = MmapOptions::new()
+            .map_raw(&*self.file)
+            .context("Remapping grown segment file")?;
+        Ok(())
+    }
+
+    /// Records a newly written segment in the segment table, committing it to the free list
+    /// first if it reused a freed segment.
+    fn commit_allocation(
+        &self,
+        slots: &SegmentSlots,
+        index: u32,
+        reused: bool,
+        depth: u8,
+        records: u32,
+    ) -> Result<()> {
+        if !reused {
+            self.table.push(depth, records, self.segment_offset(index));
+            return Ok(());
+        }
+        slots.write_free_list(&mut header(&self.map.read())?)?;
+        self.table.reset(index, depth, records)
+    }
+
+    fn segment_offset(&self, index: u32) -> u64 {
+        SEGMENTS_START + index as u64 * self.geometry.segment_size() as u64
+    }
+
+    fn segment_index_of(&self, offset: u64) -> u32 {
+        ((offset - SEGMENTS_START) / self.geometry.segment_size() as u64) as u32
+    }
+}
+
+impl Segmenter for MmapSegmenter {
+    type Header = PaddedHeader;
+
+    fn segment(&self, index: u32) -> Result<Segment> {
+        self.table.segment(index)
+    }
+
+    fn allocate_segment(&self, depth: u8) -> Result<(u32, Segment)> {
+        debug!("Allocating empty segment with depth {}", depth);
+        let mut slots = self.slots.lock();
+        let (index, reused) = slots.take(&mut header(&self.map.read())?)?;
+        self.ensure_capacity(index)?;
+        let offset = self.segment_offset(index);
+        {
+            let map = self.map.read();
+            let buf = bytes_mut(&map, offset, self.geometry.segment_size())?;
+            buf.fill(0);
+            buf[0] = depth;
+        }
+        self.commit_allocation(&slots, index, reused, depth, 0)?;
+        Ok((index, Segment { depth, offset }))
+    }
+
+    fn allocate_with_buckets(&self, buckets: Vec<Bucket>, depth: u8) -> Result<(u32, Segment)> {
+        // The number of buckets passed in *must* be the entire segment's buckets
+        let mut slots = self.slots.lock();
+        let (index, reused) = slots.take(&mut header(&self.map.read())?)?;
+        self.ensure_capacity(index)?;
+        let offset = self.segment_offset(index);
+        let mut records = 0;
+        {
+            let map = self.map.read();
+            let buf = bytes_mut(&map, offset, self.geometry.segment_size())?;
+            buf[0] = depth;
+            let mut cursor = Cursor::new(&mut buf[1..]);
+            for (i, bucket) in buckets.iter().enumerate() {
+                bucket
+                    .pack(&mut cursor)
+                    .with_context(|| format!("Writing bucket with index {}", i))?;
+                records += bucket.len() as u32;
+            }
+        }
+        self.commit_allocation(&slots, index, reused, depth, records)?;
+        Ok((index, Segment { offset, depth }))
+    }
+
+    fn allocate_with_segment(&self, buf: &SegmentBuf) -> Result<(u32, Segment)> {
+        assert_eq!(buf.geometry(), self.geometry);
+        let mut slots = self.slots.lock();
+        let (index, reused) = slots.take(&mut header(&self.map.read())?)?;
+        self.ensure_capacity(index)?;
+        let offset = self.segment_offset(index);
+        {
+            let map = self.map.read();
+            bytes_mut(&map, offset, self.geometry.segment_size())?.copy_from_slice(buf.bytes());
+        }
+        let depth = buf.depth();
+        self.commit_allocation(&slots, index, reused, depth, buf.len() as u32)?;
+        Ok((index, Segment { offset, depth }))
+    }
+
+    fn read_segment(&self, segment: &Segment, buf: &mut SegmentBuf) -> Result<()> {
+        {
+            let map = self.map.read();
+            let segment_bytes = bytes(&map, segment.offset, self.geometry.segment_size())?;
+            buf.prepare(self.geometry).copy_from_slice(segment_bytes);
+        }
+        buf.loaded(segment.offset + 1);
+        Ok(())
+    }
+
+    fn write_segment(&self, segment: &Segment, buf: &SegmentBuf) -> Result<()> {
+        assert_eq!(buf.geometry(), self.geometry);
+        {
+            let map = self.map.read();
+            bytes_mut(&map, segment.offset, self.geometry.segment_size())?
+                .copy_from_slice(buf.bytes());
+        }
+        let index = self.segment_index_of(segment.offset);
+        self.table.set_depth(index, buf.depth())?;
+        self.table.add_records(index, buf.len_delta())
+    }
+
+    fn bucket(&self, segment: &Segment, index: u32) -> Result<Bucket> {
+        assert!(index < self.geometry.segment_buckets());
+        let offset = self.bucket_offset(segment, index);
+        let map = self.map.read();
+        let buf = bytes(&map, offset, self.geometry.bucket_size as usize)?;
+        let mut bucket = Bucket::unpack(&mut Cursor::new(buf), self.geometry.bucket_layout())?;
+        bucket.offset = offset;
+        Ok(bucket)
+    }
+
+    fn bucket_offset(&self, segment: &Segment, index: u32) -> u64 {
+        //-------------------------------------------------------------------👇 for segment_depth
+        segment.offset + index as u64 * self.geometry.bucket_size as u64 + 1
+    }
+
+    fn with_bucket<R>(
+        &self,
+        segment: &Segment,
+        index: u32,
+        f: impl FnOnce(BucketRef<'_>) -> R,
+    ) -> Result<R> {
+        assert!(index < self.geometry.segment_buckets());
+        let offset = self.bucket_offset(segment, index);
+        let map = self.map.read();
+        let buf = bytes(&map, offset, self.geometry.bucket_size as usize)?;
+        let bucket = BucketRef::new(offset, buf, self.geometry.bucket_layout());
+        Ok(f(bucket))
+    }
+
+    fn write_bucket(&self, bucket: &mut Bucket) -> Result<()> {
+        {
+            let map = self.map.read();
+            let buf = bytes_mut(&map, bucket.offset, self.geometry.bucket_size as usize)?;
+            bucket.pack(&mut Cursor::new(buf))?;
+        }
+        self.table
+            .add_records(self.segment_index_of(bucket.offset), bucket.len_delta())?;
+        bucket.mark_clean();
+        Ok(())
+    }
+
+    fn write_slot(&self, bucket: &mut Bucket, index: usize) -> Result<()> {
+        {
+            let map = self.map.read();
+            for (offset, bytes) in bucket.slot_writes(index) {
+                bytes_mut(&map, offset, bytes.len())?.copy_from_slice(bytes);
+            }
+        }
+        self.table
+            .add_records(self.segment_index_of(bucket.offset), bucket.len_delta())?;
+        bucket.mark_clean();
+        Ok(())
+    }
+
+    fn free_segment(&self, index: u32) -> Result<()> {
+        let mut slots = self.slots.lock();
+        slots.release(index)?;
+        slots.write_free_list(&mut header(&self.map.read())?)?;
+        self.table.reset(index, 0, 0)?;
+        let offset = self.segment_offset(index);
+        if let Err(e) = punch_hole(&self.file, offset, self.geometry.segment_size() as u64) {
+            warn!("Unable to punch hole for freed segment {}: {}", index, e);
+        }
+        Ok(())
+    }
+
+    fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    fn num_segments(&self) -> Result<u32> {
+        Ok(self.slots.lock().num_segments)
+    }
+
+    fn update_segment(&self, segment: Segment) -> Result<()> {
+        debug!("Updating segment depth to {}", segment.depth);
+        {
+            let map = self.map.read();
+            bytes_mut(&map, segment.offset, 1)?[0] = segment.depth;
+        }
+        self.table
+            .set_depth(self.segment_index_of(segment.offset), segment.depth)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::directory::{Directory, MMapDirectory};
+    use crate::meh::MehDB;
+    use crate::meh::tests::{check_exercised, exercise};
+    use tempfile::TempDir;
+
+    #[test]
+    fn mmap_segmenter_grows_and_reopens() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("segment.bin");
+        let segmenter = MmapSegmenter::init(path.clone()).unwrap();
+        for _ in 0..GROW_SEGMENTS + 1 {
+            segmenter.allocate_segment(1).unwrap();
+        }
+        let last = segmenter.segment(GROW_SEGMENTS + 1).unwrap();
+        let mut bucket = segmenter.bucket(&last, 255).unwrap();
+        bucket.put(123, 456, 0).unwrap();
+        segmenter.write_bucket(&mut bucket).unwrap();
+        drop(segmenter);
+
+        let segmenter = MmapSegmenter::init(path).unwrap();
+        assert_eq!(segmenter.num_segments().unwrap(), GROW_SEGMENTS + 2);
+        let meta = segmenter.segment_meta(GROW_SEGMENTS + 1).unwrap();
+        assert_eq!((meta.depth, meta.records), (1, 1));
+        let last = segmenter.segment(GROW_SEGMENTS + 1).unwrap();
+        let bucket = segmenter.bucket(&last, 255).unwrap();
+        assert_eq!(bucket.get(123).unwrap().value, 456);
+    }
+
+    #[test]
+    fn mmap_segmenter_shares_the_file_format() {
+        let dir = TempDir::new().unwrap();
+        let directory = MMapDirectory::init(dir.path().join("directory.bin")).unwrap();
+        let segmenter = MmapSegmenter::init(dir.path().join("segment.bin")).unwrap();
+        let db = exercise(MehDB::with_parts(directory, segmenter).unwrap());
+        let segments = db.segmenter.num_segments().unwrap();
+        drop(db);
+        // Files written through the map can be read with the file-based segmenter
+        let mut db = MehDB::new(dir.path()).unwrap();
+        assert_eq!(db.segmenter.num_segments().unwrap(), segments);
+        check_exercised(&mut db);
+    }
+}
@@ -1,6 +1,23 @@
+pub mod basic_segmenter;
 pub mod bucket;
+pub mod cached_segmenter;
+#[cfg(unix)]
+pub mod direct_segmenter;
 //pub mod file_segmenter;
+pub mod mmap_segmenter;
+#[allow(clippy::module_inception)]
 pub mod segment;
+pub mod striped_segmenter;
+#[cfg(target_os = "linux")]
+pub mod uring_segmenter;
 
+pub use basic_segmenter::BasicSegmenter;
 pub use bucket::*;
+pub use cached_segmenter::{CacheStats, CachedSegmenter};
+#[cfg(unix)]
+pub use direct_segmenter::DirectSegmenter;
+pub use mmap_segmenter::MmapSegmenter;
 pub use segment::*;
+pub use striped_segmenter::{Placement, StripedSegmenter};
+#[cfg(target_os = "linux")]
+pub use uring_segmenter::UringSegmenter;
@@ -5,23 +5,167 @@ use std::iter::Iterator;
 use std::mem::size_of;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, AtomicU32, Ordering};
 
-use crate::segment::bucket::{Bucket, BUCKET_SIZE};
+use crate::segment::bucket::{
+    Bucket, BucketLayout, BucketRef, CACHE_LINE_SIZE, FixedValue, Record, matching_slots,
+    occupied_records,
+};
 use crate::serializer::Serializable;
 
-use anyhow::{Context, Result};
-use log::{debug, info};
-use parking_lot::Mutex;
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+use parking_lot::{Mutex, RwLock};
 
-// The number of buckets in each segment.
-// This may be adapted to be parametrizable on a per-database level
-// in the futere.
-pub const BUCKETS_PER_SEGMENT: usize = 64;
+// The offset in the segment file's header where the geometry is stored. It's past the fields of
+// the single-file superblock, which shares the header.
+pub const GEOMETRY_OFFSET: u64 = 64;
+// The geometry is stored as five u32s.
+const GEOMETRY_SIZE: u64 = 5 * 4;
+// The offset in the header where a `StripedSegmenter` records a file's place in the stripe set:
+// the number of stripes followed by the file's stripe number.
+pub const STRIPE_OFFSET: u64 = 96;
+// The offset of the free list in the header: a u32 count followed by that many u32 segment
+// indexes. It runs to the end of the 4KiB header.
+pub const FREE_LIST_OFFSET: u64 = 128;
+/// The most freed segments the header can keep track of at once.
+pub const MAX_FREE_SEGMENTS: usize = (4096 - FREE_LIST_OFFSET as usize - 4) / 4;
+const _: () = assert!(
+    GEOMETRY_OFFSET + GEOMETRY_SIZE <= STRIPE_OFFSET && STRIPE_OFFSET + 8 <= FREE_LIST_OFFSET,
+    "Header fields overlap"
+);
 
-// The size on-disk of a segment
-// ------------------------------------------------------------------👇 offset for local_depth
-pub const SEGMENT_SIZE: usize = (BUCKET_SIZE * BUCKETS_PER_SEGMENT) + 1;
+/// The shape of a database's segments and buckets. It's chosen when the database is created and
+/// stored in the file header, so it can't change afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    /// The size of each bucket in bytes.
+    pub bucket_size: u32,
+    /// The number of buckets in each segment. Must be a power of two.
+    pub buckets_per_segment: u32,
+    /// The number of buckets, starting at a key's home bucket, that a record may be placed in
+    /// before the segment has to be split. Probing wraps around within the segment.
+    pub probe_distance: u32,
+    /// The number of overflow buckets after the regular buckets of each segment. A record goes
+    /// to the stash when its probe window is full, so the segment only has to be split once the
+    /// stash is full too.
+    pub stash_buckets: u32,
+    /// The size of the value in each record, in bytes. It has to match the `FixedValue` the
+    /// database is opened with.
+    pub value_size: u32,
+}
+
+impl Geometry {
+    // Cache-line sized buckets give the 16KiB segments from the CCEH paper, plus a small stash.
+    pub const DEFAULT: Self = Self {
+        bucket_size: CACHE_LINE_SIZE as u32,
+        buckets_per_segment: 256,
+        probe_distance: 4,
+        stash_buckets: 4,
+        value_size: size_of::<u64>() as u32,
+    };
+
+    pub fn validate(&self) -> Result<()> {
+        if self.value_size == 0 {
+            return Err(anyhow!("value_size must be at least 1"));
+        }
+        if self.bucket_layout().records() == 0 {
+            return Err(anyhow!(
+                "Buckets of {} bytes are too small to hold a record",
+                self.bucket_size
+            ));
+        }
+        if !self.buckets_per_segment.is_power_of_two() {
+            return Err(anyhow!(
+                "buckets_per_segment must be a power of two, got {}",
+                self.buckets_per_segment
+            ));
+        }
+        if self.probe_distance == 0 || self.probe_distance > self.buckets_per_segment {
+            return Err(anyhow!(
+                "probe_distance must be between 1 and {}, got {}",
+                self.buckets_per_segment,
+                self.probe_distance
+            ));
+        }
+        if self.stash_buckets > self.buckets_per_segment {
+            return Err(anyhow!(
+                "stash_buckets can't be more than buckets_per_segment ({}), got {}",
+                self.buckets_per_segment,
+                self.stash_buckets
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn bucket_layout(&self) -> BucketLayout {
+        BucketLayout::with_value_size(self.bucket_size as usize, self.value_size as usize)
+    }
+
+    /// The number of buckets in a segment, including the stash.
+    pub const fn segment_buckets(&self) -> u32 {
+        self.buckets_per_segment + self.stash_buckets
+    }
+
+    /// The size on-disk of a segment, including the leading local depth byte.
+    pub fn segment_size(&self) -> usize {
+        self.bucket_size as usize * self.segment_buckets() as usize + 1
+    }
+
+    /// The index of the home bucket for a record, picked with bits of the hash that aren't used
+    /// for the directory.
+    pub fn home_bucket(&self, hk: u64) -> u32 {
+        (hk & (self.buckets_per_segment - 1) as u64) as u32
+    }
+
+    /// Returns the indexes of the buckets a record whose home bucket is `home` may be stored in,
+    /// in the order they are probed.
+    pub fn probe_window(&self, home: u32) -> Vec<u32> {
+        (0..self.probe_distance)
+            .map(|i| (home + i) % self.buckets_per_segment)
+            .collect()
+    }
+
+    /// Returns the indexes of the stash buckets, which come after the regular buckets.
+    pub fn stash(&self) -> Vec<u32> {
+        (self.buckets_per_segment..self.segment_buckets()).collect()
+    }
+}
+
+impl Default for Geometry {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl Serializable for Geometry {
+    fn pack<W: Write + Seek>(&self, buffer: &mut W) -> Result<u64> {
+        let offset = buffer.stream_position()?;
+        self.bucket_size.pack(buffer)?;
+        self.buckets_per_segment.pack(buffer)?;
+        self.probe_distance.pack(buffer)?;
+        self.stash_buckets.pack(buffer)?;
+        self.value_size.pack(buffer)?;
+        Ok(offset)
+    }
 
+    fn unpack<R: Read + Seek>(buffer: &mut R) -> Result<Self> {
+        Ok(Self {
+            bucket_size: u32::unpack(buffer).context("Reading bucket_size")?,
+            buckets_per_segment: u32::unpack(buffer).context("Reading buckets_per_segment")?,
+            probe_distance: u32::unpack(buffer).context("Reading probe_distance")?,
+            // Headers written before the stash existed have zeroes here
+            stash_buckets: u32::unpack(buffer).context("Reading stash_buckets")?,
+            // And ones written before values could be wider than a u64 have zeroes here
+            value_size: match u32::unpack(buffer).context("Reading value_size")? {
+                0 => size_of::<u64>() as u32,
+                value_size => value_size,
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct Segment {
     pub depth: u8,
     pub offset: u64,
@@ -38,7 +182,7 @@ impl Serializable for Segment {
 
     /// unpacks a segment's depth. Assumes buffer has already Seeked to proper offset
     fn unpack<R: Read + Seek>(buffer: &mut R) -> Result<Self> {
-        let offset = buffer.seek(io::SeekFrom::Current(0))?;
+        let offset = buffer.stream_position()?;
         let mut b: [u8; 1] = [0; 1];
         buffer
             .read_exact(&mut b)
@@ -48,6 +192,153 @@ impl Serializable for Segment {
     }
 }
 
+/// A whole segment held in memory, so that it can be read or written with a single I/O by
+/// `Segmenter::read_segment` and `Segmenter::write_segment`. The buffer can be reused for any
+/// number of segments with the same geometry.
+pub struct SegmentBuf {
+    geometry: Geometry,
+    // The offset of the first bucket of the segment that was read into the buffer, so its
+    // buckets get the right offsets
+    pub(crate) buckets_start: u64,
+    // The offset of its first stash bucket, which doesn't have to follow the regular buckets
+    pub(crate) stash_start: u64,
+    // The local depth followed by every bucket, exactly as it's laid out on disk
+    bytes: Vec<u8>,
+    // The number of occupied records in each bucket when the segment was read
+    loaded: Vec<usize>,
+}
+
+impl SegmentBuf {
+    pub fn new(geometry: Geometry) -> Self {
+        Self {
+            geometry,
+            buckets_start: 0,
+            stash_start: 0,
+            bytes: vec![0; geometry.segment_size()],
+            loaded: vec![0; geometry.segment_buckets() as usize],
+        }
+    }
+
+    /// Empties the buffer so it can be filled in and passed to `Segmenter::allocate_with_segment`
+    /// as a new segment with `depth`.
+    pub fn clear(&mut self, depth: u8) {
+        self.buckets_start = 0;
+        self.stash_start = 0;
+        self.bytes.fill(0);
+        self.bytes[0] = depth;
+        self.loaded.fill(0);
+    }
+
+    pub fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    pub fn depth(&self) -> u8 {
+        self.bytes[0]
+    }
+
+    pub fn set_depth(&mut self, depth: u8) {
+        self.bytes[0] = depth;
+    }
+
+    /// Copies out the bucket at `index`. Changes to it are only kept once it's put back with
+    /// `set_bucket`.
+    pub fn bucket(&self, index: u32) -> Bucket {
+        let start = self.bucket_start(index);
+        Bucket::from_bytes(
+            self.bucket_offset(index),
+            &self.bytes[start..],
+            self.geometry.bucket_layout(),
+            self.loaded[index as usize],
+        )
+    }
+
+    /// Borrows the bucket at `index` without copying it.
+    pub fn bucket_ref(&self, index: u32) -> BucketRef<'_> {
+        let start = self.bucket_start(index);
+        BucketRef::new(
+            self.bucket_offset(index),
+            &self.bytes[start..],
+            self.geometry.bucket_layout(),
+        )
+    }
+
+    pub fn set_bucket(&mut self, index: u32, bucket: &Bucket) {
+        assert_eq!(bucket.layout(), self.geometry.bucket_layout());
+        let start = self.bucket_start(index);
+        self.bytes[start..start + self.geometry.bucket_size as usize]
+            .copy_from_slice(bucket.as_bytes());
+    }
+
+    /// Copies out every bucket in the segment, stash included.
+    pub fn buckets(&self) -> impl Iterator<Item = Bucket> + '_ {
+        (0..self.geometry.segment_buckets()).map(|index| self.bucket(index))
+    }
+
+    /// The number of occupied records in the segment.
+    pub fn len(&self) -> usize {
+        occupied_records(&self.bytes[1..], self.geometry.bucket_layout())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The number of records added (or, if negative, removed) since the segment was read.
+    pub fn len_delta(&self) -> i64 {
+        self.len() as i64 - self.loaded.iter().sum::<usize>() as i64
+    }
+
+    pub(crate) fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Resizes the buffer for `geometry` and returns it to be read into. The read must be
+    /// followed by `loaded`.
+    pub(crate) fn prepare(&mut self, geometry: Geometry) -> &mut [u8] {
+        if geometry != self.geometry {
+            *self = Self::new(geometry);
+        }
+        &mut self.bytes
+    }
+
+    /// Records that a segment whose first bucket is at `buckets_start` was just read into the
+    /// buffer.
+    pub(crate) fn loaded(&mut self, buckets_start: u64) {
+        let regular = self.geometry.buckets_per_segment as u64 * self.geometry.bucket_size as u64;
+        self.loaded_at(buckets_start, buckets_start + regular);
+    }
+
+    /// Like `loaded`, for a layout that keeps the stash buckets at `stash_start` rather than
+    /// right after the regular buckets.
+    pub(crate) fn loaded_at(&mut self, buckets_start: u64, stash_start: u64) {
+        self.buckets_start = buckets_start;
+        self.stash_start = stash_start;
+        let layout = self.geometry.bucket_layout();
+        for (loaded, bucket) in self
+            .loaded
+            .iter_mut()
+            .zip(self.bytes[1..].chunks_exact(layout.size()))
+        {
+            *loaded = occupied_records(bucket, layout);
+        }
+    }
+
+    fn bucket_offset(&self, index: u32) -> u64 {
+        let bucket_size = self.geometry.bucket_size as u64;
+        match index.checked_sub(self.geometry.buckets_per_segment) {
+            Some(stashed) => self.stash_start + stashed as u64 * bucket_size,
+            None => self.buckets_start + index as u64 * bucket_size,
+        }
+    }
+
+    fn bucket_start(&self, index: u32) -> usize {
+        assert!(index < self.geometry.segment_buckets());
+        // The segment's depth comes first
+        1 + index as usize * self.geometry.bucket_size as usize
+    }
+}
+
 impl Serializable for u32 {
     fn pack<W: Write + Seek>(&self, buffer: &mut W) -> Result<u64> {
         let buf = self.to_le_bytes();
@@ -64,9 +355,9 @@ impl Serializable for u32 {
 /// A Segmenter is a something responsible for allocating and reading segments
 pub trait Segmenter {
     /// Header is a Sized type used to identify the offset for reading and writing segments. Some
-    /// implementations will have a 0-sized header. The `BasicSegmenter` will have a u64 header for
-    /// storing `num_segments`. Implementations can expose a way to read the header in their API,
-    /// but it is not strictly necessary.
+    /// implementations will have a 0-sized header. The `BasicSegmenter` has a `PaddedHeader` for
+    /// storing `num_segments`, the geometry and the free list. Implementations can expose a way to
+    /// read the header in their API, but it is not strictly necessary.
     type Header;
     /// Attempts to read an existing segment.
     fn segment(&self, index: u32) -> Result<Segment>;
@@ -82,8 +373,100 @@ pub trait Segmenter {
     /// back to to the Segmenter using `write_bucket`. Implementations that support concurrency are
     /// responsible for providing the appropriate locking mechanism to prevent race-conditions.
     fn bucket(&self, segment: &Segment, index: u32) -> Result<Bucket>;
-    /// Overwrites an existing bucket.
-    fn write_bucket(&self, bucket: &Bucket) -> Result<()>;
+    /// The offset of the bucket at `index` in `segment`, the same as the `offset` of the `Bucket`
+    /// that `bucket` returns for it. Wrappers use it to find buckets without reading them.
+    fn bucket_offset(&self, segment: &Segment, index: u32) -> u64;
+    /// Calls `f` with a borrowed view of the bucket at `index` in `segment` and returns what it
+    /// returns. Implementations that already hold the bucket's bytes in memory should override
+    /// this to lend them out without copying, by default the bucket is read with `bucket`. The
+    /// caller needs the same locks as for `bucket`.
+    fn with_bucket<R>(
+        &self,
+        segment: &Segment,
+        index: u32,
+        f: impl FnOnce(BucketRef<'_>) -> R,
+    ) -> Result<R> {
+        let bucket = self.bucket(segment, index)?;
+        Ok(f(bucket.view()))
+    }
+    /// Looks `hk` up in the bucket at `index` in `segment`. Implementations that read from
+    /// storage should override this to read only the bucket's header and then the records whose
+    /// fingerprint matches, by default it's a `Bucket::get` through `with_bucket`.
+    fn find_record<V: FixedValue>(
+        &self,
+        segment: &Segment,
+        index: u32,
+        hk: u64,
+    ) -> Result<Option<Record<V>>> {
+        self.with_bucket(segment, index, |bucket| bucket.get_value(hk))
+    }
+    /// Overwrites an existing bucket. Once its records are counted in the segment's metadata the
+    /// bucket is marked clean, so writing it again only counts what changed since.
+    fn write_bucket(&self, bucket: &mut Bucket) -> Result<()>;
+    /// Persists the change `Bucket::put` or `Bucket::remove` made to the slot at `index`, which
+    /// must be the only slot changed since the bucket was read. Implementations that can write
+    /// at byte granularity should override this to write only `Bucket::slot_writes` instead of the
+    /// whole bucket, by default the whole bucket is written.
+    fn write_slot(&self, bucket: &mut Bucket, index: usize) -> Result<()> {
+        let _ = index;
+        self.write_bucket(bucket)
+    }
+    /// Reads several buckets from `segment`, returned in the same order as `indexes`.
+    /// Implementations that can batch I/O should override this, by default the buckets are read
+    /// one at a time.
+    fn buckets(&self, segment: &Segment, indexes: &[u32]) -> Result<Vec<Bucket>> {
+        indexes
+            .iter()
+            .map(|&index| self.bucket(segment, index))
+            .collect()
+    }
+    /// Overwrites several existing buckets. Like `buckets`, this can be overridden to batch the
+    /// writes.
+    fn write_buckets(&self, buckets: &mut [Bucket]) -> Result<()> {
+        buckets
+            .iter_mut()
+            .try_for_each(|bucket| self.write_bucket(bucket))
+    }
+    /// Reads the whole of `segment`, stash included, into `buf`. Implementations should do this
+    /// with a single read, by default every bucket is read with `buckets`.
+    fn read_segment(&self, segment: &Segment, buf: &mut SegmentBuf) -> Result<()> {
+        let geometry = self.geometry();
+        let all_buckets: Vec<u32> = (0..geometry.segment_buckets()).collect();
+        let buckets = self.buckets(segment, &all_buckets)?;
+        buf.prepare(geometry);
+        buf.set_depth(segment.depth);
+        for (index, bucket) in buckets.iter().enumerate() {
+            buf.set_bucket(index as u32, bucket);
+        }
+        buf.loaded(buckets[0].offset);
+        Ok(())
+    }
+    /// Overwrites `segment`, including its local depth, with `buf`, which must have been read
+    /// from it with `read_segment`. Like `read_segment` this should be a single write, by default
+    /// the buckets are written with `write_buckets` and the depth with `update_segment`.
+    fn write_segment(&self, segment: &Segment, buf: &SegmentBuf) -> Result<()> {
+        let mut buckets: Vec<Bucket> = buf.buckets().collect();
+        self.write_buckets(&mut buckets)?;
+        self.update_segment(Segment {
+            depth: buf.depth(),
+            ..*segment
+        })
+    }
+    /// The same as `Segmenter.allocate_with_buckets` except the new segment, depth and all, is
+    /// written straight from `buf`.
+    fn allocate_with_segment(&self, buf: &SegmentBuf) -> Result<(u32, Segment)> {
+        self.allocate_with_buckets(buf.buckets().collect(), buf.depth())
+    }
+    /// Returns a segment so that a later allocation can reuse it. The caller must make sure
+    /// nothing, including the directory, still points to the segment.
+    fn free_segment(&self, index: u32) -> Result<()>;
+    /// The geometry of the segments this Segmenter reads and writes.
+    fn geometry(&self) -> Geometry;
+    /// Writes anything the Segmenter has buffered back to storage. Segmenters that write through
+    /// have nothing to do.
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
     /// Returns the *current* number of segements allocated. This may or may not be cached
     /// in-memory. Implementations that save segements to non-volatile storage *should* store this
     /// value along with the segments. If the implementation supports concurrency, this should
@@ -96,10 +479,308 @@ pub trait Segmenter {
     fn update_segment(&self, segment: Segment) -> Result<()>;
 }
 
+/// A snapshot of a segment's in-memory metadata.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentMeta {
+    pub depth: u8,
+    /// The number of occupied record slots in the segment.
+    pub records: u32,
+    pub offset: u64,
+}
+
+/// An entry in the in-memory segment table.
+struct SegmentTableEntry {
+    depth: AtomicU8,
+    records: AtomicU32,
+    offset: u64,
+}
+
+impl SegmentTableEntry {
+    fn new(depth: u8, records: u32, offset: u64) -> Self {
+        Self {
+            depth: AtomicU8::new(depth),
+            records: AtomicU32::new(records),
+            offset,
+        }
+    }
+}
+
+/// The in-memory metadata of every segment, indexed by segment index. It's loaded when a
+/// segmenter is opened so that looking up a segment's local depth never has to touch the disk.
+/// The table is only write-locked to push newly allocated segments, everything else is updated
+/// in place.
+#[derive(Default)]
+pub(crate) struct SegmentTable(RwLock<Vec<SegmentTableEntry>>);
+
+impl SegmentTable {
+    pub(crate) fn segment(&self, index: u32) -> Result<Segment> {
+        let table = self.0.read();
+        match table.get(index as usize) {
+            Some(entry) => Ok(Segment {
+                depth: entry.depth.load(Ordering::Acquire),
+                offset: entry.offset,
+            }),
+            None => Err(anyhow!(
+                "Segment index {} is out of bounds, there are only {} segments",
+                index,
+                table.len()
+            )),
+        }
+    }
+
+    pub(crate) fn meta(&self, index: u32) -> Option<SegmentMeta> {
+        self.0.read().get(index as usize).map(|e| SegmentMeta {
+            depth: e.depth.load(Ordering::Acquire),
+            records: e.records.load(Ordering::Acquire),
+            offset: e.offset,
+        })
+    }
+
+    /// Adds the segment at the end of the table, it must have the next segment index.
+    pub(crate) fn push(&self, depth: u8, records: u32, offset: u64) {
+        self.0
+            .write()
+            .push(SegmentTableEntry::new(depth, records, offset));
+    }
+
+    /// Resets the entry for a segment whose index was reused.
+    pub(crate) fn reset(&self, index: u32, depth: u8, records: u32) -> Result<()> {
+        let table = self.0.read();
+        let entry = table
+            .get(index as usize)
+            .with_context(|| format!("Segment {} was never loaded", index))?;
+        entry.depth.store(depth, Ordering::Release);
+        entry.records.store(records, Ordering::Release);
+        Ok(())
+    }
+
+    pub(crate) fn set_depth(&self, index: u32, depth: u8) -> Result<()> {
+        let table = self.0.read();
+        let entry = table
+            .get(index as usize)
+            .with_context(|| format!("Updating segment {} that was never loaded", index))?;
+        entry.depth.store(depth, Ordering::Release);
+        Ok(())
+    }
+
+    pub(crate) fn add_records(&self, index: u32, delta: i64) -> Result<()> {
+        if delta == 0 {
+            return Ok(());
+        }
+        let table = self.0.read();
+        let entry = table
+            .get(index as usize)
+            .with_context(|| format!("Segment {} is past the last segment", index))?;
+        if delta > 0 {
+            entry.records.fetch_add(delta as u32, Ordering::AcqRel);
+        } else {
+            entry.records.fetch_sub(-delta as u32, Ordering::AcqRel);
+        }
+        Ok(())
+    }
+}
+
+/// The parts of the header that allocating and freeing segments change. They share a lock so
+/// the same index is never handed out twice.
+pub(crate) struct SegmentSlots {
+    pub(crate) num_segments: u32,
+    // Freed segment indexes, reused last in first out
+    pub(crate) free: Vec<u32>,
+}
+
+impl SegmentSlots {
+    /// Checks that a segment can be freed and adds it to the free list. The free list still has
+    /// to be written.
+    pub(crate) fn release(&mut self, index: u32) -> Result<()> {
+        if index >= self.num_segments || self.free.contains(&index) {
+            return Err(anyhow!("Segment {} is not allocated", index));
+        }
+        if self.free.len() >= MAX_FREE_SEGMENTS {
+            return Err(anyhow!(
+                "The free list is full, it can only hold {} segments",
+                MAX_FREE_SEGMENTS
+            ));
+        }
+        debug!("Freeing segment {}", index);
+        self.free.push(index);
+        Ok(())
+    }
+
+    /// Takes a segment index for a new segment, preferring a freed one. Returns the index and
+    /// whether it was reused. Appending persists the new `num_segments` right away, a reused
+    /// index has to be committed with `write_free_list` once the segment has been written.
+    pub(crate) fn take<W: Write + Seek>(&mut self, file: &mut W) -> Result<(u32, bool)> {
+        if let Some(index) = self.free.pop() {
+            debug!("Reusing freed segment {}", index);
+            return Ok((index, true));
+        }
+        let index = self.num_segments;
+        self.num_segments += 1;
+        // Flush write the current number of segments to the file
+        file.seek(io::SeekFrom::Start(0))
+            .context("Seeking to beginning of segment file.")?;
+        file.write_all(&self.num_segments.to_le_bytes())
+            .context("Syncing num_segments")?;
+        Ok((index, false))
+    }
+
+    pub(crate) fn write_free_list<W: Write + Seek>(&self, file: &mut W) -> Result<()> {
+        let mut buf = Vec::with_capacity((self.free.len() + 1) * 4);
+        buf.extend_from_slice(&(self.free.len() as u32).to_le_bytes());
+        for index in &self.free {
+            buf.extend_from_slice(&index.to_le_bytes());
+        }
+        file.seek(io::SeekFrom::Start(FREE_LIST_OFFSET))?;
+        file.write_all(&buf).context("Writing free list")?;
+        file.flush()?;
+        Ok(())
+    }
+
+    pub(crate) fn read_free_list<R: Read + Seek>(file: &mut R) -> Result<Vec<u32>> {
+        file.seek(io::SeekFrom::Start(FREE_LIST_OFFSET))?;
+        // A header that was never written past the geometry has an empty free list
+        let count = u32::unpack(file).unwrap_or(0) as usize;
+        if count > MAX_FREE_SEGMENTS {
+            return Err(anyhow!("Corrupt free list with {} entries", count));
+        }
+        (0..count)
+            .map(|_| u32::unpack(file).context("Reading free list"))
+            .collect()
+    }
+}
+
+/// Reads the geometry from the header, writing `requested` (or the default) if there isn't
+/// one yet.
+pub(crate) fn load_geometry<F: Read + Write + Seek>(
+    file: &mut F,
+    requested: Option<Geometry>,
+) -> Result<Geometry> {
+    file.seek(io::SeekFrom::Start(GEOMETRY_OFFSET))?;
+    let stored = Geometry::unpack(file).ok().filter(|g| g.bucket_size != 0);
+    match (stored, requested) {
+        (Some(stored), Some(requested)) if stored != requested => Err(anyhow!(
+            "Segment file has geometry {:?}, but {:?} was requested",
+            stored,
+            requested
+        )),
+        (Some(stored), _) => {
+            stored.validate().context("Invalid geometry in header")?;
+            Ok(stored)
+        }
+        (None, requested) => {
+            let geometry = requested.unwrap_or_default();
+            geometry.validate()?;
+            file.seek(io::SeekFrom::Start(GEOMETRY_OFFSET))?;
+            geometry.pack(file).context("Writing geometry")?;
+            file.flush()?;
+            Ok(geometry)
+        }
+    }
+}
+
+/// Deallocates the disk blocks backing `len` bytes at `offset` without changing the file's size.
+/// The range reads back as zeroes.
+#[cfg(target_os = "linux")]
+pub(crate) fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn punch_hole(_file: &File, _offset: u64, _len: u64) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Allocates the disk blocks backing `len` bytes at `offset`, growing the file if the range runs
+/// past its end.
+#[cfg(target_os = "linux")]
+pub(crate) fn preallocate(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            0,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Without `fallocate` the file is only grown, so the space isn't actually reserved on disk.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn preallocate(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    if file.metadata()?.len() < offset + len {
+        file.set_len(offset + len)?;
+    }
+    Ok(())
+}
+
+/// The smallest unit storage reads or writes. `ThreadSafeFileSegmenter::find_record` reads buckets
+/// up to this size whole and `ThreadSafeFileSegmenter::write_slot` writes them whole.
+const SECTOR_SIZE: usize = 512;
+
+/// The number of segments `ThreadSafeFileSegmenter` reserves space for at a time by default.
+pub const DEFAULT_PREALLOCATE_SEGMENTS: u32 = 64;
+
+/// Options for opening a segment file with `ThreadSafeFileSegmenter::with_options`.
+#[derive(Debug, Clone, Copy)]
+pub struct SegmentFileOptions {
+    /// The geometry of a new segment file. If `None` the file's geometry is used, or the default
+    /// one if the file is new.
+    pub geometry: Option<Geometry>,
+    /// How many segments' worth of space to reserve with `fallocate` whenever an allocation runs
+    /// past the space already reserved. Reserving ahead of demand keeps the file from fragmenting
+    /// as it grows one segment at a time. `0` disables preallocation.
+    pub preallocate_segments: u32,
+}
+
+impl Default for SegmentFileOptions {
+    fn default() -> Self {
+        Self {
+            geometry: None,
+            preallocate_segments: DEFAULT_PREALLOCATE_SEGMENTS,
+        }
+    }
+}
+
+/// How much of a segment file has been reserved for segments and how much of that is in use.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SpaceUsage {
+    /// Bytes reserved for segments, whether or not they have been allocated yet.
+    pub preallocated_bytes: u64,
+    /// Bytes taken up by allocated segments, including freed ones waiting to be reused.
+    pub used_bytes: u64,
+}
+
 pub struct ThreadSafeFileSegmenter {
     path: PathBuf,
     file: RefCell<File>,
-    segment_file_lock: Arc<Mutex<u32>>,
+    segment_file_lock: Arc<Mutex<SegmentSlots>>,
+    table: Arc<SegmentTable>,
+    // The offset of the first segment. The `num_segments` header always lives at offset 0.
+    segments_start: u64,
+    geometry: Geometry,
+    // The number of segments the file has space for. This is the high-water mark of the file and
+    // is only moved while `segment_file_lock` is held.
+    reserved: Arc<AtomicU32>,
+    preallocate_segments: u32,
 }
 
 impl Clone for ThreadSafeFileSegmenter {
@@ -115,12 +796,19 @@ impl Clone for ThreadSafeFileSegmenter {
             path: self.path.clone(),
             file: RefCell::new(file),
             segment_file_lock: self.segment_file_lock.clone(),
+            table: self.table.clone(),
+            segments_start: self.segments_start,
+            geometry: self.geometry,
+            reserved: self.reserved.clone(),
+            preallocate_segments: self.preallocate_segments,
         }
     }
 }
 
 /// PaddedHeader gives the 4kb padding necessary for good performance
-/// in the segment file.
+/// in the segment file. Past `num_segments` it holds the geometry at `GEOMETRY_OFFSET` and the
+/// free list at `FREE_LIST_OFFSET`. A `StripedSegmenter` keeps each file's stripe number in
+/// between, at `STRIPE_OFFSET`.
 pub struct PaddedHeader {
     _num_segments: u32,
     _padding: [u8; 4092],
@@ -130,103 +818,179 @@ impl Segmenter for ThreadSafeFileSegmenter {
     // For this implementation, the header is simply the u32 num_segments
     type Header = PaddedHeader;
     fn segment(&self, index: u32) -> Result<Segment> {
-        let mut file = self.file.borrow_mut();
-        let offset = ((index as usize * SEGMENT_SIZE) + size_of::<Self::Header>()) as u64;
-        file.seek(io::SeekFrom::Start(offset))?;
-        let mut buf: [u8; 1] = [0; 1];
-        file.read_exact(&mut buf).with_context(|| {
-            format!(
-                "Error reading segment local depth for segment index {} with offset {}",
-                index, offset
-            )
-        })?;
-        let depth = u8::from_le_bytes(buf);
-        Ok(Segment { depth, offset })
+        self.table.segment(index)
     }
 
     fn allocate_segment(&self, depth: u8) -> Result<(u32, Segment)> {
         debug!("Allocating empty segment with depth {}", depth);
         let mut file = self.file.borrow_mut();
-        let mut num_segments = self.segment_file_lock.lock();
-        let index = (*num_segments).clone();
-        *num_segments += 1;
-        // Flush write the current number of segments to the file
-        file.seek(io::SeekFrom::Start(0))
-            .context("Seeking to beginning of segment file.")?;
-        file.write_all(&num_segments.to_le_bytes())
-            .context("Syncing num_segments")?;
+        let mut slots = self.segment_file_lock.lock();
+        let (index, reused) = slots.take(&mut *file)?;
+        self.reserve(&mut slots, &file, index);
         file.flush()?;
-        //------------------------------------------👇 for num_segments header in segments file
-        let offset = ((index as usize * SEGMENT_SIZE) + size_of::<Self::Header>()) as u64;
+        let offset = self.segment_offset(index);
         debug!("New segment offset: {}", offset);
         // Seek to the proper offset
         file.seek(io::SeekFrom::Start(offset))
             .with_context(|| format!("Seeking to new segment's offset {}", offset))?;
-        let mut buf: [u8; SEGMENT_SIZE] = [0; SEGMENT_SIZE];
+        let mut buf = vec![0; self.geometry.segment_size()];
         buf[..1].copy_from_slice(&depth.to_le_bytes());
         file.write_all(&buf).context("Writing new segment bytes")?;
         file.flush()
             .context("Flushing buffer after segment allocate.")?;
+        self.commit_allocation(&mut file, &slots, index, reused, depth, 0)?;
         Ok((index, Segment { depth, offset }))
     }
 
     fn allocate_with_buckets(&self, buckets: Vec<Bucket>, depth: u8) -> Result<(u32, Segment)> {
         // The number of buckets passed in *must* be the entire segment's buckets
-        let mut file = self.file.borrow_mut();
+        assert!(buckets.len() == self.geometry.segment_buckets() as usize);
+        let mut buf = SegmentBuf::new(self.geometry);
+        buf.clear(depth);
+        for (i, bucket) in buckets.iter().enumerate() {
+            buf.set_bucket(i as u32, bucket);
+        }
+        self.allocate_with_segment(&buf)
+    }
 
-        let mut num_segments = self.segment_file_lock.lock();
-        let index = (*num_segments).clone();
-        *num_segments += 1;
-        // Flush write the current number of segments to the file
-        file.seek(io::SeekFrom::Start(0))
-            .context("Seeking to beginning of segment file.")?;
-        file.write_all(&num_segments.to_le_bytes())
-            .context("Syncing num_segments")?;
-        // ------------------------------------------👇 for num_segments header in segments file
-        let offset = ((index as usize * SEGMENT_SIZE) + size_of::<Self::Header>()) as u64;
+    fn allocate_with_segment(&self, buf: &SegmentBuf) -> Result<(u32, Segment)> {
+        assert_eq!(buf.geometry(), self.geometry);
+        let mut file = self.file.borrow_mut();
+        let mut slots = self.segment_file_lock.lock();
+        let (index, reused) = slots.take(&mut *file)?;
+        self.reserve(&mut slots, &file, index);
+        let offset = self.segment_offset(index);
         info!("New segment offset: {}", offset);
-        // We create a BufWriter because we're going to be writing a lot and don't want to flush it
-        // until we're done.
         file.seek(io::SeekFrom::Start(offset))
             .context("Seeking to new segment location")?;
-        let mut buf: [u8; 1] = [0; 1];
-        buf[..].copy_from_slice(&depth.to_le_bytes());
-        file.write_all(&buf)
-            .context("Writing new segment's depth.")?;
-        for (i, bucket) in buckets.iter().enumerate() {
-            debug!("Writing bucket with index {}", i);
-            bucket
-                .pack(&mut *file)
-                .with_context(|| format!("Writing bucket with index {}", i))?;
-        }
+        file.write_all(buf.bytes())
+            .context("Writing new segment bytes")?;
         file.flush()
-            .context("Flushing outer buffer after segment allocate.")?;
+            .context("Flushing buffer after segment allocate.")?;
+        let depth = buf.depth();
+        self.commit_allocation(&mut file, &slots, index, reused, depth, buf.len() as u32)?;
         Ok((index, Segment { offset, depth }))
     }
 
+    fn read_segment(&self, segment: &Segment, buf: &mut SegmentBuf) -> Result<()> {
+        let mut file = self.file.borrow_mut();
+        file.seek(io::SeekFrom::Start(segment.offset))
+            .context("Seeking to segment's offset")?;
+        file.read_exact(buf.prepare(self.geometry))
+            .with_context(|| format!("Reading segment at offset {}", segment.offset))?;
+        buf.loaded(segment.offset + 1);
+        Ok(())
+    }
+
+    fn write_segment(&self, segment: &Segment, buf: &SegmentBuf) -> Result<()> {
+        assert_eq!(buf.geometry(), self.geometry);
+        let mut file = self.file.borrow_mut();
+        file.seek(io::SeekFrom::Start(segment.offset))
+            .context("Seeking to segment's offset")?;
+        file.write_all(buf.bytes())
+            .with_context(|| format!("Writing segment at offset {}", segment.offset))?;
+        file.flush()?;
+        let index = self.segment_index_of(segment.offset);
+        self.table.set_depth(index, buf.depth())?;
+        self.table.add_records(index, buf.len_delta())
+    }
+
     fn bucket(&self, segment: &Segment, index: u32) -> Result<Bucket> {
-        assert!(index < BUCKETS_PER_SEGMENT as u32);
+        assert!(index < self.geometry.segment_buckets());
         let mut file = self.file.borrow_mut();
-        //----------------------------------------------------------👇 for segment_depth
-        let offset = segment.offset + (index as usize * BUCKET_SIZE) as u64 + 1;
+        let offset = self.bucket_offset(segment, index);
         file.seek(io::SeekFrom::Start(offset))
             .context("Seeking to bucket's offset")?;
         debug!("Reading bucket at offset {}", offset);
-        return Bucket::unpack(&mut *file);
+        Bucket::unpack(&mut *file, self.geometry.bucket_layout())
     }
 
-    fn write_bucket(&self, bucket: &Bucket) -> Result<()> {
+    fn write_bucket(&self, bucket: &mut Bucket) -> Result<()> {
         let mut file = self.file.borrow_mut();
         file.seek(io::SeekFrom::Start(bucket.offset))
             .context("Seeking to bucket's offset")?;
         bucket.pack(&mut *file)?;
         file.flush()?;
+        self.bucket_written(bucket)
+    }
+
+    fn bucket_offset(&self, segment: &Segment, index: u32) -> u64 {
+        //-------------------------------------------------------------------👇 for segment_depth
+        segment.offset + index as u64 * self.geometry.bucket_size as u64 + 1
+    }
+
+    fn find_record<V: FixedValue>(
+        &self,
+        segment: &Segment,
+        index: u32,
+        hk: u64,
+    ) -> Result<Option<Record<V>>> {
+        let layout = self.geometry.bucket_layout();
+        layout.check_value::<V>();
+        // A second read costs more than it saves on buckets that fit in a sector
+        if layout.size() <= SECTOR_SIZE {
+            return Ok(self.bucket(segment, index)?.get_value(hk));
+        }
+        let offset = self.bucket_offset(segment, index);
+        let mut file = self.file.borrow_mut();
+        let mut header = vec![0; layout.header_size()];
+        file.seek(io::SeekFrom::Start(offset))
+            .context("Seeking to bucket's offset")?;
+        file.read_exact(&mut header)
+            .with_context(|| format!("Reading header of bucket at offset {}", offset))?;
+        for slot in matching_slots(&header, layout, hk) {
+            let mut buf = vec![0; layout.record_size()];
+            let record_offset = offset + layout.record_offset(slot) as u64;
+            file.seek(io::SeekFrom::Start(record_offset))
+                .context("Seeking to record's offset")?;
+            file.read_exact(&mut buf)
+                .with_context(|| format!("Reading record at offset {}", record_offset))?;
+            let record = Record::from_bytes(&buf);
+            if record.hash_key == hk {
+                return Ok(Some(record));
+            }
+        }
+        Ok(None)
+    }
+
+    fn write_slot(&self, bucket: &mut Bucket, index: usize) -> Result<()> {
+        // The sector is rewritten either way, one write is cheaper than several
+        if bucket.layout().size() <= SECTOR_SIZE {
+            return self.write_bucket(bucket);
+        }
+        let mut file = self.file.borrow_mut();
+        for (offset, bytes) in bucket.slot_writes(index) {
+            file.seek(io::SeekFrom::Start(offset))
+                .context("Seeking to slot's offset")?;
+            file.write_all(bytes)
+                .with_context(|| format!("Writing slot {} at offset {}", index, offset))?;
+        }
+        file.flush()?;
+        self.bucket_written(bucket)
+    }
+
+    fn free_segment(&self, index: u32) -> Result<()> {
+        let mut file = self.file.borrow_mut();
+        let mut slots = self.segment_file_lock.lock();
+        slots.release(index)?;
+        slots.write_free_list(&mut *file)?;
+        self.table.reset(index, 0, 0)?;
+        // Give the space back to the filesystem. This is only an optimization, the segment is
+        // rewritten in full when it's reused.
+        let offset = self.segment_offset(index);
+        if let Err(e) = punch_hole(&file, offset, self.geometry.segment_size() as u64) {
+            warn!("Unable to punch hole for freed segment {}: {}", index, e);
+        }
         Ok(())
     }
 
+    fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
     fn num_segments(&self) -> Result<u32> {
-        let num_segments = self.segment_file_lock.lock();
-        Ok(*num_segments)
+        let slots = self.segment_file_lock.lock();
+        Ok(slots.num_segments)
     }
 
     fn update_segment(&self, segment: Segment) -> Result<()> {
@@ -235,13 +999,42 @@ impl Segmenter for ThreadSafeFileSegmenter {
         file.seek(io::SeekFrom::Start(segment.offset))?;
         file.write_all(&segment.depth.to_le_bytes())?;
         file.flush()?;
-        Ok(())
+        self.table
+            .set_depth(self.segment_index_of(segment.offset), segment.depth)
     }
 }
 
 impl ThreadSafeFileSegmenter {
     /// Sets up and initializes the
     pub fn init(path: PathBuf) -> Result<Self> {
+        Self::with_options(path, SegmentFileOptions::default())
+    }
+
+    /// The same as `init`, but a new segment file is created with `geometry`. Opening an existing
+    /// file with a different geometry is an error.
+    pub fn init_with_geometry(path: PathBuf, geometry: Geometry) -> Result<Self> {
+        Self::with_options(
+            path,
+            SegmentFileOptions {
+                geometry: Some(geometry),
+                ..Default::default()
+            },
+        )
+    }
+
+    /// The same as `init`, but with the geometry and preallocation chunk taken from `options`.
+    pub fn with_options(path: PathBuf, options: SegmentFileOptions) -> Result<Self> {
+        Self::init_at(path, size_of::<PaddedHeader>() as u64, options)
+    }
+
+    /// Opens a segment file whose segments start at `segments_start` instead of directly after
+    /// the `PaddedHeader`, which lets the segments live in a region of a larger file. The
+    /// `num_segments` header is still read from and written to offset 0.
+    pub fn init_at(
+        path: PathBuf,
+        segments_start: u64,
+        options: SegmentFileOptions,
+    ) -> Result<Self> {
         let mut file = OpenOptions::new()
             .read(true)
             .write(true)
@@ -260,11 +1053,26 @@ impl ThreadSafeFileSegmenter {
                 0
             }
         };
+        let geometry = load_geometry(&mut file, options.geometry)?;
+        let free = SegmentSlots::read_free_list(&mut file)?;
+        if let Some(index) = free.iter().find(|&&i| i >= num_segments) {
+            return Err(anyhow!("Free list contains unallocated segment {}", index));
+        }
+        // Anything past the last segment was preallocated by an earlier run
+        let reserved = (file.metadata()?.len().saturating_sub(segments_start)
+            / geometry.segment_size() as u64) as u32;
         let out = Self {
             file: RefCell::new(file),
             path,
-            segment_file_lock: Arc::new(Mutex::new(num_segments)),
+            segment_file_lock: Arc::new(Mutex::new(SegmentSlots { num_segments, free })),
+            table: Default::default(),
+            segments_start,
+            geometry,
+            reserved: Arc::new(AtomicU32::new(reserved.max(num_segments))),
+            preallocate_segments: options.preallocate_segments,
         };
+        out.load_table(num_segments)
+            .context("Loading segment metadata table")?;
         if first_time {
             out.allocate_segment(0)
                 .context("Error initializing first segment.")?;
@@ -272,4 +1080,425 @@ impl ThreadSafeFileSegmenter {
 
         Ok(out)
     }
+
+    /// Records a newly written segment in the segment table, committing it to the free list
+    /// first if it reused a freed segment.
+    fn commit_allocation(
+        &self,
+        file: &mut File,
+        slots: &SegmentSlots,
+        index: u32,
+        reused: bool,
+        depth: u8,
+        records: u32,
+    ) -> Result<()> {
+        if !reused {
+            self.table.push(depth, records, self.segment_offset(index));
+            return Ok(());
+        }
+        slots.write_free_list(file)?;
+        self.table.reset(index, depth, records)
+    }
+
+    /// The indexes of the segments that are free to be reused.
+    pub fn free_segments(&self) -> Vec<u32> {
+        self.segment_file_lock.lock().free.clone()
+    }
+
+    /// The number of allocated segments that haven't been freed.
+    pub fn live_segments(&self) -> u32 {
+        let slots = self.segment_file_lock.lock();
+        slots.num_segments - slots.free.len() as u32
+    }
+
+    /// Reports how much space the file has reserved for segments versus how much is used.
+    pub fn space_usage(&self) -> SpaceUsage {
+        let slots = self.segment_file_lock.lock();
+        let segment_size = self.geometry.segment_size() as u64;
+        SpaceUsage {
+            preallocated_bytes: self.reserved.load(Ordering::Acquire) as u64 * segment_size,
+            used_bytes: slots.num_segments as u64 * segment_size,
+        }
+    }
+
+    /// Makes sure the file has space for the segment at `index`, reserving the next chunk of
+    /// segments if it doesn't. Borrowing `slots` mutably means `segment_file_lock` is held, so two
+    /// allocations can't reserve the same chunk. Failing to preallocate isn't fatal: the write that
+    /// follows grows the file anyway.
+    fn reserve(&self, _slots: &mut SegmentSlots, file: &File, index: u32) {
+        let reserved = self.reserved.load(Ordering::Acquire);
+        if index < reserved {
+            return;
+        }
+        if self.preallocate_segments == 0 {
+            self.reserved.store(index + 1, Ordering::Release);
+            return;
+        }
+        let chunk = self.preallocate_segments.max(index + 1 - reserved);
+        let segment_size = self.geometry.segment_size() as u64;
+        let offset = self.segment_offset(reserved);
+        match preallocate(file, offset, chunk as u64 * segment_size) {
+            Ok(()) => {
+                debug!("Preallocated {} segments at offset {}", chunk, offset);
+                self.reserved.store(reserved + chunk, Ordering::Release);
+            }
+            Err(e) => {
+                warn!("Unable to preallocate {} segments: {}", chunk, e);
+                self.reserved.store(index + 1, Ordering::Release);
+            }
+        }
+    }
+
+    /// Updates the segment table after `bucket` was written without going through
+    /// `write_bucket`, and marks it clean.
+    pub(crate) fn bucket_written(&self, bucket: &mut Bucket) -> Result<()> {
+        self.table
+            .add_records(self.segment_index_of(bucket.offset), bucket.len_delta())?;
+        bucket.mark_clean();
+        Ok(())
+    }
+
+    /// Returns a snapshot of the in-memory metadata for the segment at `index`.
+    pub fn segment_meta(&self, index: u32) -> Option<SegmentMeta> {
+        self.table.meta(index)
+    }
+
+    /// Reads every segment once to build the segment table. This is the only time the local
+    /// depths and record counts are read from disk.
+    fn load_table(&self, num_segments: u32) -> Result<()> {
+        let mut file = self.file.borrow_mut();
+        let mut buf = vec![0; self.geometry.segment_size()];
+        for index in 0..num_segments {
+            let offset = self.segment_offset(index);
+            file.seek(io::SeekFrom::Start(offset))?;
+            file.read_exact(&mut buf)
+                .with_context(|| format!("Reading segment {} at offset {}", index, offset))?;
+            let records = occupied_records(&buf[1..], self.geometry.bucket_layout()) as u32;
+            self.table.push(buf[0], records, offset);
+        }
+        Ok(())
+    }
+
+    fn segment_offset(&self, index: u32) -> u64 {
+        self.segments_start + (index as usize * self.geometry.segment_size()) as u64
+    }
+
+    fn segment_index_of(&self, offset: u64) -> u32 {
+        ((offset - self.segments_start) as usize / self.geometry.segment_size()) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn segment_table_is_maintained_and_reloaded() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("segment.bin");
+        let segmenter = ThreadSafeFileSegmenter::init(path.clone()).unwrap();
+        let first = segmenter.segment(0).unwrap();
+        let mut bucket = segmenter.bucket(&first, 3).unwrap();
+        bucket.put(123, 456, 0).unwrap();
+        bucket.put(789, 666, 0).unwrap();
+        segmenter.write_bucket(&mut bucket).unwrap();
+        let (index, mut second) = segmenter.allocate_segment(0).unwrap();
+        second.depth = 2;
+        segmenter.update_segment(second).unwrap();
+        assert_eq!(segmenter.segment_meta(0).unwrap().records, 2);
+        assert_eq!(segmenter.segment(index).unwrap().depth, 2);
+        drop(segmenter);
+
+        let segmenter = ThreadSafeFileSegmenter::init(path).unwrap();
+        assert_eq!(segmenter.num_segments().unwrap(), 2);
+        let first = segmenter.segment_meta(0).unwrap();
+        assert_eq!((first.depth, first.records), (0, 2));
+        let second = segmenter.segment_meta(index).unwrap();
+        assert_eq!((second.depth, second.records), (2, 0));
+        assert!(segmenter.segment(2).is_err());
+    }
+
+    #[test]
+    fn rewriting_a_bucket_only_counts_new_records() {
+        let dir = TempDir::new().unwrap();
+        let segmenter = ThreadSafeFileSegmenter::init(dir.path().join("segment.bin")).unwrap();
+        let first = segmenter.segment(0).unwrap();
+        let mut bucket = segmenter.bucket(&first, 3).unwrap();
+        bucket.put(123, 456, 0).unwrap();
+        segmenter.write_bucket(&mut bucket).unwrap();
+        assert_eq!(bucket.len_delta(), 0);
+        segmenter.write_bucket(&mut bucket).unwrap();
+        let index = bucket.put(789, 666, 0).unwrap();
+        segmenter.write_slot(&mut bucket, index).unwrap();
+        segmenter.write_slot(&mut bucket, index).unwrap();
+        assert_eq!(segmenter.segment_meta(0).unwrap().records, 2);
+    }
+
+    #[test]
+    fn write_slot_only_persists_the_changed_slot() {
+        let dir = TempDir::new().unwrap();
+        let geometry = Geometry {
+            bucket_size: 4096,
+            buckets_per_segment: 8,
+            ..Geometry::DEFAULT
+        };
+        let path = dir.path().join("segment.bin");
+        let segmenter = ThreadSafeFileSegmenter::init_with_geometry(path, geometry).unwrap();
+        let first = segmenter.segment(0).unwrap();
+        let mut bucket = segmenter.bucket(&first, 7).unwrap();
+        let index = bucket.put(123, 456, 0).unwrap();
+        segmenter.write_slot(&mut bucket, index).unwrap();
+        let mut bucket = segmenter.bucket(&first, 7).unwrap();
+        assert_eq!(bucket.get(123).unwrap().value, 456);
+        let index = bucket.put(789, 1, 0).unwrap();
+        // Only the slot passed to `write_slot` reaches the file, not this update
+        bucket.put(123, 457, 0).unwrap();
+        segmenter.write_slot(&mut bucket, index).unwrap();
+        let bucket = segmenter.bucket(&first, 7).unwrap();
+        assert_eq!(bucket.get(123).unwrap().value, 456);
+        assert_eq!(bucket.get(789).unwrap().value, 1);
+        assert_eq!(segmenter.segment_meta(0).unwrap().records, 2);
+
+        // Small buckets are written whole
+        let path = dir.path().join("small.bin");
+        let segmenter = ThreadSafeFileSegmenter::init(path).unwrap();
+        let first = segmenter.segment(0).unwrap();
+        let mut bucket = segmenter.bucket(&first, 7).unwrap();
+        bucket.put(123, 456, 0).unwrap();
+        let index = bucket.put(789, 1, 0).unwrap();
+        segmenter.write_slot(&mut bucket, index).unwrap();
+        let bucket = segmenter.bucket(&first, 7).unwrap();
+        assert_eq!(bucket.get(123).unwrap().value, 456);
+        assert_eq!(bucket.get(789).unwrap().value, 1);
+    }
+
+    #[test]
+    fn whole_segments_can_be_read_and_written() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("segment.bin");
+        let segmenter = ThreadSafeFileSegmenter::init(path.clone()).unwrap();
+        let first = segmenter.segment(0).unwrap();
+        let mut bucket = segmenter.bucket(&first, 5).unwrap();
+        bucket.put(123, 456, 0).unwrap();
+        segmenter.write_bucket(&mut bucket).unwrap();
+
+        let mut buf = SegmentBuf::new(segmenter.geometry());
+        segmenter.read_segment(&first, &mut buf).unwrap();
+        assert_eq!(buf.len(), 1);
+        let mut bucket = buf.bucket(5);
+        assert_eq!(bucket.offset, segmenter.bucket_offset(&first, 5));
+        assert_eq!(bucket.get(123).unwrap().value, 456);
+        bucket.remove(123);
+        buf.set_bucket(5, &bucket);
+        let mut stashed = buf.bucket(Geometry::DEFAULT.buckets_per_segment);
+        stashed.put(789, 1, 0).unwrap();
+        stashed.put(790, 2, 0).unwrap();
+        buf.set_bucket(Geometry::DEFAULT.buckets_per_segment, &stashed);
+        buf.set_depth(1);
+        segmenter.write_segment(&first, &buf).unwrap();
+        let meta = segmenter.segment_meta(0).unwrap();
+        assert_eq!((meta.depth, meta.records), (1, 2));
+
+        let (index, segment) = segmenter.allocate_with_segment(&buf).unwrap();
+        assert_eq!((index, segment.depth), (1, 1));
+        assert_eq!(segmenter.segment_meta(1).unwrap().records, 2);
+        drop(segmenter);
+
+        let segmenter = ThreadSafeFileSegmenter::init(path).unwrap();
+        for index in 0..2 {
+            let segment = segmenter.segment(index).unwrap();
+            assert_eq!(segment.depth, 1);
+            segmenter.read_segment(&segment, &mut buf).unwrap();
+            assert_eq!(buf.len(), 2);
+            assert!(buf.buckets().all(|bucket| bucket.get(123).is_none()));
+        }
+    }
+
+    #[test]
+    fn find_record_reads_only_matching_records() {
+        let dir = TempDir::new().unwrap();
+        let geometry = Geometry {
+            bucket_size: 4096,
+            buckets_per_segment: 4,
+            probe_distance: 2,
+            stash_buckets: 1,
+            value_size: 8,
+        };
+        let path = dir.path().join("segment.bin");
+        let segmenter = ThreadSafeFileSegmenter::init_with_geometry(path, geometry).unwrap();
+        let first = segmenter.segment(0).unwrap();
+        let mut bucket = segmenter.bucket(&first, 2).unwrap();
+        // These all share a fingerprint
+        for hk in [0x101, 0x201, 0x301, 0x7] {
+            bucket.put(hk, hk * 2, 0).unwrap();
+        }
+        bucket.remove(0x201).unwrap();
+        segmenter.write_bucket(&mut bucket).unwrap();
+        for hk in [0x101, 0x201, 0x301, 0x401, 0x7, 0x8] {
+            let found = segmenter.find_record(&first, 2, hk).unwrap();
+            assert_eq!(
+                found.map(|r| (r.hash_key, r.value)),
+                bucket.get(hk).map(|r| (r.hash_key, r.value)),
+                "{:x}",
+                hk
+            );
+        }
+        assert!(
+            segmenter
+                .find_record::<u64>(&first, 1, 0x101)
+                .unwrap()
+                .is_none()
+        );
+
+        // Small buckets are read whole
+        let path = dir.path().join("small.bin");
+        let segmenter = ThreadSafeFileSegmenter::init(path).unwrap();
+        let first = segmenter.segment(0).unwrap();
+        let mut bucket = segmenter.bucket(&first, 0).unwrap();
+        bucket.put(0x101, 1, 0).unwrap();
+        segmenter.write_bucket(&mut bucket).unwrap();
+        let found = segmenter.find_record::<u64>(&first, 0, 0x101).unwrap();
+        assert_eq!(found.unwrap().value, 1);
+        assert!(
+            segmenter
+                .find_record::<u64>(&first, 0, 0x201)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn geometry_is_stored_in_the_header() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("segment.bin");
+        let geometry = Geometry {
+            bucket_size: 256,
+            buckets_per_segment: 16,
+            probe_distance: 2,
+            stash_buckets: 1,
+            value_size: 8,
+        };
+        let segmenter =
+            ThreadSafeFileSegmenter::init_with_geometry(path.clone(), geometry).unwrap();
+        let first = segmenter.segment(0).unwrap();
+        let mut bucket = segmenter.bucket(&first, 16).unwrap();
+        assert_eq!(bucket.layout().records(), 14);
+        bucket.put(123, 456, 0).unwrap();
+        segmenter.write_bucket(&mut bucket).unwrap();
+        segmenter.allocate_segment(1).unwrap();
+        drop(segmenter);
+
+        let segmenter = ThreadSafeFileSegmenter::init(path.clone()).unwrap();
+        assert_eq!(segmenter.geometry(), geometry);
+        assert_eq!(segmenter.segment_meta(0).unwrap().records, 1);
+        assert_eq!(segmenter.segment(1).unwrap().depth, 1);
+        drop(segmenter);
+        assert!(ThreadSafeFileSegmenter::init_with_geometry(path, Geometry::DEFAULT).is_err());
+    }
+
+    #[test]
+    fn freed_segments_are_reused_and_persisted() {
+        use std::os::unix::fs::MetadataExt;
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("segment.bin");
+        let segmenter = ThreadSafeFileSegmenter::init(path.clone()).unwrap();
+        for _ in 0..3 {
+            segmenter.allocate_segment(1).unwrap();
+        }
+        let first = segmenter.segment(0).unwrap();
+        let mut bucket = segmenter.bucket(&first, 0).unwrap();
+        bucket.put(123, 456, 0).unwrap();
+        segmenter.write_bucket(&mut bucket).unwrap();
+        let blocks = std::fs::metadata(&path).unwrap().blocks();
+        segmenter.free_segment(0).unwrap();
+        segmenter.free_segment(2).unwrap();
+        assert!(segmenter.free_segment(2).is_err());
+        assert!(segmenter.free_segment(4).is_err());
+        assert_eq!(segmenter.segment_meta(0).unwrap().records, 0);
+        assert!(std::fs::metadata(&path).unwrap().blocks() < blocks);
+        drop(segmenter);
+
+        let segmenter = ThreadSafeFileSegmenter::init(path).unwrap();
+        assert_eq!(segmenter.free_segments(), vec![0, 2]);
+        let (index, _) = segmenter.allocate_segment(3).unwrap();
+        assert_eq!(index, 2);
+        let buckets = (0..Geometry::DEFAULT.segment_buckets())
+            .map(|_| Bucket::new())
+            .collect();
+        let (index, segment) = segmenter.allocate_with_buckets(buckets, 4).unwrap();
+        assert_eq!((index, segment.depth), (0, 4));
+        // The old contents of a reused segment must not come back
+        let bucket = segmenter.bucket(&segment, 0).unwrap();
+        assert!(bucket.get(123).is_none());
+        assert_eq!(segmenter.segment_meta(0).unwrap().depth, 4);
+        assert!(segmenter.free_segments().is_empty());
+        assert_eq!(segmenter.num_segments().unwrap(), 4);
+        let (index, _) = segmenter.allocate_segment(0).unwrap();
+        assert_eq!(index, 4);
+    }
+
+    #[test]
+    fn segment_space_is_preallocated_in_chunks() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("segment.bin");
+        let options = SegmentFileOptions {
+            preallocate_segments: 8,
+            ..Default::default()
+        };
+        let segment_size = Geometry::DEFAULT.segment_size() as u64;
+        let header = size_of::<PaddedHeader>() as u64;
+        let len = || std::fs::metadata(&path).unwrap().len();
+        let segmenter = ThreadSafeFileSegmenter::with_options(path.clone(), options).unwrap();
+        assert_eq!(len(), header + 8 * segment_size);
+        for _ in 1..8 {
+            segmenter.allocate_segment(1).unwrap();
+        }
+        assert_eq!(len(), header + 8 * segment_size);
+        let usage = segmenter.space_usage();
+        assert_eq!(usage.preallocated_bytes, 8 * segment_size);
+        assert_eq!(usage.used_bytes, 8 * segment_size);
+        segmenter.allocate_segment(1).unwrap();
+        assert_eq!(len(), header + 16 * segment_size);
+        drop(segmenter);
+
+        let segmenter = ThreadSafeFileSegmenter::init(path).unwrap();
+        let usage = segmenter.space_usage();
+        assert_eq!(usage.preallocated_bytes, 16 * segment_size);
+        assert_eq!(usage.used_bytes, 9 * segment_size);
+    }
+
+    #[test]
+    fn invalid_geometry_is_rejected() {
+        let bad = [
+            Geometry {
+                bucket_size: 16,
+                ..Geometry::DEFAULT
+            },
+            Geometry {
+                buckets_per_segment: 100,
+                ..Geometry::DEFAULT
+            },
+            Geometry {
+                probe_distance: 0,
+                ..Geometry::DEFAULT
+            },
+            Geometry {
+                stash_buckets: 257,
+                ..Geometry::DEFAULT
+            },
+            Geometry {
+                value_size: 0,
+                ..Geometry::DEFAULT
+            },
+            Geometry {
+                value_size: 64,
+                ..Geometry::DEFAULT
+            },
+        ];
+        for geometry in bad {
+            assert!(geometry.validate().is_err(), "{:?}", geometry);
+        }
+        assert!(Geometry::DEFAULT.validate().is_ok());
+    }
 }
@@ -0,0 +1,435 @@
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::segment::bucket::{Bucket, FixedValue, Record};
+use crate::segment::segment::{
+    Geometry, PaddedHeader, STRIPE_OFFSET, Segment, SegmentBuf, SegmentMeta, Segmenter,
+    ThreadSafeFileSegmenter,
+};
+use crate::serializer::Serializable;
+
+use anyhow::{Context, Result, anyhow};
+use log::{debug, info};
+
+// A striped segment index is the stripe (file) number in the top bits and the segment's index
+// within that file in the rest, so it still fits in a directory entry.
+const LOCAL_INDEX_BITS: u32 = 24;
+const LOCAL_INDEX_MASK: u32 = (1 << LOCAL_INDEX_BITS) - 1;
+/// The most files segments can be striped across.
+pub const MAX_STRIPES: usize = 1 << (32 - LOCAL_INDEX_BITS);
+// Segment and bucket offsets are tagged with the stripe number the same way, so a `Segment` or
+// `Bucket` on its own is enough to know which file it belongs to.
+const OFFSET_BITS: u32 = 48;
+const OFFSET_MASK: u64 = (1 << OFFSET_BITS) - 1;
+
+/// How a `StripedSegmenter` picks the file for a new segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    /// Each file in turn.
+    RoundRobin,
+    /// The file with the fewest segments in use.
+    LeastFull,
+}
+
+/// A `Segmenter` that spreads segments across several segment files, each of which can be on a
+/// different device. Every file has the same format as `segment.bin` and is managed by its own
+/// `ThreadSafeFileSegmenter`.
+///
+/// Segment indexes address `(file, index)` pairs packed into a `u32`, so up to `MAX_STRIPES`
+/// files with 2^24 segments each can be used with the existing directories. The files have to be
+/// passed in the same order every time they're opened, which is checked against their headers.
+pub struct StripedSegmenter {
+    stripes: Vec<ThreadSafeFileSegmenter>,
+    placement: Placement,
+    next: Arc<AtomicUsize>,
+    geometry: Geometry,
+}
+
+impl Clone for StripedSegmenter {
+    fn clone(&self) -> Self {
+        Self {
+            stripes: self.stripes.clone(),
+            placement: self.placement,
+            next: self.next.clone(),
+            geometry: self.geometry,
+        }
+    }
+}
+
+impl StripedSegmenter {
+    pub fn init(paths: Vec<PathBuf>, placement: Placement) -> Result<Self> {
+        Self::open(paths, placement, None)
+    }
+
+    /// The same as `init`, but new segment files are created with `geometry`.
+    pub fn init_with_geometry(
+        paths: Vec<PathBuf>,
+        placement: Placement,
+        geometry: Geometry,
+    ) -> Result<Self> {
+        Self::open(paths, placement, Some(geometry))
+    }
+
+    fn open(paths: Vec<PathBuf>, placement: Placement, geometry: Option<Geometry>) -> Result<Self> {
+        if paths.is_empty() || paths.len() > MAX_STRIPES {
+            return Err(anyhow!(
+                "Segments can be striped across 1 to {} files, got {}",
+                MAX_STRIPES,
+                paths.len()
+            ));
+        }
+        let mut stripes = Vec::with_capacity(paths.len());
+        for (stripe, path) in paths.iter().enumerate() {
+            let segmenter = match geometry {
+                Some(g) => ThreadSafeFileSegmenter::init_with_geometry(path.clone(), g),
+                None => ThreadSafeFileSegmenter::init(path.clone()),
+            }
+            .with_context(|| format!("Opening stripe {} at {:?}", stripe, path))?;
+            check_stripe(path, stripe as u32, paths.len() as u32)?;
+            // Only the first file's initial segment is used by the directory, every other
+            // file's is freed so it's the first one handed out. Segments made by a split always
+            // have a depth of at least 1, so a depth 0 segment here has to be that one.
+            if stripe > 0
+                && segmenter.segment(0)?.depth == 0
+                && !segmenter.free_segments().contains(&0)
+            {
+                debug!("Freeing the initial segment of stripe {}", stripe);
+                segmenter.free_segment(0)?;
+            }
+            stripes.push(segmenter);
+        }
+        let geometry = stripes[0].geometry();
+        if let Some(path) = stripes
+            .iter()
+            .zip(&paths)
+            .find_map(|(s, path)| (s.geometry() != geometry).then_some(path))
+        {
+            return Err(anyhow!(
+                "Segment file {:?} has a different geometry than {:?}",
+                path,
+                paths[0]
+            ));
+        }
+        Ok(Self {
+            stripes,
+            placement,
+            next: Arc::new(AtomicUsize::new(0)),
+            geometry,
+        })
+    }
+
+    /// The number of files segments are striped across.
+    pub fn stripes(&self) -> usize {
+        self.stripes.len()
+    }
+
+    /// Splits a striped segment index into the file it's in and its index in that file.
+    pub fn locate(index: u32) -> (usize, u32) {
+        (
+            (index >> LOCAL_INDEX_BITS) as usize,
+            index & LOCAL_INDEX_MASK,
+        )
+    }
+
+    /// Returns a snapshot of the in-memory metadata for the segment at striped `index`. The
+    /// offset is the segment's offset in its own file.
+    pub fn segment_meta(&self, index: u32) -> Option<SegmentMeta> {
+        let (stripe, local) = Self::locate(index);
+        self.stripes.get(stripe)?.segment_meta(local)
+    }
+
+    /// The number of segments in use in each file, not counting freed ones.
+    pub fn segments_per_stripe(&self) -> Vec<u32> {
+        self.stripes.iter().map(|s| s.live_segments()).collect()
+    }
+
+    fn stripe(&self, stripe: usize) -> Result<&ThreadSafeFileSegmenter> {
+        self.stripes.get(stripe).with_context(|| {
+            format!(
+                "Stripe {} is out of bounds, there are only {}",
+                stripe,
+                self.stripes.len()
+            )
+        })
+    }
+
+    /// Picks the file for a new segment.
+    fn place(&self) -> usize {
+        match self.placement {
+            Placement::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % self.stripes.len(),
+            Placement::LeastFull => (0..self.stripes.len())
+                .min_by_key(|&i| self.stripes[i].live_segments())
+                .unwrap_or(0),
+        }
+    }
+
+    fn allocated(&self, stripe: usize, (local, segment): (u32, Segment)) -> Result<(u32, Segment)> {
+        if local > LOCAL_INDEX_MASK {
+            return Err(anyhow!("Stripe {} has run out of segment indexes", stripe));
+        }
+        info!("Allocated segment {} in stripe {}", local, stripe);
+        let index = (stripe as u32) << LOCAL_INDEX_BITS | local;
+        Ok((index, tag(stripe, segment)))
+    }
+}
+
+fn tag(stripe: usize, segment: Segment) -> Segment {
+    Segment {
+        offset: (stripe as u64) << OFFSET_BITS | segment.offset,
+        ..segment
+    }
+}
+
+/// Splits a tagged offset into its stripe and the offset in that stripe's file.
+fn untag(offset: u64) -> (usize, u64) {
+    ((offset >> OFFSET_BITS) as usize, offset & OFFSET_MASK)
+}
+
+fn untag_segment(segment: &Segment) -> (usize, Segment) {
+    let (stripe, offset) = untag(segment.offset);
+    (stripe, Segment { offset, ..*segment })
+}
+
+fn untag_bucket(bucket: &Bucket) -> (usize, Bucket) {
+    let (stripe, offset) = untag(bucket.offset);
+    let mut bucket = bucket.clone();
+    bucket.offset = offset;
+    (stripe, bucket)
+}
+
+/// Records the file's place in the stripe set in its header, or checks that it matches what's
+/// already there.
+fn check_stripe(path: &Path, stripe: u32, stripes: u32) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Opening segment file {:?}", path))?;
+    file.seek(SeekFrom::Start(STRIPE_OFFSET))?;
+    let stored = (u32::unpack(&mut file)?, u32::unpack(&mut file)?);
+    match stored {
+        (0, _) => {
+            file.seek(SeekFrom::Start(STRIPE_OFFSET))?;
+            stripes.pack(&mut file)?;
+            stripe.pack(&mut file)?;
+            file.flush()?;
+            Ok(())
+        }
+        stored if stored == (stripes, stripe) => Ok(()),
+        (count, index) => Err(anyhow!(
+            "{:?} is stripe {} of {}, but was opened as stripe {} of {}",
+            path,
+            index,
+            count,
+            stripe,
+            stripes
+        )),
+    }
+}
+
+impl Segmenter for StripedSegmenter {
+    type Header = PaddedHeader;
+
+    fn segment(&self, index: u32) -> Result<Segment> {
+        let (stripe, local) = Self::locate(index);
+        Ok(tag(stripe, self.stripe(stripe)?.segment(local)?))
+    }
+
+    fn allocate_segment(&self, depth: u8) -> Result<(u32, Segment)> {
+        let stripe = self.place();
+        let allocated = self.stripes[stripe].allocate_segment(depth)?;
+        self.allocated(stripe, allocated)
+    }
+
+    fn allocate_with_buckets(&self, buckets: Vec<Bucket>, depth: u8) -> Result<(u32, Segment)> {
+        let stripe = self.place();
+        let allocated = self.stripes[stripe].allocate_with_buckets(buckets, depth)?;
+        self.allocated(stripe, allocated)
+    }
+
+    fn allocate_with_segment(&self, buf: &SegmentBuf) -> Result<(u32, Segment)> {
+        let stripe = self.place();
+        let allocated = self.stripes[stripe].allocate_with_segment(buf)?;
+        self.allocated(stripe, allocated)
+    }
+
+    fn read_segment(&self, segment: &Segment, buf: &mut SegmentBuf) -> Result<()> {
+        let (stripe, local) = untag_segment(segment);
+        self.stripe(stripe)?.read_segment(&local, buf)?;
+        buf.buckets_start |= (stripe as u64) << OFFSET_BITS;
+        buf.stash_start |= (stripe as u64) << OFFSET_BITS;
+        Ok(())
+    }
+
+    fn write_segment(&self, segment: &Segment, buf: &SegmentBuf) -> Result<()> {
+        let (stripe, segment) = untag_segment(segment);
+        self.stripe(stripe)?.write_segment(&segment, buf)
+    }
+
+    fn bucket(&self, segment: &Segment, index: u32) -> Result<Bucket> {
+        Ok(self.buckets(segment, &[index])?.remove(0))
+    }
+
+    fn bucket_offset(&self, segment: &Segment, index: u32) -> u64 {
+        let (stripe, local) = untag_segment(segment);
+        (stripe as u64) << OFFSET_BITS | self.stripes[stripe].bucket_offset(&local, index)
+    }
+
+    fn find_record<V: FixedValue>(
+        &self,
+        segment: &Segment,
+        index: u32,
+        hk: u64,
+    ) -> Result<Option<Record<V>>> {
+        let (stripe, segment) = untag_segment(segment);
+        self.stripe(stripe)?.find_record(&segment, index, hk)
+    }
+
+    fn write_bucket(&self, bucket: &mut Bucket) -> Result<()> {
+        let (stripe, mut untagged) = untag_bucket(bucket);
+        self.stripe(stripe)?.write_bucket(&mut untagged)?;
+        bucket.mark_clean();
+        Ok(())
+    }
+
+    fn write_slot(&self, bucket: &mut Bucket, index: usize) -> Result<()> {
+        let (stripe, mut untagged) = untag_bucket(bucket);
+        self.stripe(stripe)?.write_slot(&mut untagged, index)?;
+        bucket.mark_clean();
+        Ok(())
+    }
+
+    fn buckets(&self, segment: &Segment, indexes: &[u32]) -> Result<Vec<Bucket>> {
+        let (stripe, segment) = untag_segment(segment);
+        let mut buckets = self.stripe(stripe)?.buckets(&segment, indexes)?;
+        for bucket in &mut buckets {
+            bucket.offset |= (stripe as u64) << OFFSET_BITS;
+        }
+        Ok(buckets)
+    }
+
+    fn write_buckets(&self, buckets: &mut [Bucket]) -> Result<()> {
+        let mut by_stripe: Vec<Vec<Bucket>> = vec![Vec::new(); self.stripes.len()];
+        for bucket in buckets.iter() {
+            let (stripe, bucket) = untag_bucket(bucket);
+            by_stripe
+                .get_mut(stripe)
+                .with_context(|| format!("Bucket at offset {} has no stripe", bucket.offset))?
+                .push(bucket);
+        }
+        for (segmenter, mut buckets) in self.stripes.iter().zip(by_stripe) {
+            if !buckets.is_empty() {
+                segmenter.write_buckets(&mut buckets)?;
+            }
+        }
+        buckets.iter_mut().for_each(Bucket::mark_clean);
+        Ok(())
+    }
+
+    fn free_segment(&self, index: u32) -> Result<()> {
+        let (stripe, local) = Self::locate(index);
+        self.stripe(stripe)?.free_segment(local)
+    }
+
+    fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.stripes.iter().try_for_each(|s| s.sync())
+    }
+
+    /// The number of segments allocated across every file. Striped indexes aren't contiguous,
+    /// so this isn't a bound on them, see `segments_per_stripe` for the segments in use.
+    fn num_segments(&self) -> Result<u32> {
+        self.stripes.iter().map(|s| s.num_segments()).sum()
+    }
+
+    fn update_segment(&self, segment: Segment) -> Result<()> {
+        let (stripe, segment) = untag_segment(&segment);
+        self.stripe(stripe)?.update_segment(segment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::directory::{Directory, MMapDirectory};
+    use crate::meh::MehDB;
+    use crate::meh::tests::{check_exercised, exercise};
+    use tempfile::TempDir;
+
+    fn paths(dir: &TempDir, n: usize) -> Vec<PathBuf> {
+        (0..n)
+            .map(|i| dir.path().join(format!("segment.{}.bin", i)))
+            .collect()
+    }
+
+    #[test]
+    fn segments_are_placed_round_robin() {
+        let dir = TempDir::new().unwrap();
+        let segmenter = StripedSegmenter::init(paths(&dir, 3), Placement::RoundRobin).unwrap();
+        // The other files' initial segments are allocated, but free
+        assert_eq!(segmenter.num_segments().unwrap(), 3);
+        assert_eq!(segmenter.segments_per_stripe(), vec![1, 0, 0]);
+        let indexes: Vec<u32> = (0..6)
+            .map(|_| segmenter.allocate_segment(1).unwrap().0)
+            .collect();
+        let stripes: Vec<usize> = indexes
+            .iter()
+            .map(|&i| StripedSegmenter::locate(i).0)
+            .collect();
+        assert_eq!(stripes, vec![0, 1, 2, 0, 1, 2]);
+        // The other files' initial segments were reused first
+        assert_eq!(StripedSegmenter::locate(indexes[1]), (1, 0));
+        assert_eq!(segmenter.segments_per_stripe(), vec![3, 2, 2]);
+
+        let segment = segmenter.segment(indexes[2]).unwrap();
+        let mut bucket = segmenter.bucket(&segment, 5).unwrap();
+        bucket.put(123, 456, 1).unwrap();
+        segmenter.write_bucket(&mut bucket).unwrap();
+        assert_eq!(segmenter.segment_meta(indexes[2]).unwrap().records, 1);
+        // Nothing was written to the segment at the same position in the first file
+        let first = segmenter.segment(indexes[0]).unwrap();
+        assert!(segmenter.bucket(&first, 5).unwrap().get(123).is_none());
+    }
+
+    #[test]
+    fn least_full_fills_the_emptiest_file() {
+        let dir = TempDir::new().unwrap();
+        let segmenter = StripedSegmenter::init(paths(&dir, 2), Placement::LeastFull).unwrap();
+        for _ in 0..5 {
+            segmenter.allocate_segment(1).unwrap();
+        }
+        assert_eq!(segmenter.segments_per_stripe(), vec![3, 3]);
+    }
+
+    #[test]
+    fn stripes_must_be_reopened_in_order() {
+        let dir = TempDir::new().unwrap();
+        let mut files = paths(&dir, 2);
+        StripedSegmenter::init(files.clone(), Placement::RoundRobin).unwrap();
+        StripedSegmenter::init(files.clone(), Placement::RoundRobin).unwrap();
+        files.reverse();
+        assert!(StripedSegmenter::init(files.clone(), Placement::RoundRobin).is_err());
+        files.pop();
+        assert!(StripedSegmenter::init(files, Placement::RoundRobin).is_err());
+    }
+
+    #[test]
+    fn mehdb_works_with_striped_segmenter() {
+        let dir = TempDir::new().unwrap();
+        {
+            let directory = MMapDirectory::init(dir.path().join("directory.bin")).unwrap();
+            let segmenter = StripedSegmenter::init(paths(&dir, 3), Placement::LeastFull).unwrap();
+            let db = exercise(MehDB::with_parts(directory, segmenter).unwrap());
+            assert!(db.segmenter.segments_per_stripe().iter().all(|&n| n > 1));
+        }
+        let directory = MMapDirectory::init(dir.path().join("directory.bin")).unwrap();
+        let segmenter = StripedSegmenter::init(paths(&dir, 3), Placement::LeastFull).unwrap();
+        check_exercised(&mut MehDB::with_parts(directory, segmenter).unwrap());
+    }
+}
@@ -0,0 +1,312 @@
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::Cursor;
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+
+use crate::segment::bucket::{Bucket, FixedValue, Record};
+use crate::segment::segment::{
+    Geometry, PaddedHeader, Segment, SegmentBuf, SegmentMeta, Segmenter, ThreadSafeFileSegmenter,
+};
+
+use anyhow::{Context, Result, anyhow};
+use io_uring::{IoUring, opcode, types};
+use log::{debug, warn};
+
+// The number of submission queue entries. Batches larger than this are submitted in chunks.
+pub const RING_ENTRIES: u32 = 256;
+
+/// A `Segmenter` that reads and writes batches of buckets with a single io_uring submission,
+/// so splitting a segment or answering a `multi_get` doesn't cost a syscall per bucket. It uses
+/// the same file as `ThreadSafeFileSegmenter`, which it delegates everything else to, including
+/// single bucket reads and writes.
+///
+/// If io_uring isn't available (old kernels, or it's blocked by seccomp) the batch operations fall
+/// back to the synchronous path. Like `ThreadSafeFileSegmenter` it isn't `Sync`, every thread
+/// should use its own clone, which gets its own ring.
+pub struct UringSegmenter {
+    inner: ThreadSafeFileSegmenter,
+    path: PathBuf,
+    file: File,
+    ring: Option<RefCell<IoUring>>,
+}
+
+impl Clone for UringSegmenter {
+    fn clone(&self) -> Self {
+        let (file, ring) = Self::open_ring(&self.path).expect("Unable to clone UringSegmenter");
+        Self {
+            inner: self.inner.clone(),
+            path: self.path.clone(),
+            file,
+            ring,
+        }
+    }
+}
+
+impl UringSegmenter {
+    pub fn init(path: PathBuf) -> Result<Self> {
+        Self::wrap(ThreadSafeFileSegmenter::init(path.clone())?, path)
+    }
+
+    /// The same as `init`, but a new segment file is created with `geometry`.
+    pub fn init_with_geometry(path: PathBuf, geometry: Geometry) -> Result<Self> {
+        Self::wrap(
+            ThreadSafeFileSegmenter::init_with_geometry(path.clone(), geometry)?,
+            path,
+        )
+    }
+
+    fn wrap(inner: ThreadSafeFileSegmenter, path: PathBuf) -> Result<Self> {
+        let (file, ring) = Self::open_ring(&path)?;
+        Ok(Self {
+            inner,
+            path,
+            file,
+            ring,
+        })
+    }
+
+    fn open_ring(path: &PathBuf) -> Result<(File, Option<RefCell<IoUring>>)> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Opening segment file {:?}", path))?;
+        let ring = match IoUring::new(RING_ENTRIES) {
+            Ok(ring) => Some(RefCell::new(ring)),
+            Err(e) => {
+                warn!("io_uring is unavailable, using synchronous I/O: {}", e);
+                None
+            }
+        };
+        Ok((file, ring))
+    }
+
+    /// Whether batches are submitted through io_uring.
+    pub fn is_uring(&self) -> bool {
+        self.ring.is_some()
+    }
+
+    /// Returns a snapshot of the in-memory metadata for the segment at `index`.
+    pub fn segment_meta(&self, index: u32) -> Option<SegmentMeta> {
+        self.inner.segment_meta(index)
+    }
+
+    /// Submits one read or write per buffer and waits for all of them to complete. Each buffer is
+    /// paired with the file offset it's read from or written to.
+    fn submit(
+        &self,
+        ring: &RefCell<IoUring>,
+        bufs: &mut [(u64, Vec<u8>)],
+        write: bool,
+    ) -> Result<()> {
+        let mut ring = ring.borrow_mut();
+        let fd = types::Fd(self.file.as_raw_fd());
+        for (chunk_index, chunk) in bufs.chunks_mut(RING_ENTRIES as usize).enumerate() {
+            debug!(
+                "Submitting {} bucket {} in batch {}",
+                chunk.len(),
+                if write { "writes" } else { "reads" },
+                chunk_index
+            );
+            for (i, (offset, buf)) in chunk.iter_mut().enumerate() {
+                let entry = match write {
+                    true => opcode::Write::new(fd, buf.as_ptr(), buf.len() as u32)
+                        .offset(*offset)
+                        .build(),
+                    false => opcode::Read::new(fd, buf.as_mut_ptr(), buf.len() as u32)
+                        .offset(*offset)
+                        .build(),
+                };
+                // Safety: the buffers outlive the submission, we wait for every completion below
+                unsafe {
+                    ring.submission()
+                        .push(&entry.user_data(i as u64))
+                        .map_err(|_| anyhow!("io_uring submission queue is full"))?;
+                }
+            }
+            ring.submit_and_wait(chunk.len())
+                .context("Submitting io_uring batch")?;
+            let completions: Vec<_> = ring
+                .completion()
+                .map(|cqe| (cqe.user_data() as usize, cqe.result()))
+                .collect();
+            if completions.len() != chunk.len() {
+                return Err(anyhow!(
+                    "Expected {} io_uring completions, got {}",
+                    chunk.len(),
+                    completions.len()
+                ));
+            }
+            for (i, result) in completions {
+                let (offset, buf) = &chunk[i];
+                if result < 0 {
+                    return Err(std::io::Error::from_raw_os_error(-result))
+                        .with_context(|| format!("Bucket I/O at offset {}", offset));
+                }
+                if result as usize != buf.len() {
+                    return Err(anyhow!(
+                        "Short bucket I/O at offset {}: {} of {} bytes",
+                        offset,
+                        result,
+                        buf.len()
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Segmenter for UringSegmenter {
+    type Header = PaddedHeader;
+
+    fn segment(&self, index: u32) -> Result<Segment> {
+        self.inner.segment(index)
+    }
+
+    fn allocate_segment(&self, depth: u8) -> Result<(u32, Segment)> {
+        self.inner.allocate_segment(depth)
+    }
+
+    fn allocate_with_buckets(&self, buckets: Vec<Bucket>, depth: u8) -> Result<(u32, Segment)> {
+        self.inner.allocate_with_buckets(buckets, depth)
+    }
+
+    fn bucket(&self, segment: &Segment, index: u32) -> Result<Bucket> {
+        self.inner.bucket(segment, index)
+    }
+
+    fn bucket_offset(&self, segment: &Segment, index: u32) -> u64 {
+        self.inner.bucket_offset(segment, index)
+    }
+
+    fn find_record<V: FixedValue>(
+        &self,
+        segment: &Segment,
+        index: u32,
+        hk: u64,
+    ) -> Result<Option<Record<V>>> {
+        self.inner.find_record(segment, index, hk)
+    }
+
+    fn write_bucket(&self, bucket: &mut Bucket) -> Result<()> {
+        self.inner.write_bucket(bucket)
+    }
+
+    fn write_slot(&self, bucket: &mut Bucket, index: usize) -> Result<()> {
+        self.inner.write_slot(bucket, index)
+    }
+
+    fn read_segment(&self, segment: &Segment, buf: &mut SegmentBuf) -> Result<()> {
+        self.inner.read_segment(segment, buf)
+    }
+
+    fn write_segment(&self, segment: &Segment, buf: &SegmentBuf) -> Result<()> {
+        self.inner.write_segment(segment, buf)
+    }
+
+    fn allocate_with_segment(&self, buf: &SegmentBuf) -> Result<(u32, Segment)> {
+        self.inner.allocate_with_segment(buf)
+    }
+
+    fn buckets(&self, segment: &Segment, indexes: &[u32]) -> Result<Vec<Bucket>> {
+        let Some(ring) = &self.ring else {
+            return indexes
+                .iter()
+                .map(|&index| self.inner.bucket(segment, index))
+                .collect();
+        };
+        let geometry = self.inner.geometry();
+        let mut bufs: Vec<(u64, Vec<u8>)> = indexes
+            .iter()
+            .map(|&index| {
+                assert!(index < geometry.segment_buckets());
+                (
+                    self.inner.bucket_offset(segment, index),
+                    vec![0; geometry.bucket_size as usize],
+                )
+            })
+            .collect();
+        self.submit(ring, &mut bufs, false)?;
+        bufs.into_iter()
+            .map(|(offset, buf)| {
+                let mut bucket = Bucket::unpack(&mut Cursor::new(buf), geometry.bucket_layout())?;
+                bucket.offset = offset;
+                Ok(bucket)
+            })
+            .collect()
+    }
+
+    fn write_buckets(&self, buckets: &mut [Bucket]) -> Result<()> {
+        let Some(ring) = &self.ring else {
+            return buckets
+                .iter_mut()
+                .try_for_each(|bucket| self.inner.write_bucket(bucket));
+        };
+        let mut bufs = buckets
+            .iter()
+            .map(|bucket| {
+                let mut buf = Cursor::new(Vec::new());
+                bucket.pack(&mut buf)?;
+                Ok((bucket.offset, buf.into_inner()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.submit(ring, &mut bufs, true)?;
+        buckets
+            .iter_mut()
+            .try_for_each(|bucket| self.inner.bucket_written(bucket))
+    }
+
+    fn free_segment(&self, index: u32) -> Result<()> {
+        self.inner.free_segment(index)
+    }
+
+    fn geometry(&self) -> Geometry {
+        self.inner.geometry()
+    }
+
+    fn num_segments(&self) -> Result<u32> {
+        self.inner.num_segments()
+    }
+
+    fn update_segment(&self, segment: Segment) -> Result<()> {
+        self.inner.update_segment(segment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::directory::{Directory, MMapDirectory};
+    use crate::meh::MehDB;
+    use crate::meh::tests::exercise;
+    use tempfile::TempDir;
+
+    #[test]
+    fn batches_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let segmenter = UringSegmenter::init(dir.path().join("segment.bin")).unwrap();
+        let segment = segmenter.segment(0).unwrap();
+        let indexes: Vec<u32> = (0..Geometry::DEFAULT.segment_buckets()).rev().collect();
+        let mut buckets = segmenter.buckets(&segment, &indexes).unwrap();
+        for (bucket, &index) in buckets.iter_mut().zip(&indexes) {
+            bucket.put(index as u64, index as u64 * 2, 0).unwrap();
+        }
+        segmenter.write_buckets(&mut buckets).unwrap();
+        assert_eq!(segmenter.segment_meta(0).unwrap().records, 260);
+        // Read them back one at a time through the synchronous path
+        for index in indexes {
+            let bucket = segmenter.bucket(&segment, index).unwrap();
+            assert_eq!(bucket.get(index as u64).unwrap().value, index as u64 * 2);
+        }
+    }
+
+    #[test]
+    fn mehdb_works_with_uring_segmenter() {
+        let dir = TempDir::new().unwrap();
+        let directory = MMapDirectory::init(dir.path().join("directory.bin")).unwrap();
+        let segmenter = UringSegmenter::init(dir.path().join("segment.bin")).unwrap();
+        exercise(MehDB::with_parts(directory, segmenter).unwrap());
+    }
+}
@@ -11,24 +11,12 @@ pub trait Serializable: Sized {
     fn unpack<R: Read + Seek>(buffer: &mut R) -> Result<Self>;
 }
 
-#[derive(Debug, Eq, PartialEq, Clone)]
+#[derive(Debug, Eq, PartialEq, Clone, Default)]
 pub struct ByteKey(pub Vec<u8>);
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Eq, PartialEq, Default)]
 pub struct ByteValue(pub u64);
 
-impl Default for ByteKey {
-    fn default() -> Self {
-        ByteKey(Vec::new())
-    }
-}
-
-impl Default for ByteValue {
-    fn default() -> Self {
-        ByteValue(0)
-    }
-}
-
 pub enum DataOrOffset {
     Offset(u64),
     Data(Vec<u8>),
@@ -0,0 +1,472 @@
+use crate::directory::{Directory, DirectorySegments, GlobalDepth, MAX_DEPTH, grown_entries};
+use crate::segment::{
+    Bucket, FixedValue, Geometry, Record, Segment, SegmentBuf, SegmentFileOptions, Segmenter,
+    ThreadSafeFileSegmenter,
+};
+use crate::serializer::Serializable;
+
+use anyhow::{Context, Result, anyhow};
+use crossbeam::sync::ShardedLock;
+use log::{debug, info};
+use memmap2::{MmapMut, MmapOptions};
+use std::fs::OpenOptions;
+use std::io::{self, Read, Seek, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+// A single-file database is laid out as:
+//
+// | superblock (4KiB) | directory slot 0 | directory slot 1 | segments... |
+//
+// The directory slots are each large enough for a directory at `max_global_depth`. Only the
+// active slot is live; growing writes the doubled directory into the other slot and then flips
+// `active_slot` and `global_depth` in the superblock, so a crash mid-grow leaves the old directory
+// intact. The slots are never written past the current directory size, so the file stays sparse.
+// The segment region uses exactly the same format as `segment.bin`, with `num_segments` stored in
+// the first 4 bytes of the superblock and the geometry at `GEOMETRY_OFFSET`.
+
+const MAGIC: &[u8; 8] = b"MEHDBSF1";
+pub const SUPERBLOCK_SIZE: usize = 4096;
+// Offsets of the superblock fields that are modified in place through the map
+const GLOBAL_DEPTH: usize = 12;
+const ACTIVE_SLOT: usize = 13;
+/// The largest `max_global_depth` used for new files unless configured otherwise. Each directory
+/// slot reserves `4 * 2^max_global_depth` bytes of (sparse) file.
+pub const DEFAULT_MAX_GLOBAL_DEPTH: u8 = 24;
+
+pub struct SingleFileConfig {
+    pub path: PathBuf,
+    /// Only used when creating a new file, existing files keep the depth they were created with.
+    pub max_global_depth: u8,
+    /// The geometry for a new file. Existing files must have been created with the same geometry
+    /// unless this is `None`.
+    pub geometry: Option<Geometry>,
+}
+
+impl From<PathBuf> for SingleFileConfig {
+    fn from(path: PathBuf) -> Self {
+        Self {
+            path,
+            max_global_depth: DEFAULT_MAX_GLOBAL_DEPTH,
+            geometry: None,
+        }
+    }
+}
+
+/// The fixed-size header at the start of a single-file database that points to the directory
+/// and segment regions.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Superblock {
+    pub num_segments: u32,
+    pub global_depth: u8,
+    pub active_slot: u8,
+    pub max_global_depth: u8,
+    pub directory_offset: u64,
+    pub segments_offset: u64,
+}
+
+impl Superblock {
+    fn new(max_global_depth: u8) -> Self {
+        let directory_offset = SUPERBLOCK_SIZE as u64;
+        Self {
+            num_segments: 0,
+            global_depth: 0,
+            active_slot: 0,
+            max_global_depth,
+            directory_offset,
+            segments_offset: directory_offset + 2 * Self::slot_size(max_global_depth),
+        }
+    }
+
+    /// The number of bytes reserved for each of the two directory slots.
+    fn slot_size(max_global_depth: u8) -> u64 {
+        4 << max_global_depth
+    }
+}
+
+impl Serializable for Superblock {
+    fn pack<W: Write + Seek>(&self, buffer: &mut W) -> Result<u64> {
+        let mut buf = [0; SUPERBLOCK_SIZE];
+        buf[0..4].copy_from_slice(&self.num_segments.to_le_bytes());
+        buf[4..12].copy_from_slice(MAGIC);
+        buf[GLOBAL_DEPTH] = self.global_depth;
+        buf[ACTIVE_SLOT] = self.active_slot;
+        buf[14] = self.max_global_depth;
+        buf[16..24].copy_from_slice(&self.directory_offset.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.segments_offset.to_le_bytes());
+        buffer.write_all(&buf).context("Error packing superblock")?;
+        Ok(SUPERBLOCK_SIZE as u64)
+    }
+
+    fn unpack<R: Read + Seek>(buffer: &mut R) -> Result<Self> {
+        let mut buf = [0; SUPERBLOCK_SIZE];
+        buffer
+            .read_exact(&mut buf)
+            .context("Error reading superblock")?;
+        if &buf[4..12] != MAGIC {
+            return Err(anyhow!("Not a single-file MehDB database, bad magic"));
+        }
+        let superblock = Self {
+            num_segments: u32::from_le_bytes(buf[0..4].try_into().unwrap()),
+            global_depth: buf[GLOBAL_DEPTH],
+            active_slot: buf[ACTIVE_SLOT],
+            max_global_depth: buf[14],
+            directory_offset: u64::from_le_bytes(buf[16..24].try_into().unwrap()),
+            segments_offset: u64::from_le_bytes(buf[24..32].try_into().unwrap()),
+        };
+        if superblock.global_depth > superblock.max_global_depth || superblock.active_slot > 1 {
+            return Err(anyhow!("Corrupt superblock: {:?}", superblock));
+        }
+        Ok(superblock)
+    }
+}
+
+/// A database stored in one file, implementing both `Directory` and `Segmenter`. Clones share
+/// the directory map and segment table but get their own file handle, the same as
+/// `ThreadSafeFileSegmenter`.
+#[derive(Clone)]
+pub struct SingleFile {
+    // Maps the superblock and both directory slots
+    map: Arc<ShardedLock<MmapMut>>,
+    directory_offset: usize,
+    slot_size: usize,
+    max_global_depth: u8,
+    segmenter: ThreadSafeFileSegmenter,
+}
+
+impl SingleFile {
+    /// The offset in the map of directory entry `i` in the currently active slot.
+    fn entry_offset(&self, map: &MmapMut, i: u64) -> usize {
+        self.directory_offset + (map[ACTIVE_SLOT] as usize * self.slot_size) + (i * 4) as usize
+    }
+
+    /// Writes a doubled copy of the active directory slot into the inactive one and then
+    /// switches to it. Returns the new global depth.
+    fn grow_locked(&self, map: &mut MmapMut) -> Result<u8> {
+        let global_depth = map[GLOBAL_DEPTH];
+        if global_depth >= self.max_global_depth {
+            return Err(anyhow!(
+                "Directory is already at its maximum global depth of {}",
+                self.max_global_depth
+            ));
+        }
+        info!(
+            "Increase global_depth from {} to {}",
+            global_depth,
+            global_depth + 1
+        );
+        let old_slot = map[ACTIVE_SLOT] as usize;
+        let new_slot = 1 - old_slot;
+        let old_base = self.directory_offset + old_slot * self.slot_size;
+        let new_base = self.directory_offset + new_slot * self.slot_size;
+        let entries = 1usize << global_depth;
+        for i in 0..entries {
+            let mut data: [u8; 4] = [0; 4];
+            data.copy_from_slice(&map[old_base + i * 4..old_base + i * 4 + 4]);
+            // Write exactly twice
+            let offset = new_base + i * 8;
+            map[offset..offset + 4].copy_from_slice(&data);
+            map[offset + 4..offset + 8].copy_from_slice(&data);
+        }
+        map.flush_range(new_base, entries * 8)
+            .context("Flushing grown directory slot")?;
+        // Commit the new directory
+        map[GLOBAL_DEPTH] = global_depth + 1;
+        map[ACTIVE_SLOT] = new_slot as u8;
+        map.flush_range(GLOBAL_DEPTH, 2)
+            .context("Flushing superblock after growing directory")?;
+        Ok(global_depth + 1)
+    }
+}
+
+impl Directory for SingleFile {
+    type Config = SingleFileConfig;
+
+    fn init(config: Self::Config) -> Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .truncate(false)
+            .create(true)
+            .open(&config.path)
+            .with_context(|| format!("Opening single-file database {:?}", config.path))?;
+        let superblock = if file.metadata()?.len() == 0 {
+            if config.max_global_depth > MAX_DEPTH {
+                return Err(anyhow!("max_global_depth can be at most {}", MAX_DEPTH));
+            }
+            debug!("Initializing new single-file database {:?}", config.path);
+            let superblock = Superblock::new(config.max_global_depth);
+            superblock.pack(&mut file)?;
+            // Reserve the directory slots without writing to them
+            file.set_len(superblock.segments_offset)
+                .context("Reserving directory slots")?;
+            file.sync_all()?;
+            superblock
+        } else {
+            file.seek(io::SeekFrom::Start(0))?;
+            Superblock::unpack(&mut file)?
+        };
+        let map = unsafe {
+            MmapOptions::new()
+                .len(superblock.segments_offset as usize)
+                .map_mut(&file)
+                .context("Mapping superblock and directory")?
+        };
+        let segmenter = ThreadSafeFileSegmenter::init_at(
+            config.path.clone(),
+            superblock.segments_offset,
+            SegmentFileOptions {
+                geometry: config.geometry,
+                ..Default::default()
+            },
+        )?;
+        if segmenter.num_segments()? == 0 {
+            segmenter
+                .allocate_segment(0)
+                .context("Error initializing first segment.")?;
+        }
+        Ok(Self {
+            map: Arc::new(ShardedLock::new(map)),
+            directory_offset: superblock.directory_offset as usize,
+            slot_size: Superblock::slot_size(superblock.max_global_depth) as usize,
+            max_global_depth: superblock.max_global_depth,
+            segmenter,
+        })
+    }
+
+    fn segment_index(&self, i: u64) -> Result<u32> {
+        let unlocked = match self.map.read() {
+            Err(_) => return Err(anyhow!("Directory lock is probably poisoned.")),
+            Ok(l) => l,
+        };
+        let global_depth = unlocked[GLOBAL_DEPTH];
+        let index = if global_depth == 0 {
+            0
+        } else {
+            i >> (64 - global_depth)
+        };
+        let offset = self.entry_offset(&unlocked, index);
+        let mut buf: [u8; 4] = [0; 4];
+        buf.copy_from_slice(&unlocked[offset..offset + 4]);
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn set_segment_index(&self, i: u64, index: u32, gd: &mut GlobalDepth<'_>) -> Result<()> {
+        self.set_segment_range(i, 1, index, gd)
+    }
+
+    fn set_segment_range(
+        &self,
+        start: u64,
+        count: u64,
+        index: u32,
+        gd: &mut GlobalDepth<'_>,
+    ) -> Result<()> {
+        if start + count > 1 << gd.global_depth {
+            return Err(anyhow!(
+                "Directory entries {}..{} are out of bounds",
+                start,
+                start + count
+            ));
+        }
+        info!(
+            "Setting dir indexes {}..{} to segment index {}",
+            start,
+            start + count,
+            index
+        );
+        let offset = self.entry_offset(&gd.lock, start);
+        let len = (count * 4) as usize;
+        let bytes = index.to_le_bytes();
+        for entry in gd.lock[offset..offset + len].chunks_exact_mut(4) {
+            entry.copy_from_slice(&bytes);
+        }
+        gd.lock
+            .flush_range(offset, len)
+            .context("Flushing directory range")?;
+        Ok(())
+    }
+
+    fn grow(&self) -> Result<u32> {
+        let mut unlocked = match self.map.write() {
+            Err(_) => return Err(anyhow!("Directory lock is probably poisoned.")),
+            Ok(l) => l,
+        };
+        let entries = grown_entries(unlocked[GLOBAL_DEPTH], self.max_global_depth)?;
+        self.grow_locked(&mut unlocked)?;
+        Ok(entries)
+    }
+
+    fn global_depth(&self) -> Result<GlobalDepth<'_>> {
+        let unlocked = match self.map.write() {
+            Err(_) => return Err(anyhow!("Directory lock is probably poisoned.")),
+            Ok(l) => l,
+        };
+        Ok(GlobalDepth {
+            global_depth: unlocked[GLOBAL_DEPTH],
+            lock: unlocked,
+        })
+    }
+
+    fn max_depth(&self) -> u8 {
+        self.max_global_depth
+    }
+
+    fn grow_if_eq(&self, local_depth: u8) -> Result<u8> {
+        let mut unlocked = match self.map.write() {
+            Err(_) => return Err(anyhow!("Directory lock is probably poisoned.")),
+            Ok(l) => l,
+        };
+        if unlocked[GLOBAL_DEPTH] > local_depth {
+            return Ok(unlocked[GLOBAL_DEPTH]);
+        }
+        self.grow_locked(&mut unlocked)
+    }
+
+    fn segments(&self) -> Result<impl Iterator<Item = (u32, u64, u64, u8)> + '_> {
+        let unlocked = match self.map.read() {
+            Err(_) => return Err(anyhow!("Directory lock is probably poisoned.")),
+            Ok(l) => l,
+        };
+        let global_depth = unlocked[GLOBAL_DEPTH];
+        let base = self.entry_offset(&unlocked, 0);
+        Ok(DirectorySegments::new(unlocked, base, global_depth))
+    }
+}
+
+impl Segmenter for SingleFile {
+    type Header = Superblock;
+
+    fn segment(&self, index: u32) -> Result<Segment> {
+        self.segmenter.segment(index)
+    }
+
+    fn allocate_segment(&self, depth: u8) -> Result<(u32, Segment)> {
+        self.segmenter.allocate_segment(depth)
+    }
+
+    fn allocate_with_buckets(&self, buckets: Vec<Bucket>, depth: u8) -> Result<(u32, Segment)> {
+        self.segmenter.allocate_with_buckets(buckets, depth)
+    }
+
+    fn bucket(&self, segment: &Segment, index: u32) -> Result<Bucket> {
+        self.segmenter.bucket(segment, index)
+    }
+
+    fn bucket_offset(&self, segment: &Segment, index: u32) -> u64 {
+        self.segmenter.bucket_offset(segment, index)
+    }
+
+    fn find_record<V: FixedValue>(
+        &self,
+        segment: &Segment,
+        index: u32,
+        hk: u64,
+    ) -> Result<Option<Record<V>>> {
+        self.segmenter.find_record(segment, index, hk)
+    }
+
+    fn write_bucket(&self, bucket: &mut Bucket) -> Result<()> {
+        self.segmenter.write_bucket(bucket)
+    }
+
+    fn write_slot(&self, bucket: &mut Bucket, index: usize) -> Result<()> {
+        self.segmenter.write_slot(bucket, index)
+    }
+
+    fn read_segment(&self, segment: &Segment, buf: &mut SegmentBuf) -> Result<()> {
+        self.segmenter.read_segment(segment, buf)
+    }
+
+    fn write_segment(&self, segment: &Segment, buf: &SegmentBuf) -> Result<()> {
+        self.segmenter.write_segment(segment, buf)
+    }
+
+    fn allocate_with_segment(&self, buf: &SegmentBuf) -> Result<(u32, Segment)> {
+        self.segmenter.allocate_with_segment(buf)
+    }
+
+    fn free_segment(&self, index: u32) -> Result<()> {
+        self.segmenter.free_segment(index)
+    }
+
+    fn geometry(&self) -> Geometry {
+        self.segmenter.geometry()
+    }
+
+    fn num_segments(&self) -> Result<u32> {
+        self.segmenter.num_segments()
+    }
+
+    fn update_segment(&self, segment: Segment) -> Result<()> {
+        self.segmenter.update_segment(segment)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meh::MehDB;
+    use crate::meh::tests::{check_exercised, exercise};
+    use tempfile::TempDir;
+
+    #[test]
+    fn superblock_can_round_trip() {
+        let superblock = Superblock::new(10);
+        let mut buf = io::Cursor::new(Vec::new());
+        superblock.pack(&mut buf).unwrap();
+        buf.seek(io::SeekFrom::Start(0)).unwrap();
+        assert_eq!(Superblock::unpack(&mut buf).unwrap(), superblock);
+        let mut garbage = io::Cursor::new(vec![0; SUPERBLOCK_SIZE]);
+        assert!(Superblock::unpack(&mut garbage).is_err());
+    }
+
+    #[test]
+    fn directory_grows_into_other_slot() {
+        let dir = TempDir::new().unwrap();
+        let file = SingleFile::init(SingleFileConfig {
+            path: dir.path().join("db.meh"),
+            max_global_depth: 2,
+            geometry: None,
+        })
+        .unwrap();
+        file.grow_if_eq(0).unwrap();
+        let mut gd = file.global_depth().unwrap();
+        file.set_segment_index(1, 7, &mut gd).unwrap();
+        drop(gd);
+        assert_eq!(file.grow().unwrap(), 4);
+        let segments: Vec<_> = file.segments().unwrap().collect();
+        assert_eq!(segments, vec![(0, 0, 2, 1), (7, 2, 2, 1)]);
+        assert!(file.grow().is_err());
+    }
+
+    #[test]
+    fn mehdb_works_with_non_default_geometry() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("db.meh");
+        let geometry = Geometry {
+            bucket_size: 128,
+            buckets_per_segment: 32,
+            probe_distance: 2,
+            stash_buckets: 2,
+            value_size: 8,
+        };
+        let file = SingleFile::init(SingleFileConfig {
+            geometry: Some(geometry),
+            ..path.clone().into()
+        })
+        .unwrap();
+        drop(exercise(MehDB::with_parts(file.clone(), file).unwrap()));
+        let mut db = MehDB::open_single_file(&path).unwrap();
+        assert_eq!(db.segmenter.geometry(), geometry);
+        check_exercised(&mut db);
+    }
+
+    #[test]
+    fn mehdb_can_reopen_single_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("db.meh");
+        drop(exercise(MehDB::open_single_file(&path).unwrap()));
+        check_exercised(&mut MehDB::open_single_file(&path).unwrap());
+    }
+}